@@ -0,0 +1,5 @@
+create table interlocks(
+  name text not null primary key,
+  rule text not null,
+  created_at timestamp not null default CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+alter table outputs drop column max_switches_per_hour;
+alter table outputs drop column min_off;
+alter table outputs drop column min_on;
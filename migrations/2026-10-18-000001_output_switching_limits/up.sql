@@ -0,0 +1,3 @@
+alter table outputs add column min_on int;
+alter table outputs add column min_off int;
+alter table outputs add column max_switches_per_hour int;
@@ -0,0 +1,2 @@
+alter table outputs drop column error_policy;
+alter table outputs drop column safe_state;
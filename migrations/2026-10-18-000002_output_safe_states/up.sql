@@ -0,0 +1,2 @@
+alter table outputs add column safe_state boolean;
+alter table outputs add column error_policy text not null default 'hold';
@@ -0,0 +1,3 @@
+alter table inputs drop column calibration;
+alter table inputs drop column scale;
+alter table inputs drop column offset;
@@ -0,0 +1,3 @@
+alter table inputs add column offset real not null default 0;
+alter table inputs add column scale real not null default 1;
+alter table inputs add column calibration text;
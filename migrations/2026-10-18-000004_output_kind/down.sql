@@ -0,0 +1 @@
+alter table outputs drop column kind;
@@ -0,0 +1 @@
+alter table outputs add column kind text not null default 'boolean';
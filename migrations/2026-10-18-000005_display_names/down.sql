@@ -0,0 +1,3 @@
+alter table outputs drop column display_name;
+alter table inputs drop column display_name;
+alter table devices drop column display_name;
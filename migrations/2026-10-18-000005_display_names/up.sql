@@ -0,0 +1,6 @@
+alter table devices add column display_name text not null default '';
+alter table inputs add column display_name text not null default '';
+alter table outputs add column display_name text not null default '';
+update devices set display_name = name;
+update inputs set display_name = name;
+update outputs set display_name = name;
@@ -0,0 +1,10 @@
+create table audit_log(
+  id integer primary key autoincrement,
+  user text not null,
+  mutation text not null,
+  arguments text not null,
+  succeeded boolean not null,
+  error text,
+  created_at timestamp not null default CURRENT_TIMESTAMP
+);
+create index audit_log_created_at on audit_log(created_at);
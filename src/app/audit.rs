@@ -0,0 +1,96 @@
+use crate::app::db::models;
+use crate::session::AppContext;
+use juniper::graphql_object;
+use serde_json::Value;
+
+/// Shown in place of anything that looks like a password
+pub const REDACTED: &str = "[redacted]";
+
+/// Who entries the app records on its own behalf are made by
+pub const SYSTEM_USER: &str = "system";
+
+/// What an interlock stopping an output is recorded as
+pub const INTERLOCK_VIOLATION: &str = "interlockViolation";
+
+/// A recorded API mutation, or something the app did that operators should know about
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub data: models::AuditEntry,
+}
+
+#[graphql_object(context = AppContext)]
+impl AuditEntry {
+    pub fn id(&self) -> i32 {
+        self.data.id
+    }
+
+    /// Who made it, or tried to sign in as
+    pub fn user(&self) -> &str {
+        self.data.user.as_str()
+    }
+
+    /// The GraphQL mutation, e.g. updateOutput
+    pub fn mutation(&self) -> &str {
+        self.data.mutation.as_str()
+    }
+
+    /// Its arguments as json, with passwords redacted
+    pub fn arguments(&self) -> &str {
+        self.data.arguments.as_str()
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.data.succeeded
+    }
+
+    /// Why it failed, if it did
+    pub fn error(&self) -> Option<&str> {
+        self.data.error.as_deref()
+    }
+
+    /// When it was made, as an RFC 3339 timestamp in UTC
+    pub fn created_at(&self) -> String {
+        self.data.created_at.and_utc().to_rfc3339()
+    }
+}
+
+/// Replace the value of every field whose name mentions a password, however deeply nested
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if name.to_lowercase().contains("password") {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_passwords_at_any_depth() {
+        let mut arguments = json!({
+            "email": "a@example.com",
+            "plaintextPassword": "hunter2",
+            "users": [{"name": "b", "password": {"hash": "x"}}],
+        });
+        redact(&mut arguments);
+        assert_eq!(
+            arguments,
+            json!({
+                "email": "a@example.com",
+                "plaintextPassword": REDACTED,
+                "users": [{"name": "b", "password": REDACTED}],
+            })
+        );
+    }
+}
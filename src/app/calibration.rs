@@ -0,0 +1,149 @@
+use crate::app::db::models;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use juniper::GraphQLObject;
+use serde_derive::{Deserialize, Serialize};
+
+/// A raw reading from a sensor, and what it should have read
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct CalibrationPoint {
+    pub raw: f64,
+    pub actual: f64,
+}
+
+/// Corrections applied to an input's readings before anything else sees them.
+///
+/// A reading is first mapped through the piecewise linear `table` (if any), then multiplied by
+/// `scale`, then `offset` is added.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Calibration {
+    pub offset: f64,
+    pub scale: f64,
+    pub table: Vec<CalibrationPoint>,
+}
+
+impl Calibration {
+    /// Parse a table stored as a JSON list of `[raw, actual]` pairs
+    pub fn parse_table(json: &str) -> Result<Vec<CalibrationPoint>> {
+        let pairs: Vec<(f64, f64)> = serde_json::from_str(json)?;
+        let mut table: Vec<CalibrationPoint> = pairs
+            .into_iter()
+            .map(|(raw, actual)| CalibrationPoint { raw, actual })
+            .collect();
+        if table
+            .iter()
+            .any(|p| !p.raw.is_finite() || !p.actual.is_finite())
+        {
+            return Err(Error::Config(
+                "calibration points must be finite numbers".to_string(),
+            ));
+        }
+        table.sort_by(|a, b| a.raw.total_cmp(&b.raw));
+        if table.windows(2).any(|w| w[0].raw == w[1].raw) {
+            return Err(Error::Config(
+                "calibration table has two points with the same raw value".to_string(),
+            ));
+        }
+        Ok(table)
+    }
+
+    pub fn from_input(input: &models::Input) -> Result<Self> {
+        Ok(Self {
+            offset: input.offset,
+            scale: input.scale,
+            table: match &input.calibration {
+                Some(json) => Self::parse_table(json)?,
+                None => vec![],
+            },
+        })
+    }
+
+    /// Map `raw` through the table, extending the first and last segments beyond its ends
+    fn interpolate(&self, raw: f64) -> f64 {
+        match self.table.as_slice() {
+            [] => raw,
+            [only] => raw + (only.actual - only.raw),
+            table => {
+                let upper = table
+                    .iter()
+                    .position(|p| p.raw > raw)
+                    .unwrap_or(table.len() - 1)
+                    .max(1);
+                let (a, b) = (table[upper - 1], table[upper]);
+                a.actual + (raw - a.raw) * (b.actual - a.actual) / (b.raw - a.raw)
+            }
+        }
+    }
+
+    /// The calibrated value of `raw`, ignoring `offset`
+    pub fn apply_without_offset(&self, raw: f64) -> f64 {
+        self.interpolate(raw) * self.scale
+    }
+
+    pub fn apply(&self, raw: f64) -> f64 {
+        self.apply_without_offset(raw) + self.offset
+    }
+
+    /// Calibrate a reading. Booleans and errors pass through unchanged.
+    pub fn apply_to(&self, reading: Dimensioned) -> Dimensioned {
+        match reading.unit() {
+            Ok(Unit::Boolean) | Err(_) => reading,
+            Ok(unit) => match reading.value() {
+                Ok(raw) => Dimensioned::new(unit, self.apply(raw)),
+                Err(_) => reading,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(offset: f64, scale: f64, table: &str) -> Calibration {
+        Calibration {
+            offset,
+            scale,
+            table: Calibration::parse_table(table).unwrap(),
+        }
+    }
+
+    #[test]
+    fn offset_and_scale() {
+        let c = calibration(-0.7, 1.0, "[]");
+        assert!((c.apply(21.7) - 21.0).abs() < 1e-9);
+
+        let c = calibration(1.0, 2.0, "[]");
+        assert!((c.apply(10.0) - 21.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn piecewise_table() {
+        let c = calibration(0.0, 1.0, "[[100, 90], [0, 0], [50, 50]]");
+        assert!((c.apply(25.0) - 25.0).abs() < 1e-9);
+        assert!((c.apply(75.0) - 70.0).abs() < 1e-9);
+        // beyond the ends, the nearest segment is extended
+        assert!((c.apply(150.0) - 130.0).abs() < 1e-9);
+        assert!((c.apply(-10.0) - -10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        assert!(Calibration::parse_table("[[1, 2], [1, 3]]").is_err());
+        assert!(Calibration::parse_table("{}").is_err());
+    }
+
+    #[test]
+    fn booleans_are_not_calibrated() {
+        let c = calibration(5.0, 1.0, "[]");
+        assert_eq!(
+            c.apply_to(Dimensioned::from_bool(true)),
+            Dimensioned::from_bool(true)
+        );
+        assert_eq!(
+            c.apply_to(Dimensioned::from_degc(1.0)),
+            Dimensioned::from_degc(6.0)
+        );
+    }
+}
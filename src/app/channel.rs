@@ -1,16 +1,26 @@
 use super::db;
 use super::dimensioned::Dimensioned;
+use crate::app::audit::AuditEntry;
 use crate::app::db::models;
 use crate::app::device::Device;
-use crate::app::input::Input;
-use crate::app::output::{BoolExpr, Output};
-use crate::app::{AppID, device, state};
-use crate::error::Result;
+use crate::app::health::{Health, restart_delay};
+use crate::app::input::{Input, InputChange};
+use crate::app::interlock::{Interlock, Rule};
+use crate::app::output::Output;
+use crate::app::site::Site;
+use crate::app::{AppID, device, state, switching};
+use crate::error::{Error, Result};
+use crate::rpi::health::{HealthStatus, RetryPolicy};
+use crate::rpi::i2c::mcp23017::PinChange;
+use crate::rpi::i2c::scan::Detected;
 use chrono::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use std::vec::Vec;
+use tokio::sync::Mutex;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tracing::{debug, error, info, instrument, warn};
@@ -28,6 +38,33 @@ pub enum AppMessage {
         response: oneshot::Sender<Result<()>>,
     },
 
+    /**
+     * Bring a device up or take it down, without restarting
+     */
+    SetDeviceDisabled {
+        device_id: AppID,
+        disabled: bool,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /**
+     * Change a device's notes or model settings, resetting it if the model changed
+     */
+    UpdateDevice {
+        device_id: AppID,
+        fields: models::UpdateDevice,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
+    /**
+     * Rename a device, moving its inputs and outputs with it
+     */
+    RenameDevice {
+        device_id: AppID,
+        new_name: AppID,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
     /**
      * Return all devices
      */
@@ -45,11 +82,13 @@ pub enum AppMessage {
     },
 
     /**
-     * Read a single f64 value with unit from an input
+     * Read a single f64 value with unit from an input, from the device if `fresh` or the cached
+     * reading is too old.
      * result is the value read, or an error
      */
     ReadValue {
         input_id: AppID,
+        fresh: bool,
         response: oneshot::Sender<Result<Dimensioned>>,
     },
 
@@ -58,6 +97,48 @@ pub enum AppMessage {
         response: oneshot::Sender<Result<bool>>,
     },
 
+    CurrentOutputLevel {
+        output_id: AppID,
+        response: oneshot::Sender<Result<f64>>,
+    },
+
+    /**
+     * A change to an output that is being held back by its switching limits, if any
+     */
+    PendingOutputChange {
+        output_id: AppID,
+        response: oneshot::Sender<Result<Option<switching::Pending>>>,
+    },
+
+    /**
+     * Probe the I2C bus, suggesting devices for the addresses that answer
+     */
+    I2cScan {
+        response: oneshot::Sender<Result<Vec<Detected>>>,
+    },
+
+    /**
+     * List the DS18B20 probes on the 1-Wire bus
+     */
+    W1Scan {
+        response: oneshot::Sender<Result<Vec<String>>>,
+    },
+
+    /**
+     * A device interrupt captured a change on one of its pins
+     */
+    PinChanged { device_id: AppID, change: PinChange },
+
+    /**
+     * Set an input's offset so that it currently reads `reference_value`
+     * result is the new offset
+     */
+    CalibrateInput {
+        input_id: AppID,
+        reference_value: f64,
+        response: oneshot::Sender<Result<f64>>,
+    },
+
     /**
      * Read a single boolean value from an input
      * result is the value read, or an error
@@ -76,6 +157,15 @@ pub enum AppMessage {
         response: oneshot::Sender<Result<()>>,
     },
 
+    /**
+     * set a level output to a duty cycle between 0 and 1.
+     */
+    WriteLevel {
+        output_id: AppID,
+        value: f64,
+        response: oneshot::Sender<Result<()>>,
+    },
+
     /**
      * add device, returning id
      */
@@ -142,6 +232,11 @@ pub enum AppMessage {
         response: oneshot::Sender<Result<Vec<device::Slot>>>,
     },
 
+    DeviceHealth {
+        device_id: AppID,
+        response: oneshot::Sender<Result<HealthStatus>>,
+    },
+
     /**
      * Read inputs for a device
      */
@@ -190,6 +285,97 @@ pub enum AppMessage {
         response: oneshot::Sender<Result<AppID>>,
     },
 
+    /**
+     * Update an input
+     */
+    UpdateInput {
+        input_id: AppID,
+        fields: models::UpdateInput,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
+    /**
+     * Rename an input, rewriting automation scripts that read it
+     */
+    RenameInput {
+        input_id: AppID,
+        new_name: AppID,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
+    /**
+     * Rename an output, rewriting interlocks that refer to it
+     */
+    RenameOutput {
+        output_id: AppID,
+        new_name: AppID,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
+    /**
+     * Read all interlocks
+     */
+    GetInterlocks {
+        response: oneshot::Sender<Result<Vec<Interlock>>>,
+    },
+
+    /**
+     * Add an interlock, returning its id
+     */
+    AddInterlock {
+        name: String,
+        rule: Rule,
+        response: oneshot::Sender<Result<AppID>>,
+    },
+
+    /**
+     * remove an interlock.
+     */
+    RemoveInterlock {
+        interlock_id: AppID,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /**
+     * Read the whole configuration as one document
+     */
+    ExportSite {
+        response: oneshot::Sender<Result<Site>>,
+    },
+
+    /**
+     * Replace the whole configuration, restarting all devices
+     */
+    ImportSite {
+        site: Site,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /**
+     * Record a mutation made through the API
+     */
+    RecordAudit {
+        entry: models::NewAuditEntry,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /**
+     * Read recorded mutations, newest first
+     */
+    GetAuditLog {
+        limit: i64,
+        before_id: Option<i32>,
+        response: oneshot::Sender<Result<Vec<AuditEntry>>>,
+    },
+
+    /**
+     * Delete recorded mutations older than the given number of days, returning how many
+     */
+    PruneAuditLog {
+        older_than_days: u32,
+        response: oneshot::Sender<Result<usize>>,
+    },
+
     /**
      * Advance the time of the system to specified value.
      * state machine will update all automated outputs for that given time.
@@ -204,9 +390,11 @@ pub enum AppMessage {
     },
 
     /**
-     * will gracefully terminate the app channel
+     * will gracefully terminate the app channel, after putting outputs in their safe states
      */
-    Terminate,
+    Terminate {
+        response: oneshot::Sender<Result<()>>,
+    },
 }
 
 /**
@@ -217,18 +405,40 @@ pub enum AppMessage {
 pub struct AppChannel {
     sender: mpsc::Sender<AppMessage>,
     users: HashMap<String, String>,
+    admins: HashSet<String>,
+    health: Arc<Health>,
+    input_changes: broadcast::Sender<InputChange>,
 }
 
 impl AppChannel {
     pub async fn terminate(&self) -> Result<()> {
-        self.sender.clone().send(AppMessage::Terminate).await?;
-        Ok(())
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::Terminate { response })
+            .await?;
+        receiver.await?
+    }
+
+    /// Liveness of the app loop behind this channel
+    pub fn health(&self) -> &Arc<Health> {
+        &self.health
+    }
+
+    /// Input changes reported by device interrupts, from now on
+    pub fn subscribe_input_changes(&self) -> broadcast::Receiver<InputChange> {
+        self.input_changes.subscribe()
     }
 
     pub fn hash_for(&self, user: &str) -> Option<&String> {
         self.users.get(user)
     }
 
+    /// Whether `user` may read and prune the audit log
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.contains(user)
+    }
+
     pub async fn set_now(&self) -> Result<()> {
         let time = Local::now();
         Ok(self
@@ -259,6 +469,49 @@ impl AppChannel {
         receiver.await?
     }
 
+    pub async fn set_device_disabled(&self, device_id: AppID, disabled: bool) -> Result<()> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::SetDeviceDisabled {
+                device_id,
+                disabled,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn update_device(
+        &self,
+        device_id: AppID,
+        fields: models::UpdateDevice,
+    ) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::UpdateDevice {
+                device_id,
+                fields,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn rename_device(&self, device_id: AppID, new_name: AppID) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::RenameDevice {
+                device_id,
+                new_name,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
     pub async fn all_devices(&self) -> Result<Vec<Device>> {
         let (response, receiver) = oneshot::channel();
         self.sender
@@ -302,6 +555,31 @@ impl AppChannel {
         receiver.await?
     }
 
+    pub async fn write_level(&self, output_id: AppID, value: f64) -> Result<()> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::WriteLevel {
+                response,
+                output_id,
+                value,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn current_output_level(&self, output_id: AppID) -> Result<f64> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::CurrentOutputLevel {
+                response,
+                output_id,
+            })
+            .await?;
+        receiver.await?
+    }
+
     pub async fn current_output_value(&self, output_id: AppID) -> Result<bool> {
         let (response, receiver) = oneshot::channel();
         self.sender
@@ -314,11 +592,113 @@ impl AppChannel {
         receiver.await?
     }
 
-    pub async fn read_value(&self, input_id: AppID) -> Result<Dimensioned> {
+    pub async fn pending_output_change(
+        &self,
+        output_id: AppID,
+    ) -> Result<Option<switching::Pending>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::PendingOutputChange {
+                response,
+                output_id,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn read_value(&self, input_id: AppID, fresh: bool) -> Result<Dimensioned> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::ReadValue {
+                response,
+                input_id,
+                fresh,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn i2c_scan(&self) -> Result<Vec<Detected>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::I2cScan { response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn export_site(&self) -> Result<Site> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::ExportSite { response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn import_site(&self, site: Site) -> Result<()> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::ImportSite { site, response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn record_audit(&self, entry: models::NewAuditEntry) -> Result<()> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::RecordAudit { entry, response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn audit_log(&self, limit: i64, before_id: Option<i32>) -> Result<Vec<AuditEntry>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::GetAuditLog {
+                limit,
+                before_id,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn prune_audit_log(&self, older_than_days: u32) -> Result<usize> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::PruneAuditLog {
+                older_than_days,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn w1_scan(&self) -> Result<Vec<String>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::W1Scan { response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn calibrate_input(&self, input_id: AppID, reference_value: f64) -> Result<f64> {
         let (response, receiver) = oneshot::channel();
         self.sender
             .clone()
-            .send(AppMessage::ReadValue { response, input_id })
+            .send(AppMessage::CalibrateInput {
+                response,
+                input_id,
+                reference_value,
+            })
             .await?;
         receiver.await?
     }
@@ -455,12 +835,25 @@ impl AppChannel {
         receiver.await?
     }
 
-    pub async fn get_inputs_for_device(&self, device_id: AppID) -> Result<Vec<Input>> {
+    /// How well a device has been answering reads and writes
+    pub async fn device_health(&self, device_id: AppID) -> Result<HealthStatus> {
         let (response, receiver) = oneshot::channel();
         self.sender
             .clone()
-            .send(AppMessage::GetInputsForDevice {
-                response,
+            .send(AppMessage::DeviceHealth {
+                device_id,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn get_inputs_for_device(&self, device_id: AppID) -> Result<Vec<Input>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::GetInputsForDevice {
+                response,
                 device_id,
             })
             .await?;
@@ -485,6 +878,83 @@ impl AppChannel {
         receiver.await?
     }
 
+    pub async fn all_interlocks(&self) -> Result<Vec<Interlock>> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::GetInterlocks { response })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn add_interlock(&self, name: String, rule: Rule) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::AddInterlock {
+                response,
+                name,
+                rule,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn remove_interlock(&self, interlock_id: AppID) -> Result<()> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::RemoveInterlock {
+                response,
+                interlock_id,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn update_input(
+        &self,
+        input_id: AppID,
+        fields: models::UpdateInput,
+    ) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::UpdateInput {
+                input_id,
+                fields,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn rename_input(&self, input_id: AppID, new_name: AppID) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::RenameInput {
+                input_id,
+                new_name,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
+    pub async fn rename_output(&self, output_id: AppID, new_name: AppID) -> Result<AppID> {
+        let (response, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(AppMessage::RenameOutput {
+                output_id,
+                new_name,
+                response,
+            })
+            .await?;
+        receiver.await?
+    }
+
     pub async fn update_output(
         &self,
         output_id: AppID,
@@ -593,13 +1063,8 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
             response,
         } => {
             let get_result = async {
-                let expr = crate::config::parse::bool_expr(&format!("{} == 0", expression))?;
-                match expr {
-                    BoolExpr::Equal(_, a, crate::config::types::Value::Const(_)) => {
-                        crate::config::value::evaluate(state, &a).await
-                    }
-                    _ => Err(crate::error::Error::ParseError),
-                }
+                let expr = crate::config::parse::value_expr(&expression)?;
+                crate::config::value::evaluate(state, &expr).await
             };
             let result = get_result.await;
             match response.send(result) {
@@ -664,6 +1129,17 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
             };
         }
 
+        AppMessage::DeviceHealth {
+            device_id,
+            response,
+        } => {
+            let result = state.device_health(&device_id);
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
         AppMessage::GetInputsForDevice {
             device_id,
             response,
@@ -709,6 +1185,78 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
             };
         }
 
+        AppMessage::SetDeviceDisabled {
+            device_id,
+            disabled,
+            response,
+        } => {
+            let result = state.set_device_disabled(&device_id, disabled).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::UpdateDevice {
+            device_id,
+            fields,
+            response,
+        } => {
+            let result = state.update_device(&device_id, fields).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::UpdateInput {
+            input_id,
+            fields,
+            response,
+        } => {
+            let result = state.update_input(&input_id, fields).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::RenameDevice {
+            device_id,
+            new_name,
+            response,
+        } => {
+            let result = state.rename_device(&device_id, &new_name).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::RenameInput {
+            input_id,
+            new_name,
+            response,
+        } => {
+            let result = state.rename_input(&input_id, &new_name).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::RenameOutput {
+            output_id,
+            new_name,
+            response,
+        } => {
+            let result = state.rename_output(&output_id, &new_name).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
         AppMessage::UpdateOutput {
             output_id,
             fields,
@@ -737,6 +1285,60 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
             };
         }
 
+        AppMessage::GetInterlocks { response } => {
+            let result = state.interlocks();
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::AddInterlock {
+            name,
+            rule,
+            response,
+        } => {
+            let result = state.add_interlock(name, rule).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::RemoveInterlock {
+            interlock_id,
+            response,
+        } => {
+            let result = state.remove_interlock(&interlock_id).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::WriteLevel {
+            output_id,
+            value,
+            response,
+        } => {
+            let result = state.write_output_level(&output_id, value).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::CurrentOutputLevel {
+            output_id,
+            response,
+        } => {
+            let result = state.read_output_level(&output_id).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
         AppMessage::CurrentOutputValue {
             output_id,
             response,
@@ -748,13 +1350,119 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
             };
         }
 
-        AppMessage::ReadValue { input_id, response } => {
-            let result = state.read_input_value(&input_id).await;
+        AppMessage::PendingOutputChange {
+            output_id,
+            response,
+        } => {
+            let result = Ok(state.pending_output_change(&output_id));
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::PinChanged { device_id, change } => {
+            if let Err(e) = state.pin_changed(&device_id, change) {
+                warn!("failed to record change on '{}': {}", device_id, e);
+            }
+        }
+
+        AppMessage::I2cScan { response } => {
+            let result = state.i2c_scan().await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::ExportSite { response } => {
+            let result = state.export_site();
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::ImportSite { site, response } => {
+            let result = state.import_site(site).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::RecordAudit { entry, response } => {
+            let result = state.record_audit(&entry);
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::GetAuditLog {
+            limit,
+            before_id,
+            response,
+        } => {
+            let result = state.audit_log(limit, before_id);
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::PruneAuditLog {
+            older_than_days,
+            response,
+        } => {
+            let result = state.prune_audit_log(older_than_days);
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::W1Scan { response } => {
+            let result = state.w1_scan().await;
             match response.send(result) {
                 Ok(..) => (),
                 Err(e) => error!("send failed: {:?}", e),
             };
         }
+
+        AppMessage::CalibrateInput {
+            input_id,
+            reference_value,
+            response,
+        } => {
+            let result = state.calibrate_input(&input_id, reference_value).await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+        }
+
+        AppMessage::ReadValue {
+            input_id,
+            fresh,
+            response,
+        } => {
+            // the device is read off the loop, so a slow one doesn't hold up everything else
+            match state.input_read(&input_id, fresh) {
+                Ok(read) => {
+                    tokio::spawn(async move {
+                        match response.send(read.await) {
+                            Ok(..) => (),
+                            Err(e) => error!("send failed: {:?}", e),
+                        };
+                    });
+                }
+                Err(e) => match response.send(Err(e)) {
+                    Ok(..) => (),
+                    Err(e) => error!("send failed: {:?}", e),
+                },
+            }
+        }
         AppMessage::GetTime { response } => {
             let result = Ok(state.current_dt());
             match response.send(result) {
@@ -762,22 +1470,152 @@ async fn process_message(message: AppMessage, state: &mut state::State) -> bool
                 Err(e) => error!("send failed: {:?}", e),
             };
         }
-        AppMessage::Terminate => should_terminate = true,
+        AppMessage::Terminate { response } => {
+            let result = state.apply_safe_states().await;
+            match response.send(result) {
+                Ok(..) => (),
+                Err(e) => error!("send failed: {:?}", e),
+            };
+            should_terminate = true
+        }
     }
     return should_terminate;
 }
 
+/// Consecutive failed automation passes before the app loop is restarted
+const MAX_AUTOMATION_FAILURES: u32 = 5;
+
+/// Why the app loop stopped
+enum LoopExit {
+    Terminated,
+    Closed,
+    Failed(Error),
+}
+
+/// Process messages until asked to terminate, or automations keep failing
+async fn run_loop(
+    receiver: Arc<Mutex<mpsc::Receiver<AppMessage>>>,
+    mut state: state::State,
+    health: Arc<Health>,
+) -> LoopExit {
+    let mut receiver = receiver.lock().await;
+    let mut last_emit = Instant::now();
+    let mut failures = 0;
+    while let Some(next) = receiver.recv().await {
+        debug!("processing message: {:?}", &next);
+
+        if process_message(next, &mut state).await {
+            return LoopExit::Terminated;
+        }
+        health.message_processed();
+
+        if last_emit.elapsed().as_millis() > 700 {
+            last_emit = Instant::now();
+            debug!("running automation...");
+            match state.emit_automations().await {
+                Ok(()) => {
+                    failures = 0;
+                    health.automation_succeeded();
+                }
+                Err(e) => {
+                    failures += 1;
+                    error!(
+                        "automations failed ({} of {} allowed): {}",
+                        failures, MAX_AUTOMATION_FAILURES, e
+                    );
+                    if failures >= MAX_AUTOMATION_FAILURES {
+                        return LoopExit::Failed(e);
+                    }
+                }
+            }
+        }
+
+        // TODO: Support sending real time change notification by allowing clients to send a sender to us,
+        // which we'll keep in a list and notify each time we get to here, with removal upon error.
+    }
+    LoopExit::Closed
+}
+
+/// Run the app loop, restarting it with fresh state from `restart` if it fails or panics
+async fn supervise<F, Fut>(
+    receiver: Arc<Mutex<mpsc::Receiver<AppMessage>>>,
+    state: state::State,
+    restart: F,
+    health: Arc<Health>,
+) where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<state::State>>,
+{
+    let mut state = Some(state);
+    loop {
+        let current = match state.take() {
+            Some(s) => s,
+            None => match restart().await {
+                Ok(s) => s,
+                Err(e) => {
+                    let failures = health.restart_failed(e.to_string());
+                    let delay = restart_delay(failures);
+                    error!(
+                        "failed to restart app state {} times in a row, trying again in {:?}: {}",
+                        failures, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            },
+        };
+
+        health.started();
+        let exit = tokio::spawn(run_loop(receiver.clone(), current, health.clone())).await;
+        match exit {
+            Ok(LoopExit::Terminated) => {
+                info!("terminating channel");
+                health.stopped(false);
+                break;
+            }
+            Ok(LoopExit::Closed) => {
+                info!("all app channels closed");
+                health.stopped(false);
+                break;
+            }
+            Ok(LoopExit::Failed(e)) => {
+                error!("app loop failed, restarting: {}", e);
+                health.failed(format!("app loop failed: {}", e));
+            }
+            Err(e) => {
+                error!("app loop panicked, restarting: {}", e);
+                health.failed(format!("app loop panicked: {}", e));
+            }
+        }
+        health.stopped(true);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
 pub async fn start_app(
     bus: u8,
     here: (f64, f64),
-    path: &std::path::Path,
+    reading_max_age: Duration,
+    retry: RetryPolicy,
+    db: db::Db,
     users: HashMap<String, String>,
+    admins: HashSet<String>,
 ) -> Result<AppChannel> {
-    let (sender, mut receiver) = mpsc::channel::<AppMessage>(10);
-
-    let db = db::Db::start_db(path)?;
-
-    let mut state = state::new_state(bus, here, db).await?;
+    let (sender, receiver) = mpsc::channel::<AppMessage>(10);
+
+    // slow subscribers miss old changes rather than holding up the app
+    let (input_changes, _) = broadcast::channel(64);
+
+    let state = state::new_state(
+        bus,
+        here,
+        reading_max_age,
+        retry,
+        db.clone(),
+        sender.clone(),
+        input_changes.clone(),
+    )
+    .await?;
 
     let sender_clone = sender.clone();
 
@@ -797,27 +1635,112 @@ pub async fn start_app(
         }
     });
 
-    tokio::spawn(async move {
-        let mut last_emit = Instant::now();
-        while let Some(next) = receiver.recv().await {
-            debug!("processing message: {:?}", &next);
+    let health = Arc::new(Health::new());
+    let receiver = Arc::new(Mutex::new(receiver));
+    let restart = {
+        let sender = sender.clone();
+        let input_changes = input_changes.clone();
+        move || {
+            state::new_state(
+                bus,
+                here,
+                reading_max_age,
+                retry,
+                db.clone(),
+                sender.clone(),
+                input_changes.clone(),
+            )
+        }
+    };
+    tokio::spawn(supervise(receiver, state, restart, health.clone()));
+
+    Ok(AppChannel {
+        sender,
+        users,
+        admins,
+        health,
+        input_changes,
+    })
+}
 
-            if process_message(next, &mut state).await {
-                info!("terminating channel");
-                break;
-            } else if last_emit.elapsed().as_millis() > 700 {
-                last_emit = Instant::now();
-                debug!("running automation...");
-                state
-                    .emit_automations()
-                    .await
-                    .expect("emit automations errors");
-            }
+#[cfg(all(test, feature = "mock-gpio"))]
+mod tests {
+    use super::*;
+    use crate::config::types::Unit;
+    use crate::rpi::driver::{self, Driver};
+    use futures::future::BoxFuture;
+
+    /// Takes `delay` to answer every read
+    #[derive(Debug)]
+    struct Slow {
+        delay: Duration,
+    }
 
-            // TODO: Support sending real time change notification by allowing clients to send a sender to us,
-            // which we'll keep in a list and notify each time we get to here, with removal upon error.
+    impl Driver for Slow {
+        fn slots(&self) -> Vec<device::Slot> {
+            vec![driver::input_slot(Unit::Volts)]
         }
-    });
 
-    Ok(AppChannel { sender, users })
+        fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn read_sensor(&self, _index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(Dimensioned::from_volts(1.0))
+            })
+        }
+    }
+
+    async fn app(name: &str) -> AppChannel {
+        let path =
+            std::env::temp_dir().join(format!("restedpi-channel-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        start_app(
+            1,
+            (0.0, 0.0),
+            Duration::from_millis(0),
+            RetryPolicy::default(),
+            db::Db::start_db(&path).unwrap(),
+            HashMap::new(),
+            HashSet::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_reads_do_not_hold_up_other_queries() {
+        driver::register(
+            "SlowForChannel",
+            Arc::new(|_, _| {
+                Ok(Box::new(Slow {
+                    delay: Duration::from_millis(1000),
+                }))
+            }),
+        );
+        let app = app("slow").await;
+        let model = device::Type::Custom(device::Custom {
+            driver: "SlowForChannel".to_string(),
+            config: String::new(),
+        });
+        let device_id = app
+            .add_device(model, "slow".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let input_id = app
+            .add_input(models::NewInput::new("reading".to_string(), device_id, 0))
+            .await
+            .unwrap();
+
+        let reader = app.clone();
+        let read = tokio::spawn(async move { reader.read_value(input_id, true).await });
+        // let the read reach the device before asking for something unrelated
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let asked = Instant::now();
+        app.get_now().await.unwrap();
+        assert!(asked.elapsed() < Duration::from_millis(500));
+        assert_eq!(read.await.unwrap(), Ok(Dimensioned::from_volts(1.0)));
+    }
 }
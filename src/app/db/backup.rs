@@ -0,0 +1,433 @@
+use super::{Db, MIGRATIONS, schema_version};
+use crate::error::{Error, Result};
+use chrono::Local;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use libsqlite3_sys as ffi;
+use std::ffi::{CStr, CString, c_int};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+const BACKUP_PREFIX: &str = "rpi-";
+const BACKUP_SUFFIX: &str = ".sql3";
+
+/// Pages copied in each step of a backup, a few hundred KB at the default page size
+const PAGES_PER_STEP: c_int = 64;
+
+/// How long a backup leaves the database alone between steps, for writers to get in
+const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+/// Tables a database must have before we'll restore it. These are the ones every schema version
+/// has, so backups from before the migrations were written can still be restored.
+const REQUIRED_TABLES: &[&str] = &["devices", "inputs", "outputs"];
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    integrity_check: String,
+}
+
+#[derive(QueryableByName)]
+struct TableCount {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    count: i64,
+}
+
+impl Db {
+    /// Write a consistent copy of the database to `dest`, while it stays in use.
+    ///
+    /// This is SQLite's online backup API, copying `PAGES_PER_STEP` pages at a time and pausing
+    /// between steps, so writers only wait for a step rather than the whole copy. If the database
+    /// is written to mid-copy, SQLite starts the copy again. diesel doesn't hand out the pooled
+    /// connections' handles, so the copy is read through a connection of its own.
+    ///
+    /// The copy is made beside `dest` and renamed into place, so `dest` is never left half written.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        let partial = partial_path(dest);
+        if partial.exists() {
+            fs::remove_file(&partial)?;
+        }
+        let source = Handle::open(&self.file, ffi::SQLITE_OPEN_READONLY)?;
+        let target = Handle::open(
+            &partial,
+            ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+        )?;
+        let main = c"main";
+        let backup =
+            unsafe { ffi::sqlite3_backup_init(target.0, main.as_ptr(), source.0, main.as_ptr()) };
+        if backup.is_null() {
+            return Err(target.error("start the backup"));
+        }
+        let stepped = loop {
+            match unsafe { ffi::sqlite3_backup_step(backup, PAGES_PER_STEP) } {
+                ffi::SQLITE_DONE => break ffi::SQLITE_OK,
+                ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => {
+                    std::thread::sleep(STEP_PAUSE)
+                }
+                failed => break failed,
+            }
+        };
+        // finishing reports the first error of any step
+        if unsafe { ffi::sqlite3_backup_finish(backup) } != ffi::SQLITE_OK
+            || stepped != ffi::SQLITE_OK
+        {
+            return Err(target.error("back up"));
+        }
+        drop(target);
+        fs::rename(&partial, dest)?;
+        Ok(())
+    }
+
+    /// Back up into `dir` under a timestamped name, then delete all but the newest `keep`
+    pub fn backup_rotated(&self, dir: &Path, keep: usize) -> Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let dest = dir.join(format!(
+            "{}{}{}",
+            BACKUP_PREFIX,
+            Local::now().format("%Y%m%d-%H%M%S"),
+            BACKUP_SUFFIX
+        ));
+        self.backup(&dest)?;
+        rotate(dir, keep)?;
+        Ok(dest)
+    }
+}
+
+/// A raw connection, for the parts of SQLite diesel doesn't expose. Closed when dropped.
+struct Handle(*mut ffi::sqlite3);
+
+impl Handle {
+    fn open(file: &Path, flags: c_int) -> Result<Self> {
+        let name = file
+            .to_str()
+            .and_then(|name| CString::new(name).ok())
+            .ok_or_else(|| Error::IoError(format!("Database path {:?} is not UTF-8", file)))?;
+        let mut db = ptr::null_mut();
+        let opened = unsafe { ffi::sqlite3_open_v2(name.as_ptr(), &mut db, flags, ptr::null()) };
+        // a handle comes back even when opening fails, to report why and be closed
+        let handle = Handle(db);
+        if opened != ffi::SQLITE_OK {
+            return Err(handle.error(&format!("open {:?}", file)));
+        }
+        Ok(handle)
+    }
+
+    fn error(&self, doing: &str) -> Error {
+        let message = if self.0.is_null() {
+            "out of memory".into()
+        } else {
+            unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }.to_string_lossy()
+        };
+        Error::DbError(format!("Failed to {}: {}", doing, message))
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
+
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    dest.with_file_name(name)
+}
+
+/// Delete all but the newest `keep` backups in `dir`, returning the ones deleted.
+///
+/// Only files named like `backup_rotated` names them are touched; their timestamps sort by age.
+pub fn rotate(dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(BACKUP_PREFIX) && n.ends_with(BACKUP_SUFFIX))
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = backups.drain(..excess).collect();
+    for path in &removed {
+        fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+/// Check that `file` is an intact restedpi database this build can migrate, returning its
+/// schema version
+pub fn check_backup(file: &Path) -> Result<usize> {
+    if !file.is_file() {
+        return Err(Error::NonExistant(format!("{:?}", file)));
+    }
+    let uri = file
+        .to_str()
+        .ok_or_else(|| Error::IoError(format!("Backup path {:?} is not UTF-8", file)))?;
+    let mut conn = SqliteConnection::establish(&format!("file:{}?mode=ro", uri))
+        .map_err(|e| Error::DbError(format!("Failed to open {:?}: {}", file, e)))?;
+
+    let check: IntegrityCheck =
+        diesel::sql_query("PRAGMA integrity_check").get_result(&mut conn)?;
+    if check.integrity_check != "ok" {
+        return Err(Error::DbError(format!(
+            "{:?} is damaged: {}",
+            file, check.integrity_check
+        )));
+    }
+    for table in REQUIRED_TABLES {
+        let found: TableCount = diesel::sql_query(
+            "SELECT COUNT(*) AS count FROM sqlite_master WHERE type = 'table' AND name = ?",
+        )
+        .bind::<diesel::sql_types::Text, _>(*table)
+        .get_result(&mut conn)?;
+        if found.count == 0 {
+            return Err(Error::DbError(format!(
+                "{:?} has no {} table, it isn't a restedpi database",
+                file, table
+            )));
+        }
+    }
+    let version = schema_version(&mut conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::DbError(format!(
+            "{:?} has schema version {}, newer than this build's {}",
+            file,
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+    Ok(version)
+}
+
+/// Replace the database in `path` with `backup`, once it passes `check_backup`.
+///
+/// The server must be stopped. The live database is locked exclusively for the whole swap, and
+/// the restore is refused if it can't be, so a server still writing to it is never swapped out
+/// from under. Taking the lock also rolls back any hot journal into the database it belongs to,
+/// before that database is replaced. The current database is kept as `rpi.sql3.before-restore`,
+/// and the backup is copied beside it and renamed into place so there is always a whole database.
+/// Older schemas are migrated the next time the database is opened.
+pub fn restore(path: &Path, backup: &Path) -> Result<usize> {
+    let version = check_backup(backup)?;
+    let db_file = path.join("rpi.sql3");
+    let journal = path.join("rpi.sql3-journal");
+    let incoming = path.join("rpi.sql3.restore");
+    fs::copy(backup, &incoming)?;
+    let _lock = match lock(&db_file) {
+        Ok(lock) => lock,
+        Err(e) => {
+            fs::remove_file(&incoming)?;
+            return Err(e);
+        }
+    };
+    if db_file.exists() {
+        fs::copy(&db_file, path.join("rpi.sql3.before-restore"))?;
+    }
+    // rolled back by taking the lock, so anything left over belongs to no transaction
+    if journal.exists() {
+        fs::remove_file(journal)?;
+    }
+    fs::rename(&incoming, &db_file)?;
+    info!("Restored {:?} from {:?}", db_file, backup);
+    Ok(version)
+}
+
+/// Hold an exclusive lock on the database in `file` until the connection is dropped, failing at
+/// once if anything else has it locked. `None` if there's no database there yet.
+fn lock(file: &Path) -> Result<Option<SqliteConnection>> {
+    if !file.exists() {
+        return Ok(None);
+    }
+    let uri = file
+        .to_str()
+        .ok_or_else(|| Error::IoError(format!("Database path {:?} is not UTF-8", file)))?;
+    let mut conn = SqliteConnection::establish(uri)
+        .map_err(|e| Error::DbError(format!("Failed to open {:?}: {}", file, e)))?;
+    conn.batch_execute("PRAGMA busy_timeout = 0; BEGIN EXCLUSIVE")
+        .map_err(|e| {
+            Error::DbError(format!(
+                "{:?} is in use, stop the server before restoring: {}",
+                file, e
+            ))
+        })?;
+    Ok(Some(conn))
+}
+
+/// Back `db` up into `dir` every `interval`, keeping the newest `keep` backups
+pub fn start(db: Db, dir: PathBuf, interval: Duration, keep: usize) -> JoinHandle<()> {
+    info!(
+        "Backing up the database to {:?} every {:?}, keeping {}",
+        dir, interval, keep
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick is immediate, so a restart doesn't push the next backup a whole interval
+        loop {
+            ticker.tick().await;
+            let db = db.clone();
+            let dir = dir.clone();
+            match tokio::task::spawn_blocking(move || db.backup_rotated(&dir, keep)).await {
+                Ok(Ok(dest)) => info!("Backed up the database to {:?}", dest),
+                Ok(Err(e)) => error!("database backup failed: {}", e),
+                Err(e) => error!("database backup task failed: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("restedpi-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn backs_up_and_restores() {
+        let dir = scratch("restore");
+        let db = Db::start_db(&dir.join("live")).unwrap();
+        let backup = dir.join("copy.sql3");
+        db.backup(&backup).unwrap();
+        assert_eq!(check_backup(&backup).unwrap(), MIGRATIONS.len());
+
+        let restored = dir.join("restored");
+        fs::create_dir_all(&restored).unwrap();
+        restore(&restored, &backup).unwrap();
+        Db::start_db(&restored).unwrap().export().unwrap();
+
+        fs::write(dir.join("junk.sql3"), b"not a database").unwrap();
+        assert!(restore(&restored, &dir.join("junk.sql3")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backs_up_while_a_write_is_under_way() {
+        let dir = scratch("busy");
+        let db = Db::start_db(&dir.join("live")).unwrap();
+        let model = crate::app::device::Type::Custom(crate::app::device::Custom {
+            driver: "Relay".to_string(),
+            config: String::new(),
+        });
+        db.add_device(&crate::app::db::models::NewDevice::new(
+            model,
+            "relay".to_string(),
+            "Relay".to_string(),
+            String::new(),
+            None,
+        ))
+        .unwrap();
+        let mut writer = db.db.get().unwrap();
+        writer
+            .batch_execute("BEGIN IMMEDIATE; DELETE FROM devices")
+            .unwrap();
+
+        let backup = dir.join("copy.sql3");
+        db.backup(&backup).unwrap();
+        writer.batch_execute("COMMIT").unwrap();
+        drop(writer);
+
+        let restored = dir.join("restored");
+        fs::create_dir_all(&restored).unwrap();
+        restore(&restored, &backup).unwrap();
+        let devices = Db::start_db(&restored).unwrap().devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert!(!partial_path(&backup).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_restore_over_a_database_in_use() {
+        let dir = scratch("in-use");
+        let backup = dir.join("copy.sql3");
+        Db::start_db(&dir.join("old"))
+            .unwrap()
+            .backup(&backup)
+            .unwrap();
+
+        let live = dir.join("live");
+        let db = Db::start_db(&live).unwrap();
+        let mut conn = db.db.get().unwrap();
+        conn.batch_execute("BEGIN IMMEDIATE").unwrap();
+        assert!(matches!(restore(&live, &backup), Err(Error::DbError(_))));
+        assert!(!live.join("rpi.sql3.restore").exists());
+        assert!(!live.join("rpi.sql3.before-restore").exists());
+
+        conn.batch_execute("ROLLBACK").unwrap();
+        drop(conn);
+        restore(&live, &backup).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_backups_from_before_migrations() {
+        let dir = scratch("version-0");
+        let backup = dir.join("old.sql3");
+        let mut conn = SqliteConnection::establish(backup.to_str().unwrap()).unwrap();
+        for statement in [
+            "CREATE TABLE devices(
+                name TEXT NOT NULL PRIMARY KEY,
+                model TEXT NOT NULL,
+                notes TEXT NOT NULL,
+                disabled BOOLEAN NOT NULL DEFAULT FALSE,
+                created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+            "CREATE TABLE inputs(
+                name TEXT NOT NULL PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                device_input_id INT NOT NULL,
+                created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+            "CREATE TABLE outputs(
+                name TEXT NOT NULL PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                device_output_id INT NOT NULL,
+                active_low BOOLEAN NOT NULL DEFAULT FALSE,
+                automation_script TEXT,
+                created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        ] {
+            diesel::sql_query(statement).execute(&mut conn).unwrap();
+        }
+        drop(conn);
+        assert_eq!(check_backup(&backup).unwrap(), 0);
+
+        let restored = dir.join("restored");
+        fs::create_dir_all(&restored).unwrap();
+        assert_eq!(restore(&restored, &backup).unwrap(), 0);
+        let db = Db::start_db(&restored).unwrap();
+        assert!(db.export().unwrap().interlocks.is_empty());
+        let mut conn = db.db.get().unwrap();
+        assert_eq!(schema_version(&mut conn).unwrap(), MIGRATIONS.len());
+        drop(conn);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_oldest_backups() {
+        let dir = scratch("rotate");
+        for name in [
+            "rpi-20260101-000000.sql3",
+            "rpi-20260102-000000.sql3",
+            "rpi-20260103-000000.sql3",
+            "other.sql3",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let removed = rotate(&dir, 2).unwrap();
+        assert_eq!(removed, vec![dir.join("rpi-20260101-000000.sql3")]);
+        assert!(dir.join("other.sql3").exists());
+        assert!(rotate(&dir, 2).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
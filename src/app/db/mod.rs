@@ -1,11 +1,14 @@
+pub mod backup;
 pub mod models;
 
 use crate::app::AppID;
+use crate::app::site::Site;
 use crate::error::{Error, Result};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
 use diesel::sqlite::SqliteConnection;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
@@ -15,6 +18,7 @@ const SCHEMA_STATEMENTS: &[&str] = &[
     "PRAGMA foreign_keys = ON",
     "CREATE TABLE IF NOT EXISTS devices(
         name TEXT NOT NULL PRIMARY KEY,
+        display_name TEXT NOT NULL DEFAULT '',
         model TEXT NOT NULL,
         notes TEXT NOT NULL,
         disabled BOOLEAN NOT NULL DEFAULT FALSE,
@@ -22,29 +26,150 @@ const SCHEMA_STATEMENTS: &[&str] = &[
     )",
     "CREATE TABLE IF NOT EXISTS inputs(
         name TEXT NOT NULL PRIMARY KEY,
+        display_name TEXT NOT NULL DEFAULT '',
         device_id TEXT NOT NULL,
         device_input_id INT NOT NULL,
+        offset REAL NOT NULL DEFAULT 0,
+        scale REAL NOT NULL DEFAULT 1,
+        calibration TEXT,
         created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
         FOREIGN KEY (device_id) REFERENCES devices(name) ON DELETE CASCADE
     )",
     "CREATE TABLE IF NOT EXISTS outputs(
         name TEXT NOT NULL PRIMARY KEY,
+        display_name TEXT NOT NULL DEFAULT '',
         device_id TEXT NOT NULL,
         device_output_id INT NOT NULL,
         active_low BOOLEAN NOT NULL DEFAULT FALSE,
         automation_script TEXT,
+        min_on INT,
+        min_off INT,
+        max_switches_per_hour INT,
+        safe_state BOOLEAN,
+        error_policy TEXT NOT NULL DEFAULT 'hold',
+        kind TEXT NOT NULL DEFAULT 'boolean',
         created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
         FOREIGN KEY (device_id) REFERENCES devices(name) ON DELETE CASCADE
     )",
+    "CREATE TABLE IF NOT EXISTS interlocks(
+        name TEXT NOT NULL PRIMARY KEY,
+        rule TEXT NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )",
+    "CREATE TABLE IF NOT EXISTS audit_log(
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user TEXT NOT NULL,
+        mutation TEXT NOT NULL,
+        arguments TEXT NOT NULL,
+        succeeded BOOLEAN NOT NULL,
+        error TEXT,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )",
+    "CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log(created_at)",
+];
+
+/// SQL statements that bring a database created by an older release up to date.
+///
+/// The database records how many of these have been applied in `PRAGMA user_version`, so entries
+/// must only ever be appended. A freshly bootstrapped database is created with the full schema
+/// above and starts at the latest version.
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: output interlocks
+    &["CREATE TABLE IF NOT EXISTS interlocks(
+        name TEXT NOT NULL PRIMARY KEY,
+        rule TEXT NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )"],
+    // 2: output switching limits
+    &[
+        "ALTER TABLE outputs ADD COLUMN min_on INT",
+        "ALTER TABLE outputs ADD COLUMN min_off INT",
+        "ALTER TABLE outputs ADD COLUMN max_switches_per_hour INT",
+    ],
+    // 3: fail-safe output states
+    &[
+        "ALTER TABLE outputs ADD COLUMN safe_state BOOLEAN",
+        "ALTER TABLE outputs ADD COLUMN error_policy TEXT NOT NULL DEFAULT 'hold'",
+    ],
+    // 4: input calibration
+    &[
+        "ALTER TABLE inputs ADD COLUMN offset REAL NOT NULL DEFAULT 0",
+        "ALTER TABLE inputs ADD COLUMN scale REAL NOT NULL DEFAULT 1",
+        "ALTER TABLE inputs ADD COLUMN calibration TEXT",
+    ],
+    // 5: level (PWM) outputs
+    &["ALTER TABLE outputs ADD COLUMN kind TEXT NOT NULL DEFAULT 'boolean'"],
+    // 6: display names, separate from the generated IDs
+    &[
+        "ALTER TABLE devices ADD COLUMN display_name TEXT NOT NULL DEFAULT ''",
+        "ALTER TABLE inputs ADD COLUMN display_name TEXT NOT NULL DEFAULT ''",
+        "ALTER TABLE outputs ADD COLUMN display_name TEXT NOT NULL DEFAULT ''",
+        "UPDATE devices SET display_name = name",
+        "UPDATE inputs SET display_name = name",
+        "UPDATE outputs SET display_name = name",
+    ],
+    // 7: audit log of API mutations
+    &[
+        "CREATE TABLE IF NOT EXISTS audit_log(
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user TEXT NOT NULL,
+        mutation TEXT NOT NULL,
+        arguments TEXT NOT NULL,
+        succeeded BOOLEAN NOT NULL,
+        error TEXT,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )",
+        "CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log(created_at)",
+    ],
 ];
 
+#[derive(QueryableByName)]
+struct UserVersion {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    user_version: i32,
+}
+
+fn schema_version(conn: &mut SqliteConnection) -> Result<usize> {
+    let version: UserVersion = diesel::sql_query("PRAGMA user_version").get_result(conn)?;
+    Ok(version.user_version.max(0) as usize)
+}
+
+fn set_schema_version(conn: &mut SqliteConnection, version: usize) -> Result<()> {
+    diesel::sql_query(format!("PRAGMA user_version = {}", version)).execute(conn)?;
+    Ok(())
+}
+
+/// Wait for locks rather than failing at once, so a backup or the CLI can share the database
+/// with a running server
+#[derive(Debug)]
+struct BusyTimeout;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for BusyTimeout {
+    fn on_acquire(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> std::result::Result<(), diesel::r2d2::Error> {
+        diesel::sql_query("PRAGMA busy_timeout = 5000")
+            .execute(conn)
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
 fn get_pool(db_url: &str) -> Result<DbPool> {
     let manager = ConnectionManager::<SqliteConnection>::new(db_url);
-    Pool::new(manager).map_err(|e| Error::DbError(format!("Failed to create DB pool: {}", e)))
+    Pool::builder()
+        .connection_customizer(Box::new(BusyTimeout))
+        .build(manager)
+        .map_err(|e| Error::DbError(format!("Failed to create DB pool: {}", e)))
 }
 
+#[derive(Clone)]
 pub struct Db {
     db: DbPool,
+
+    /// The database file, for backups that copy it page by page
+    file: PathBuf,
 }
 
 impl Db {
@@ -84,12 +209,13 @@ impl Db {
             ))
         })?;
 
+        let mut conn = pool.get().map_err(|e| {
+            Error::DbError(format!("Failed to get connection for bootstrap: {}", e))
+        })?;
+
         // Bootstrap schema if this is a new database
         if needs_bootstrap {
             info!("Bootstrapping new database at {:?}", db_file);
-            let mut conn = pool.get().map_err(|e| {
-                Error::DbError(format!("Failed to get connection for bootstrap: {}", e))
-            })?;
 
             for statement in SCHEMA_STATEMENTS {
                 diesel::sql_query(*statement)
@@ -98,13 +224,33 @@ impl Db {
                         Error::DbError(format!("Failed to execute schema statement: {}", e))
                     })?;
             }
+            set_schema_version(&mut conn, MIGRATIONS.len())?;
 
             info!("Database schema created successfully");
         } else {
             info!("Using existing database at {:?}", db_file);
+            let version = schema_version(&mut conn)?;
+            for (index, statements) in MIGRATIONS.iter().enumerate().skip(version) {
+                info!("Migrating database schema to version {}", index + 1);
+                for statement in statements.iter() {
+                    diesel::sql_query(*statement)
+                        .execute(&mut conn)
+                        .map_err(|e| {
+                            Error::DbError(format!(
+                                "Failed to execute migration {}: {}",
+                                index + 1,
+                                e
+                            ))
+                        })?;
+                }
+                set_schema_version(&mut conn, index + 1)?;
+            }
         }
 
-        Ok(Db { db: pool })
+        Ok(Db {
+            db: pool,
+            file: db_file,
+        })
     }
 
     pub fn add_device(&self, new_device: &models::NewDevice) -> Result<models::Device> {
@@ -139,6 +285,62 @@ impl Db {
         Ok(devices.filter(name.eq(did)).first(&mut db)?)
     }
 
+    pub fn set_device_disabled(&self, did: &AppID, value: bool) -> Result<models::Device> {
+        use crate::schema::devices::dsl::*;
+        use crate::schema::devices::table;
+        let mut db = self.db.get()?;
+        let res = diesel::update(table)
+            .filter(name.eq(did))
+            .set(disabled.eq(value))
+            .execute(&mut db)?;
+        info!("updated {} rows of device table", res);
+        let r: models::Device = devices.find(did).first(&mut db)?;
+        Ok(r)
+    }
+
+    pub fn update_device(
+        &self,
+        did: &AppID,
+        new_model: &crate::app::device::Type,
+        new_display_name: &str,
+        new_notes: &str,
+    ) -> Result<models::Device> {
+        use crate::schema::devices::dsl::*;
+        use crate::schema::devices::table;
+        let mut db = self.db.get()?;
+        let res = diesel::update(table)
+            .filter(name.eq(did))
+            .set((
+                model.eq(serde_json::to_string(new_model)?),
+                display_name.eq(new_display_name),
+                notes.eq(new_notes),
+            ))
+            .execute(&mut db)?;
+        info!("updated {} rows of device table", res);
+        let r: models::Device = devices.find(did).first(&mut db)?;
+        Ok(r)
+    }
+
+    /// Rename a device, moving its inputs and outputs with it
+    pub fn rename_device(&self, old_id: &AppID, new_id: &AppID) -> Result<()> {
+        use crate::schema::{devices, inputs, outputs};
+        let mut db = self.db.get()?;
+        db.transaction(|conn| {
+            // children point at the old name until the end of the transaction
+            diesel::sql_query("PRAGMA defer_foreign_keys = ON").execute(conn)?;
+            diesel::update(devices::dsl::devices.filter(devices::dsl::name.eq(old_id)))
+                .set(devices::dsl::name.eq(new_id))
+                .execute(conn)?;
+            diesel::update(inputs::dsl::inputs.filter(inputs::dsl::device_id.eq(old_id)))
+                .set(inputs::dsl::device_id.eq(new_id))
+                .execute(conn)?;
+            diesel::update(outputs::dsl::outputs.filter(outputs::dsl::device_id.eq(old_id)))
+                .set(outputs::dsl::device_id.eq(new_id))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
     pub fn devices(&self) -> Result<Vec<models::Device>> {
         use crate::schema::devices::dsl::*;
         let mut db = self.db.get()?;
@@ -213,6 +415,120 @@ impl Db {
         Ok(r)
     }
 
+    /// Rename an input, saving the automation scripts rewritten to refer to its new name
+    pub fn rename_input(
+        &self,
+        old_id: &AppID,
+        new_id: &AppID,
+        scripts: &[(AppID, String)],
+    ) -> Result<()> {
+        use crate::schema::{inputs, outputs};
+        let mut db = self.db.get()?;
+        db.transaction(|conn| {
+            diesel::update(inputs::dsl::inputs.filter(inputs::dsl::name.eq(old_id)))
+                .set(inputs::dsl::name.eq(new_id))
+                .execute(conn)?;
+            for (output_id, script) in scripts {
+                diesel::update(outputs::dsl::outputs.filter(outputs::dsl::name.eq(output_id)))
+                    .set(outputs::dsl::automation_script.eq(script))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_input_offset(&self, iid: &AppID, new_offset: f64) -> Result<models::Input> {
+        use crate::schema::inputs::dsl::*;
+        use crate::schema::inputs::table;
+        let mut db = self.db.get()?;
+        let res = diesel::update(table)
+            .filter(name.eq(iid))
+            .set(offset.eq(new_offset))
+            .execute(&mut db)?;
+        info!("updated {} rows of input table", res);
+        let r: models::Input = inputs.find(iid).first(&mut db)?;
+        Ok(r)
+    }
+
+    pub fn update_input(
+        &self,
+        old_input_id: &AppID,
+        fields: &models::UpdateInput,
+    ) -> Result<models::Input> {
+        use crate::schema::inputs::dsl::*;
+        use crate::schema::inputs::table;
+        let mut db = self.db.get()?;
+
+        if let models::UpdateInput {
+            display_name: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(display_name.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        if let models::UpdateInput {
+            device_id: Some(f), ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(device_id.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        if let models::UpdateInput {
+            device_input_id: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(device_input_id.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        if let models::UpdateInput {
+            offset: Some(f), ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(offset.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        if let models::UpdateInput { scale: Some(f), .. } = fields {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(scale.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        if let models::UpdateInput {
+            calibration: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_input_id))
+                .set(calibration.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of input table", res);
+        }
+
+        let r: models::Input = inputs.find(old_input_id).first(&mut db)?;
+        Ok(r)
+    }
+
     pub fn update_output(
         &self,
         old_output_id: &AppID,
@@ -222,6 +538,18 @@ impl Db {
         use crate::schema::outputs::table;
         let mut db = self.db.get()?;
 
+        if let models::UpdateOutput {
+            display_name: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(display_name.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
         if let models::UpdateOutput {
             device_output_id: Some(f),
             ..
@@ -258,10 +586,92 @@ impl Db {
             info!("updated {} rows of output table", res);
         }
 
+        if let models::UpdateOutput {
+            min_on: Some(f), ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(min_on.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
+        if let models::UpdateOutput {
+            min_off: Some(f), ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(min_off.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
+        if let models::UpdateOutput {
+            max_switches_per_hour: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(max_switches_per_hour.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
+        if let models::UpdateOutput {
+            safe_state: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(safe_state.eq(f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
+        if let models::UpdateOutput {
+            error_policy: Some(f),
+            ..
+        } = fields
+        {
+            let ex = diesel::update(table)
+                .filter(name.eq(old_output_id))
+                .set(error_policy.eq(*f));
+            let res = ex.execute(&mut db)?;
+            info!("updated {} rows of output table", res);
+        }
+
         let r: models::Output = outputs.find(old_output_id).first(&mut db)?;
         Ok(r)
     }
 
+    /// Rename an output, saving the interlock rules rewritten to refer to its new name
+    pub fn rename_output(
+        &self,
+        old_id: &AppID,
+        new_id: &AppID,
+        rules: &[(AppID, String)],
+    ) -> Result<()> {
+        use crate::schema::{interlocks, outputs};
+        let mut db = self.db.get()?;
+        db.transaction(|conn| {
+            diesel::update(outputs::dsl::outputs.filter(outputs::dsl::name.eq(old_id)))
+                .set(outputs::dsl::name.eq(new_id))
+                .execute(conn)?;
+            for (interlock_id, rule) in rules {
+                diesel::update(
+                    interlocks::dsl::interlocks.filter(interlocks::dsl::name.eq(interlock_id)),
+                )
+                .set(interlocks::dsl::rule.eq(rule))
+                .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
     pub fn add_output(&self, new_output: &models::NewOutput) -> Result<models::Output> {
         use crate::schema::outputs::dsl::*;
         use crate::schema::outputs::table;
@@ -285,4 +695,110 @@ impl Db {
         let mut db = self.db.get()?;
         Ok(outputs.filter(device_id.eq(d_id)).load(&mut db)?)
     }
+
+    pub fn interlocks(&self) -> Result<Vec<models::Interlock>> {
+        use crate::schema::interlocks::dsl::*;
+        let mut db = self.db.get()?;
+        Ok(interlocks.load(&mut db)?)
+    }
+
+    pub fn add_interlock(&self, new_interlock: &models::NewInterlock) -> Result<models::Interlock> {
+        use crate::schema::interlocks::dsl::*;
+        use crate::schema::interlocks::table;
+        let mut db = self.db.get()?;
+        let res = diesel::insert_into(table)
+            .values(new_interlock)
+            .execute(&mut db)?;
+        info!("Added {} rows to interlock table", res);
+        let r: models::Interlock = interlocks.find(&new_interlock.name).first(&mut db)?;
+        Ok(r)
+    }
+
+    pub fn remove_interlock(&self, id: &AppID) -> Result<()> {
+        use crate::schema::interlocks::dsl::*;
+        let mut db = self.db.get()?;
+        diesel::delete(interlocks.filter(name.eq(id))).execute(&mut db)?;
+        Ok(())
+    }
+
+    pub fn add_audit_entry(&self, entry: &models::NewAuditEntry) -> Result<()> {
+        use crate::schema::audit_log::table;
+        let mut db = self.db.get()?;
+        diesel::insert_into(table).values(entry).execute(&mut db)?;
+        Ok(())
+    }
+
+    /// Up to `limit` audit entries, newest first, starting before `before_id` if given
+    pub fn audit_log(&self, limit: i64, before_id: Option<i32>) -> Result<Vec<models::AuditEntry>> {
+        use crate::schema::audit_log::dsl::*;
+        let mut db = self.db.get()?;
+        let mut query = audit_log.order(id.desc()).limit(limit).into_boxed();
+        if let Some(before) = before_id {
+            query = query.filter(id.lt(before));
+        }
+        Ok(query.load(&mut db)?)
+    }
+
+    /// Delete audit entries recorded before `cutoff` (UTC), returning how many were deleted
+    pub fn prune_audit_log(&self, cutoff: NaiveDateTime) -> Result<usize> {
+        use crate::schema::audit_log::dsl::*;
+        let mut db = self.db.get()?;
+        Ok(diesel::delete(audit_log.filter(created_at.lt(cutoff))).execute(&mut db)?)
+    }
+
+    /// Everything in the database, read in one transaction so that it's consistent
+    pub fn export(&self) -> Result<Site> {
+        use crate::schema::{devices, inputs, interlocks, outputs};
+        let mut db = self.db.get()?;
+        let (d, i, o, l) = db.transaction(|conn| {
+            Ok::<_, Error>((
+                devices::dsl::devices.load(conn)?,
+                inputs::dsl::inputs.load(conn)?,
+                outputs::dsl::outputs.load(conn)?,
+                interlocks::dsl::interlocks.load(conn)?,
+            ))
+        })?;
+        Site::from_models(d, i, o, l)
+    }
+
+    /// Replace everything in the database with `site`, in one transaction: if any of it can't be
+    /// stored, nothing changes
+    pub fn import(&self, site: &Site) -> Result<()> {
+        use crate::schema::{devices, inputs, interlocks, outputs};
+        let mut db = self.db.get()?;
+        db.transaction(|conn| {
+            diesel::delete(interlocks::table).execute(conn)?;
+            diesel::delete(outputs::table).execute(conn)?;
+            diesel::delete(inputs::table).execute(conn)?;
+            diesel::delete(devices::table).execute(conn)?;
+            for device in site.new_devices() {
+                diesel::insert_into(devices::table)
+                    .values(&device)
+                    .execute(conn)?;
+            }
+            for input in site.new_inputs() {
+                diesel::insert_into(inputs::table)
+                    .values(&input)
+                    .execute(conn)?;
+            }
+            for output in site.new_outputs() {
+                diesel::insert_into(outputs::table)
+                    .values(&output)
+                    .execute(conn)?;
+            }
+            for interlock in site.new_interlocks() {
+                diesel::insert_into(interlocks::table)
+                    .values(&interlock)
+                    .execute(conn)?;
+            }
+            info!(
+                "Imported {} devices, {} inputs, {} outputs and {} interlocks",
+                site.devices.len(),
+                site.inputs.len(),
+                site.outputs.len(),
+                site.interlocks.len()
+            );
+            Ok(())
+        })
+    }
 }
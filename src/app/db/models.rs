@@ -1,4 +1,6 @@
-use crate::schema::{devices, inputs, outputs};
+use crate::app::device::InputDirections;
+use crate::app::output::{ErrorPolicy, OutputKind};
+use crate::schema::{audit_log, devices, inputs, interlocks, outputs};
 use chrono::prelude::*;
 use juniper::{GraphQLInputObject, GraphQLObject};
 
@@ -6,22 +8,25 @@ use juniper::{GraphQLInputObject, GraphQLObject};
 #[diesel(table_name = devices)]
 pub struct NewDevice {
     pub name: String,
+    display_name: String,
     model: String,
     notes: String,
     disabled: Option<bool>,
 }
 
 impl NewDevice {
+    /// A device with ID `name`, which should come from `webapp::slugify::identifier`
     pub fn new(
         model: crate::app::device::Type,
         name: String,
+        display_name: String,
         notes: String,
         disabled: Option<bool>,
     ) -> Self {
-        // TODO: Transform name to a key that is a valid identifier.
         Self {
             model: serde_json::to_string(&model).unwrap(),
             name,
+            display_name,
             notes,
             disabled,
         }
@@ -36,6 +41,9 @@ pub struct Device {
     /// What do we name this particular device for identification?
     pub name: String,
 
+    /// What people call the device; the name is generated from this
+    pub display_name: String,
+
     /// What model of device is this? must be a supported type.
     pub model: String,
 
@@ -49,21 +57,53 @@ pub struct Device {
     pub created_at: NaiveDateTime,
 }
 
+/// Changes to a device's settings; fields left out are kept as they are
+#[derive(Clone, Debug, GraphQLInputObject)]
+pub struct UpdateDevice {
+    pub display_name: Option<String>,
+    pub notes: Option<String>,
+
+    /// New I2C address, for models that have one
+    pub address: Option<i32>,
+
+    /// New pin directions, for an MCP23017
+    pub bank_a: Option<InputDirections>,
+    pub bank_b: Option<InputDirections>,
+}
+
 #[derive(Insertable, Clone, Debug, GraphQLInputObject)]
 #[diesel(table_name = inputs)]
 pub struct NewInput {
+    /// What to call the input. Its ID is generated from this, so that expressions can refer to it.
     pub name: String,
+
+    /// Set from `name` when the input is added
+    #[graphql(skip)]
+    pub display_name: String,
+
     pub device_id: String,
     pub device_input_id: i32,
+
+    /// Added to each reading, after scaling
+    pub offset: Option<f64>,
+
+    /// Each reading is multiplied by this
+    pub scale: Option<f64>,
+
+    /// JSON list of `[raw, actual]` pairs, interpolated between before scaling
+    pub calibration: Option<String>,
 }
 
 impl NewInput {
     pub fn new(name: String, device_id: String, device_input_id: i32) -> Self {
-        // TODO: Generate a valid identifier
         Self {
             name,
+            display_name: String::new(),
             device_id,
             device_input_id,
+            offset: None,
+            scale: None,
+            calibration: None,
         }
     }
 }
@@ -74,6 +114,9 @@ pub struct Input {
     /// What do we want to call this input
     pub name: String,
 
+    /// What people call the input; the name is generated from this
+    pub display_name: String,
+
     /// The device this input is associated with
     pub device_id: String,
 
@@ -81,25 +124,62 @@ pub struct Input {
     /// number, channel, etc)
     pub device_input_id: i32,
 
+    /// Added to each reading, after scaling
+    pub offset: f64,
+
+    /// Each reading is multiplied by this
+    pub scale: f64,
+
+    /// JSON list of `[raw, actual]` pairs, interpolated between before scaling
+    pub calibration: Option<String>,
+
     /// When was this created
     pub created_at: NaiveDateTime,
 }
 
+#[derive(Clone, Debug, GraphQLInputObject)]
+pub struct UpdateInput {
+    pub display_name: Option<String>,
+    pub device_id: Option<String>,
+    pub device_input_id: Option<i32>,
+    pub offset: Option<f64>,
+    pub scale: Option<f64>,
+    pub calibration: Option<Option<String>>,
+}
+
 #[derive(Clone, Debug, GraphQLInputObject)]
 pub struct UpdateOutput {
+    pub display_name: Option<String>,
     pub device_output_id: Option<i32>,
     pub active_low: Option<bool>,
     pub automation_script: Option<Option<String>>,
+    pub min_on: Option<Option<i32>>,
+    pub min_off: Option<Option<i32>>,
+    pub max_switches_per_hour: Option<Option<i32>>,
+    pub safe_state: Option<Option<bool>>,
+    pub error_policy: Option<ErrorPolicy>,
 }
 
 #[derive(Insertable, Clone, Debug, GraphQLInputObject)]
 #[diesel(table_name = outputs)]
 pub struct NewOutput {
+    /// What to call the output. Its ID is generated from this.
     pub name: String,
+
+    /// Set from `name` when the output is added
+    #[graphql(skip)]
+    pub display_name: String,
+
     pub device_id: String,
     pub device_output_id: i32,
     pub active_low: bool,
     pub automation_script: Option<String>,
+    pub min_on: Option<i32>,
+    pub min_off: Option<i32>,
+    pub max_switches_per_hour: Option<i32>,
+    pub safe_state: Option<bool>,
+    pub error_policy: Option<ErrorPolicy>,
+    pub kind: Option<OutputKind>,
 }
 
 impl NewOutput {
@@ -112,10 +192,17 @@ impl NewOutput {
     ) -> Self {
         Self {
             name,
+            display_name: String::new(),
             device_id,
             device_output_id,
             active_low,
             automation_script,
+            min_on: None,
+            min_off: None,
+            max_switches_per_hour: None,
+            safe_state: None,
+            error_policy: None,
+            kind: None,
         }
     }
 }
@@ -126,6 +213,9 @@ pub struct Output {
     /// What do we call this device
     pub name: String,
 
+    /// What people call the output; the name is generated from this
+    pub display_name: String,
+
     /// The device this input is associated with
     pub device_id: String,
 
@@ -139,6 +229,87 @@ pub struct Output {
     /// If set to an expression, the system will compute this output every state change and write it to the output
     pub automation_script: Option<String>,
 
+    /// Once turned on, keep the output on for at least this many seconds
+    pub min_on: Option<i32>,
+
+    /// Once turned off, keep the output off for at least this many seconds
+    pub min_off: Option<i32>,
+
+    /// Defer changes that would switch the output more often than this within an hour, at least 1
+    pub max_switches_per_hour: Option<i32>,
+
+    /// Value to write when the output can't be trusted: at startup, shutdown, and on errors
+    pub safe_state: Option<bool>,
+
+    /// What to do when the automation script fails to evaluate
+    pub error_policy: ErrorPolicy,
+
+    /// Whether this output is switched on and off, or set to a level
+    pub kind: OutputKind,
+
+    /// When was this created
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = interlocks)]
+pub struct NewInterlock {
+    pub name: String,
+    rule: String,
+}
+
+impl NewInterlock {
+    pub fn new(name: String, rule: &crate::app::interlock::Rule) -> Self {
+        Self {
+            name,
+            rule: serde_json::to_string(rule).unwrap(),
+        }
+    }
+}
+
+/// A safety rule that constrains which outputs may be on together
+#[derive(Queryable, Clone, Debug)]
+pub struct Interlock {
+    /// What do we call this interlock
+    pub name: String,
+
+    /// The rule to enforce, as json. must be a supported rule.
+    pub rule: String,
+
     /// When was this created
     pub created_at: NaiveDateTime,
 }
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    pub user: String,
+    pub mutation: String,
+    pub arguments: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+/// A mutation made through the API, and how it went
+#[derive(Queryable, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: i32,
+
+    /// Who made it, or tried to sign in as
+    pub user: String,
+
+    /// The GraphQL mutation, e.g. updateOutput
+    pub mutation: String,
+
+    /// Its arguments as json, with passwords redacted
+    pub arguments: String,
+
+    /// Whether it succeeded
+    pub succeeded: bool,
+
+    /// Why it failed, if it did
+    pub error: Option<String>,
+
+    /// When it was made, in UTC
+    pub created_at: NaiveDateTime,
+}
@@ -1,5 +1,7 @@
 use crate::app::{db::models, input, output};
 use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use crate::rpi::health::HealthStatus;
 use crate::session::AppContext;
 use juniper::{
     FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject, GraphQLUnion, graphql_object,
@@ -25,6 +27,98 @@ pub struct BMP085 {
     pub mode: SamplingMode,
 }
 
+/// Which of the ADS1x15 family a device is
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Debug, GraphQLEnum)]
+pub enum AdcChip {
+    /// 12 bit, up to 3300 samples per second
+    ADS1015,
+    /// 16 bit, up to 860 samples per second
+    ADS1115,
+}
+
+/// Programmable gain, as the full-scale range of a reading. No input may exceed VDD + 0.3V
+/// whatever the gain.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Debug, GraphQLEnum)]
+pub enum AdcGain {
+    /// ±6.144V
+    TwoThirds,
+    /// ±4.096V
+    One,
+    /// ±2.048V
+    Two,
+    /// ±1.024V
+    Four,
+    /// ±0.512V
+    Eight,
+    /// ±0.256V
+    Sixteen,
+}
+
+/// What a channel measures: one input against ground, or the difference between two inputs
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Debug, GraphQLEnum)]
+pub enum AdcMux {
+    Single0,
+    Single1,
+    Single2,
+    Single3,
+    Diff0To1,
+    Diff0To3,
+    Diff1To3,
+    Diff2To3,
+}
+
+/// The four channels of an ADS1x15, each of which is a slot
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct AdcChannels {
+    pub c0: AdcMux,
+    pub c1: AdcMux,
+    pub c2: AdcMux,
+    pub c3: AdcMux,
+}
+
+impl Default for AdcChannels {
+    fn default() -> Self {
+        AdcChannels {
+            c0: AdcMux::Single0,
+            c1: AdcMux::Single1,
+            c2: AdcMux::Single2,
+            c3: AdcMux::Single3,
+        }
+    }
+}
+
+impl AdcChannels {
+    pub fn get(&self, channel: usize) -> Option<AdcMux> {
+        match channel {
+            0 => Some(self.c0),
+            1 => Some(self.c1),
+            2 => Some(self.c2),
+            3 => Some(self.c3),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ADS1x15 {
+    pub address: i32,
+    pub chip: AdcChip,
+    pub gain: AdcGain,
+
+    /// Samples per second; must be one the chip supports
+    pub data_rate: i32,
+    pub channels: AdcChannels,
+}
+
+/// A 16 channel PWM controller, whose channels are level outputs
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PCA9685 {
+    pub address: i32,
+
+    /// PWM frequency in Hz, from 24 to 1526
+    pub frequency: i32,
+}
+
 /// Direction and features that all GPIO ports in a bank can be set
 #[derive(Copy, Clone, GraphQLInputObject, Serialize, Deserialize, PartialEq, Debug)]
 pub struct InputDirections {
@@ -63,14 +157,14 @@ impl From<InputDirections> for Directions {
             p7,
         } = input;
         Directions {
-            p0,
-            p1,
-            p2,
-            p3,
-            p4,
-            p5,
-            p6,
-            p7,
+            p0: p0.normalized(),
+            p1: p1.normalized(),
+            p2: p2.normalized(),
+            p3: p3.normalized(),
+            p4: p4.normalized(),
+            p5: p5.normalized(),
+            p6: p6.normalized(),
+            p7: p7.normalized(),
         }
     }
 }
@@ -123,19 +217,159 @@ impl Directions {
     }
 }
 
+/// A Sensirion SHT31 (or SHT30/SHT35) temperature and humidity sensor
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SHT31 {
+    /// 0x44, or 0x45 with ADDR pulled high
+    pub address: i32,
+
+    /// Run the on-chip heater, to dry the sensor out after condensation
+    pub heater: bool,
+
+    /// Let the sensor hold the bus while it measures, instead of waiting and polling
+    pub clock_stretching: bool,
+}
+
+/// A HTU21D (or SHT21/Si7021) temperature and humidity sensor
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct HTU21D {
+    /// Always 0x40
+    pub address: i32,
+
+    /// Run the on-chip heater, to dry the sensor out after condensation
+    pub heater: bool,
+
+    /// Let the sensor hold the bus while it measures, instead of waiting and polling
+    pub clock_stretching: bool,
+}
+
+/// An INA219 current and power monitor, on the high side of a shunt resistor
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct INA219 {
+    pub address: i32,
+
+    /// Resistance of the shunt in ohms, 0.1 on most breakout boards
+    pub shunt_ohms: f64,
+
+    /// Largest current expected through the shunt in amps, which sets the resolution
+    pub max_current: f64,
+}
+
+/// An MCP3008 8 channel ADC on SPI0
+#[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct MCP3008 {
+    /// CE0 or CE1
+    pub chip_select: i32,
+
+    /// Voltage on the VREF pin, which a full scale reading equals
+    pub vref: f64,
+}
+
+/// A DS18B20 temperature probe on the 1-Wire bus
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DS18B20 {
+    /// The probe's ROM ID as the kernel names it, e.g. 28-0316a2791aff
+    pub rom_id: String,
+}
+
+/// A device driven by an out-of-tree driver, registered with `rpi::driver::register`
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Custom {
+    /// The name the driver was registered under
+    pub driver: String,
+
+    /// Driver-specific configuration, usually JSON
+    pub config: String,
+}
+
 #[derive(Copy, Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
 pub struct MCP23017 {
     pub address: i32,
     pub bank_a: Directions,
     pub bank_b: Directions,
+
+    /// GPIO pin wired to the chip's INTA line. When set, input changes are reported as they
+    /// happen instead of only being seen when polled.
+    #[serde(default)]
+    pub interrupt_pin: Option<i32>,
 }
 
-#[derive(Copy, Serialize, Deserialize, GraphQLUnion, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, GraphQLUnion, PartialEq, Clone, Debug)]
 #[serde(tag = "name")]
 pub enum Type {
     MCP9808(MCP9808),
     BMP085(BMP085),
     MCP23017(MCP23017),
+    ADS1x15(ADS1x15),
+    PCA9685(PCA9685),
+    DS18B20(DS18B20),
+    SHT31(SHT31),
+    HTU21D(HTU21D),
+    INA219(INA219),
+    MCP3008(MCP3008),
+    Custom(Custom),
+}
+
+impl Type {
+    /// The name of the driver registered for this model
+    pub fn driver_name(&self) -> &str {
+        match self {
+            Type::MCP9808(_) => "MCP9808",
+            Type::BMP085(_) => "BMP085",
+            Type::MCP23017(_) => "MCP23017",
+            Type::ADS1x15(_) => "ADS1x15",
+            Type::PCA9685(_) => "PCA9685",
+            Type::DS18B20(_) => "DS18B20",
+            Type::SHT31(_) => "SHT31",
+            Type::HTU21D(_) => "HTU21D",
+            Type::INA219(_) => "INA219",
+            Type::MCP3008(_) => "MCP3008",
+            Type::Custom(custom) => &custom.driver,
+        }
+    }
+
+    /// This model with `fields` applied, or an error if it has nothing they could change
+    pub fn updated(&self, fields: &models::UpdateDevice) -> Result<Type> {
+        let mut model = self.clone();
+        if let Some(new_address) = fields.address {
+            let address = match &mut model {
+                Type::MCP9808(d) => &mut d.address,
+                Type::BMP085(d) => &mut d.address,
+                Type::MCP23017(d) => &mut d.address,
+                Type::ADS1x15(d) => &mut d.address,
+                Type::PCA9685(d) => &mut d.address,
+                Type::SHT31(d) => &mut d.address,
+                Type::HTU21D(d) => &mut d.address,
+                Type::INA219(d) => &mut d.address,
+                Type::DS18B20(_) | Type::MCP3008(_) | Type::Custom(_) => {
+                    return Err(Error::Config(format!(
+                        "{} devices have no I2C address",
+                        self.driver_name()
+                    )));
+                }
+            };
+            *address = new_address;
+        }
+        if fields.bank_a.is_some() || fields.bank_b.is_some() {
+            match &mut model {
+                Type::MCP23017(d) => {
+                    if let Some(bank_a) = fields.bank_a {
+                        d.bank_a = bank_a.into();
+                    }
+                    if let Some(bank_b) = fields.bank_b {
+                        d.bank_b = bank_b.into();
+                    }
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "{} devices have no pin banks",
+                        self.driver_name()
+                    )));
+                }
+            }
+        }
+        Ok(model)
+    }
 }
 
 /// Direction and modification that a GPIO port can be configured to take.
@@ -147,44 +381,70 @@ pub enum Dir {
     /// Active Low output
     OutL,
 
-    /// Input without pulldown
+    /// Floating input, for pins with an external pull-up or pull-down
     In,
 
-    /// Input with pulldown
+    /// Input with the chip's internal pull-up. The chip has no pull-downs; older configurations
+    /// calling this `InWithPD` always got a pull-up.
+    #[serde(alias = "InWithPD")]
+    InPullUp,
+
+    /// Floating input that reads true when the pin is low
+    InInverted,
+
+    /// Input with the internal pull-up that reads true when the pin is low, as for a switch to
+    /// ground
+    InPullUpInverted,
+
+    /// The old name for `InPullUp`, still accepted from clients. It is stored and reported as
+    /// `InPullUp`.
+    #[graphql(deprecated = "the chip has no pull-downs, this is InPullUp")]
+    #[serde(rename(serialize = "InPullUp"), skip_deserializing)]
     InWithPD,
 }
 
+impl Dir {
+    /// The direction as it is stored, with deprecated names replaced
+    pub fn normalized(self) -> Dir {
+        match self {
+            Dir::InWithPD => Dir::InPullUp,
+            dir => dir,
+        }
+    }
+
+    pub fn is_input(self) -> bool {
+        !matches!(self, Dir::OutH | Dir::OutL)
+    }
+
+    /// Is the chip's internal pull-up enabled for this pin
+    pub fn pull_up(self) -> bool {
+        matches!(self, Dir::InPullUp | Dir::InWithPD | Dir::InPullUpInverted)
+    }
+
+    /// Does the chip invert what it reads on this pin
+    pub fn inverted(self) -> bool {
+        matches!(self, Dir::InInverted | Dir::InPullUpInverted)
+    }
+}
+
 /// Represents a slot on a device that can maybe be bound to an input
 #[derive(Serialize, Deserialize, GraphQLObject, PartialEq, Copy, Clone, Debug)]
 pub struct Slot {
     pub can_input: bool,
     pub can_output: bool,
     pub unit: Unit,
+
+    /// How the pin is configured, for slots that are GPIO pins
+    pub direction: Option<Dir>,
 }
 
 impl Slot {
     pub fn from_dir(dir: Dir) -> Slot {
-        match dir {
-            Dir::OutH => Slot {
-                can_input: true,
-                can_output: true,
-                unit: Unit::Boolean,
-            },
-            Dir::OutL => Slot {
-                can_input: true,
-                can_output: true,
-                unit: Unit::Boolean,
-            },
-            Dir::In => Slot {
-                can_input: true,
-                can_output: false,
-                unit: Unit::Boolean,
-            },
-            Dir::InWithPD => Slot {
-                can_input: true,
-                can_output: false,
-                unit: Unit::Boolean,
-            },
+        Slot {
+            can_input: true,
+            can_output: !dir.is_input(),
+            unit: Unit::Boolean,
+            direction: Some(dir),
         }
     }
 }
@@ -205,12 +465,23 @@ impl Device {
     pub fn name(&self) -> &str {
         self.db_device.name.as_str()
     }
+    pub fn display_name(&self) -> &str {
+        self.db_device.display_name.as_str()
+    }
     pub fn disabled(&self) -> bool {
         self.db_device.disabled
     }
     pub fn notes(&self) -> &str {
         self.db_device.notes.as_str()
     }
+    /// How well the device has been answering since it was started, or null if it isn't running
+    pub async fn health(&self, context: &AppContext) -> Option<HealthStatus> {
+        context
+            .channel()
+            .device_health(self.db_device.name.clone())
+            .await
+            .ok()
+    }
     pub async fn slots(&self, context: &AppContext) -> FieldResult<Vec<Slot>> {
         Ok(context
             .channel()
@@ -230,3 +501,83 @@ impl Device {
             .await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(address: Option<i32>, bank_a: Option<InputDirections>) -> models::UpdateDevice {
+        models::UpdateDevice {
+            display_name: None,
+            notes: None,
+            address,
+            bank_a,
+            bank_b: None,
+        }
+    }
+
+    fn some_inputs() -> InputDirections {
+        InputDirections {
+            p0: Dir::InPullUp,
+            p1: Dir::InPullUp,
+            p2: Dir::In,
+            p3: Dir::In,
+            p4: Dir::OutH,
+            p5: Dir::OutH,
+            p6: Dir::OutL,
+            p7: Dir::OutL,
+        }
+    }
+
+    #[test]
+    fn old_pulldown_name_is_stored_as_pull_up() {
+        let inputs = InputDirections {
+            p0: Dir::InWithPD,
+            ..some_inputs()
+        };
+        let directions: Directions = inputs.into();
+        assert_eq!(directions.p0, Dir::InPullUp);
+        assert_eq!(
+            serde_json::to_string(&Dir::InWithPD).unwrap(),
+            "\"InPullUp\""
+        );
+        assert!(Dir::InWithPD.pull_up() && !Dir::InWithPD.inverted());
+    }
+
+    #[test]
+    fn changes_address() {
+        let model = Type::MCP9808(MCP9808 { address: 0x18 });
+        let updated = model.updated(&update(Some(0x19), None)).unwrap();
+        assert_eq!(updated, Type::MCP9808(MCP9808 { address: 0x19 }));
+    }
+
+    #[test]
+    fn changes_only_given_bank() {
+        let model = Type::MCP23017(MCP23017 {
+            address: 0x20,
+            bank_a: Directions::new(),
+            bank_b: Directions::new(),
+            interrupt_pin: Some(17),
+        });
+        let inputs = some_inputs();
+        match model.updated(&update(None, Some(inputs))).unwrap() {
+            Type::MCP23017(d) => {
+                assert_eq!(d.bank_a, inputs.into());
+                assert_eq!(d.bank_b, Directions::new());
+                assert_eq!(d.address, 0x20);
+                assert_eq!(d.interrupt_pin, Some(17));
+            }
+            other => panic!("unexpected model {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_fields_the_model_lacks() {
+        let probe = Type::DS18B20(DS18B20 {
+            rom_id: "28-0316a2791aff".to_string(),
+        });
+        assert!(probe.updated(&update(Some(0x20), None)).is_err());
+        let sensor = Type::MCP9808(MCP9808 { address: 0x18 });
+        assert!(sensor.updated(&update(None, Some(some_inputs()))).is_err());
+    }
+}
@@ -21,6 +21,33 @@ pub struct DimKPa {
     pub value: f64,
 }
 
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DimVolts {
+    pub value: f64,
+}
+
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DimAmps {
+    pub value: f64,
+}
+
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DimWatts {
+    pub value: f64,
+}
+
+/// A fraction from 0 to 1, such as a PWM duty cycle
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DimRatio {
+    pub value: f64,
+}
+
+/// Relative humidity in percent
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DimRelHumidity {
+    pub value: f64,
+}
+
 #[derive(Serialize, Deserialize, GraphQLUnion, PartialEq, Clone, Debug)]
 #[serde(tag = "dim")]
 pub enum Dimensioned {
@@ -28,6 +55,11 @@ pub enum Dimensioned {
     Boolean(DimBool),
     DegC(DimDegC),
     KPa(DimKPa),
+    Volts(DimVolts),
+    Amps(DimAmps),
+    Watts(DimWatts),
+    Ratio(DimRatio),
+    RelHumidity(DimRelHumidity),
 }
 
 impl Dimensioned {
@@ -40,6 +72,21 @@ impl Dimensioned {
     pub fn from_kpa(value: f64) -> Dimensioned {
         Dimensioned::KPa(DimKPa { value })
     }
+    pub fn from_volts(value: f64) -> Dimensioned {
+        Dimensioned::Volts(DimVolts { value })
+    }
+    pub fn from_amps(value: f64) -> Dimensioned {
+        Dimensioned::Amps(DimAmps { value })
+    }
+    pub fn from_watts(value: f64) -> Dimensioned {
+        Dimensioned::Watts(DimWatts { value })
+    }
+    pub fn from_ratio(value: f64) -> Dimensioned {
+        Dimensioned::Ratio(DimRatio { value })
+    }
+    pub fn from_rel_humidity(value: f64) -> Dimensioned {
+        Dimensioned::RelHumidity(DimRelHumidity { value })
+    }
 
     pub fn from_bool(value: bool) -> Dimensioned {
         Dimensioned::Boolean(DimBool { value })
@@ -50,6 +97,11 @@ impl Dimensioned {
             Self::DegC(DimDegC { value }) => Ok(*value),
             Self::Boolean(DimBool { value }) => Ok(if *value { 1.0 } else { 0.0 }),
             Self::KPa(DimKPa { value }) => Ok(*value),
+            Self::Volts(DimVolts { value }) => Ok(*value),
+            Self::Amps(DimAmps { value }) => Ok(*value),
+            Self::Watts(DimWatts { value }) => Ok(*value),
+            Self::Ratio(DimRatio { value }) => Ok(*value),
+            Self::RelHumidity(DimRelHumidity { value }) => Ok(*value),
             Self::Error(DimMessage { message }) => {
                 Err(crate::error::Error::UnitError(message.clone()))
             }
@@ -61,6 +113,11 @@ impl Dimensioned {
             Self::DegC(_) => Ok(Unit::DegC),
             Self::Boolean(_) => Ok(Unit::Boolean),
             Self::KPa(_) => Ok(Unit::KPa),
+            Self::Volts(_) => Ok(Unit::Volts),
+            Self::Amps(_) => Ok(Unit::Amps),
+            Self::Watts(_) => Ok(Unit::Watts),
+            Self::Ratio(_) => Ok(Unit::Ratio),
+            Self::RelHumidity(_) => Ok(Unit::RelHumidity),
             Self::Error(DimMessage { message }) => {
                 Err(crate::error::Error::UnitError(message.clone()))
             }
@@ -72,6 +129,11 @@ impl Dimensioned {
             Unit::DegC => Dimensioned::DegC(DimDegC { value }),
             Unit::Boolean => Dimensioned::Boolean(DimBool { value: value > 0.0 }),
             Unit::KPa => Dimensioned::KPa(DimKPa { value }),
+            Unit::Volts => Dimensioned::Volts(DimVolts { value }),
+            Unit::Amps => Dimensioned::Amps(DimAmps { value }),
+            Unit::Watts => Dimensioned::Watts(DimWatts { value }),
+            Unit::Ratio => Dimensioned::Ratio(DimRatio { value }),
+            Unit::RelHumidity => Dimensioned::RelHumidity(DimRelHumidity { value }),
         }
     }
     pub fn is_unit(&self, unit: Unit) -> bool {
@@ -79,6 +141,11 @@ impl Dimensioned {
             (unit, self),
             (Unit::KPa, &Dimensioned::KPa(_))
                 | (Unit::DegC, &Dimensioned::DegC(_))
+                | (Unit::Volts, &Dimensioned::Volts(_))
+                | (Unit::Amps, &Dimensioned::Amps(_))
+                | (Unit::Watts, &Dimensioned::Watts(_))
+                | (Unit::Ratio, &Dimensioned::Ratio(_))
+                | (Unit::RelHumidity, &Dimensioned::RelHumidity(_))
                 | (Unit::Boolean, &Dimensioned::Boolean(_))
         )
     }
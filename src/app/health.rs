@@ -0,0 +1,198 @@
+use serde_derive::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long the app loop may go without a successful automation pass before it is considered dead.
+/// The time loop sends a message every second, so a healthy loop ticks about once a second.
+pub const MAX_TICK_AGE: Duration = Duration::from_secs(10);
+
+/// How long to wait before trying again after the app state first fails to restart, doubling for
+/// each failure after, up to `MAX_RESTART_DELAY`
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// How long to wait after `failures` restarts in a row have failed
+pub fn restart_delay(failures: u32) -> Duration {
+    RESTART_DELAY
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_RESTART_DELAY)
+}
+
+#[derive(Debug, Default)]
+struct Ticks {
+    /// When the app loop last finished processing a message
+    last_message: Option<Instant>,
+
+    /// When the app loop last ran automations without error
+    last_automation: Option<Instant>,
+
+    /// Has the app state finished starting up
+    ready: bool,
+
+    /// How many times the supervisor has restarted the app loop
+    restarts: u32,
+
+    /// Restarts of the app state in a row that have failed
+    failed_restarts: u32,
+
+    /// Why the app loop last stopped, or its state last failed to restart
+    last_failure: Option<String>,
+}
+
+/// Tracks whether the app loop is making progress, shared between the loop, the watchdog and the
+/// web server
+#[derive(Debug, Default)]
+pub struct Health {
+    ticks: Mutex<Ticks>,
+}
+
+/// A point in time summary of `Health`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    /// The app loop is still making progress
+    pub live: bool,
+
+    /// The app loop is live, and its state is loaded
+    pub ready: bool,
+
+    /// Milliseconds since the last successful automation pass, if there has been one
+    pub last_automation_ms: Option<u128>,
+
+    /// Milliseconds since the last message was processed, if there has been one
+    pub last_message_ms: Option<u128>,
+
+    pub restarts: u32,
+
+    /// Restarts of the app state in a row that have failed
+    pub failed_restarts: u32,
+
+    /// Why the app loop last stopped, or its state last failed to restart, until it next starts
+    pub last_failure: Option<String>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_ticks<T>(&self, f: impl FnOnce(&mut Ticks) -> T) -> T {
+        // a panic while holding the lock can't leave ticks inconsistent, so ignore poisoning
+        let mut ticks = self.ticks.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut ticks)
+    }
+
+    pub fn message_processed(&self) {
+        self.with_ticks(|t| t.last_message = Some(Instant::now()));
+    }
+
+    pub fn automation_succeeded(&self) {
+        self.with_ticks(|t| t.last_automation = Some(Instant::now()));
+    }
+
+    /// Record that the app state has started, and the loop is about to run
+    pub fn started(&self) {
+        self.with_ticks(|t| {
+            t.ready = true;
+            t.last_automation = Some(Instant::now());
+            t.failed_restarts = 0;
+            t.last_failure = None;
+        });
+    }
+
+    /// Record why the app loop stopped
+    pub fn failed(&self, reason: String) {
+        self.with_ticks(|t| t.last_failure = Some(reason));
+    }
+
+    /// Record that the app state couldn't be restarted, returning how many times in a row
+    pub fn restart_failed(&self, reason: String) -> u32 {
+        self.with_ticks(|t| {
+            t.failed_restarts += 1;
+            t.last_failure = Some(reason);
+            t.failed_restarts
+        })
+    }
+
+    /// Record that the app loop has stopped and is being restarted
+    pub fn stopped(&self, restarting: bool) {
+        self.with_ticks(|t| {
+            t.ready = false;
+            if restarting {
+                t.restarts += 1;
+            }
+        });
+    }
+
+    pub fn status(&self) -> Status {
+        self.status_at(Instant::now())
+    }
+
+    pub fn status_at(&self, now: Instant) -> Status {
+        self.with_ticks(|t| {
+            let age = |tick: Option<Instant>| tick.map(|i| now.saturating_duration_since(i));
+            let live = age(t.last_automation).is_some_and(|a| a <= MAX_TICK_AGE);
+            Status {
+                live,
+                ready: live && t.ready,
+                last_automation_ms: age(t.last_automation).map(|a| a.as_millis()),
+                last_message_ms: age(t.last_message).map(|a| a.as_millis()),
+                restarts: t.restarts,
+                failed_restarts: t.failed_restarts,
+                last_failure: t.last_failure.clone(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_only_while_ticking() {
+        let health = Health::new();
+        assert!(!health.status().live);
+
+        health.started();
+        let status = health.status();
+        assert!(status.live);
+        assert!(status.ready);
+
+        let later = Instant::now() + MAX_TICK_AGE + Duration::from_secs(1);
+        assert!(!health.status_at(later).live);
+        assert!(!health.status_at(later).ready);
+
+        health.stopped(true);
+        let status = health.status();
+        assert!(status.live);
+        assert!(!status.ready);
+        assert_eq!(status.restarts, 1);
+    }
+
+    #[test]
+    fn failed_restarts_are_reported_until_one_works() {
+        let health = Health::new();
+        health.started();
+        health.failed("loop failed".to_string());
+        health.stopped(true);
+        assert_eq!(health.restart_failed("no database".to_string()), 1);
+        assert_eq!(health.restart_failed("no database".to_string()), 2);
+        let status = health.status();
+        assert_eq!(status.failed_restarts, 2);
+        assert_eq!(status.last_failure.as_deref(), Some("no database"));
+
+        health.started();
+        let status = health.status();
+        assert_eq!(status.failed_restarts, 0);
+        assert_eq!(status.last_failure, None);
+    }
+
+    #[test]
+    fn restarts_back_off() {
+        assert_eq!(restart_delay(1), Duration::from_secs(5));
+        assert_eq!(restart_delay(2), Duration::from_secs(10));
+        assert_eq!(restart_delay(4), Duration::from_secs(40));
+        assert_eq!(restart_delay(7), MAX_RESTART_DELAY);
+        assert_eq!(restart_delay(u32::MAX), MAX_RESTART_DELAY);
+    }
+}
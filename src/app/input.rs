@@ -1,8 +1,18 @@
+use crate::app::calibration::{Calibration, CalibrationPoint};
 use crate::app::db::models;
 use crate::app::device::Device;
 use crate::app::dimensioned::Dimensioned;
 use crate::session::AppContext;
-use juniper::graphql_object;
+use juniper::{FieldResult, GraphQLObject, graphql_object};
+
+/// An input changed value, as reported by its device's interrupt rather than found by polling
+#[derive(Debug, Clone, GraphQLObject)]
+pub struct InputChange {
+    pub input_id: String,
+    pub value: bool,
+    /// When the app heard about it, as RFC 3339
+    pub at: String,
+}
 
 #[derive(Debug, Clone)]
 pub struct Input {
@@ -14,12 +24,35 @@ impl Input {
     pub fn name(&self) -> &str {
         self.db.name.as_str()
     }
-    pub async fn value(&self, context: &AppContext) -> Dimensioned {
-        match context.channel().read_value(self.db.name.clone()).await {
+    pub fn display_name(&self) -> &str {
+        self.db.display_name.as_str()
+    }
+    /// The current reading, which may be cached for a short while unless `fresh` is set
+    pub async fn value(&self, context: &AppContext, fresh: Option<bool>) -> Dimensioned {
+        match context
+            .channel()
+            .read_value(self.db.name.clone(), fresh.unwrap_or(false))
+            .await
+        {
             Ok(d) => d,
             Err(e) => Dimensioned::from_error(e.to_string()),
         }
     }
+    /// Added to each reading, after scaling
+    pub fn offset(&self) -> f64 {
+        self.db.offset
+    }
+    /// Each reading is multiplied by this
+    pub fn scale(&self) -> f64 {
+        self.db.scale
+    }
+    /// Points interpolated between before scaling
+    pub fn calibration(&self) -> FieldResult<Vec<CalibrationPoint>> {
+        match &self.db.calibration {
+            Some(table) => Ok(Calibration::parse_table(table)?),
+            None => Ok(vec![]),
+        }
+    }
     pub async fn device(&self, context: &AppContext) -> Option<Device> {
         context
             .channel()
@@ -27,4 +60,8 @@ impl Input {
             .await
             .ok()
     }
+    /// False while the input's device is disabled, when it can't be read
+    pub async fn available(&self, context: &AppContext) -> bool {
+        self.device(context).await.is_some_and(|d| !d.disabled())
+    }
 }
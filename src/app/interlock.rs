@@ -0,0 +1,270 @@
+use crate::app::AppID;
+use crate::app::db::models;
+use crate::error::{Error, Result};
+use crate::session::AppContext;
+use chrono::prelude::*;
+use juniper::{FieldResult, GraphQLObject, GraphQLUnion, graphql_object};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// No more than one of the listed outputs may be on at any time
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct AtMostOne {
+    pub outputs: Vec<AppID>,
+}
+
+/// `output` may only turn on once `requires_off` has been off for at least `for_seconds`
+#[derive(Clone, GraphQLObject, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RequiresOff {
+    pub output: AppID,
+    pub requires_off: AppID,
+    pub for_seconds: i32,
+}
+
+#[derive(Clone, Serialize, Deserialize, GraphQLUnion, PartialEq, Debug)]
+#[serde(tag = "name")]
+pub enum Rule {
+    AtMostOne(AtMostOne),
+    RequiresOff(RequiresOff),
+}
+
+/// What an interlock needs to know about an output to decide if another may turn on
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct OutputStatus {
+    pub on: bool,
+
+    /// When the output last turned off, if it is off
+    pub off_since: DateTime<Local>,
+}
+
+impl Rule {
+    /// All outputs this rule refers to
+    pub fn outputs(&self) -> Vec<&AppID> {
+        match self {
+            Rule::AtMostOne(AtMostOne { outputs }) => outputs.iter().collect(),
+            Rule::RequiresOff(RequiresOff {
+                output,
+                requires_off,
+                ..
+            }) => vec![output, requires_off],
+        }
+    }
+
+    /// Outputs whose status must be known before `output_id` may turn on
+    pub fn dependencies(&self, output_id: &str) -> Vec<&AppID> {
+        match self {
+            Rule::AtMostOne(AtMostOne { outputs }) => {
+                if outputs.iter().any(|o| o == output_id) {
+                    outputs.iter().filter(|o| *o != output_id).collect()
+                } else {
+                    vec![]
+                }
+            }
+            Rule::RequiresOff(RequiresOff {
+                output,
+                requires_off,
+                ..
+            }) => {
+                if output == output_id {
+                    vec![requires_off]
+                } else {
+                    vec![]
+                }
+            }
+        }
+    }
+
+    /// Refer to output `from` as `to` instead. Returns whether the rule referred to `from` at all.
+    pub fn rename_output(&mut self, from: &str, to: &str) -> bool {
+        let mut renamed = false;
+        let refs: Vec<&mut AppID> = match self {
+            Rule::AtMostOne(AtMostOne { outputs }) => outputs.iter_mut().collect(),
+            Rule::RequiresOff(RequiresOff {
+                output,
+                requires_off,
+                ..
+            }) => vec![output, requires_off],
+        };
+        for name in refs {
+            if name == from {
+                *name = to.to_string();
+                renamed = true;
+            }
+        }
+        renamed
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Rule::AtMostOne(AtMostOne { outputs }) => {
+                if outputs.len() < 2 {
+                    Err(Error::Config(
+                        "at-most-one interlock needs at least two outputs".to_string(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            Rule::RequiresOff(RequiresOff {
+                output,
+                requires_off,
+                for_seconds,
+            }) => {
+                if output == requires_off {
+                    Err(Error::Config(
+                        "an output can not require itself to be off".to_string(),
+                    ))
+                } else if *for_seconds < 0 {
+                    Err(Error::Config(
+                        "for_seconds must not be negative".to_string(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Would turning `output_id` on at `now` violate this rule?
+    ///
+    /// Outputs missing from `status` no longer exist, and can't hold anything else off.
+    pub fn permits(
+        &self,
+        output_id: &str,
+        status: &HashMap<AppID, OutputStatus>,
+        now: DateTime<Local>,
+    ) -> std::result::Result<(), String> {
+        for other in self.dependencies(output_id) {
+            let Some(other_status) = status.get(other) else {
+                continue;
+            };
+            match self {
+                Rule::AtMostOne(_) => {
+                    if other_status.on {
+                        return Err(format!("'{}' is already on", other));
+                    }
+                }
+                Rule::RequiresOff(RequiresOff { for_seconds, .. }) => {
+                    if other_status.on {
+                        return Err(format!("'{}' is on", other));
+                    }
+                    let off_for = now - other_status.off_since;
+                    if off_for < chrono::Duration::seconds(*for_seconds as i64) {
+                        return Err(format!(
+                            "'{}' has only been off for {}s of the required {}s",
+                            other,
+                            off_for.num_seconds(),
+                            for_seconds
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A named rule stored in the database
+#[derive(Clone, Debug)]
+pub struct Interlock {
+    pub data: models::Interlock,
+}
+
+#[graphql_object(context = AppContext)]
+impl Interlock {
+    pub fn name(&self) -> &str {
+        self.data.name.as_str()
+    }
+
+    pub fn rule(&self) -> FieldResult<Rule> {
+        Ok(serde_json::from_str(&self.data.rule)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn off_since(now: DateTime<Local>, secs: i64) -> OutputStatus {
+        OutputStatus {
+            on: false,
+            off_since: now - chrono::Duration::seconds(secs),
+        }
+    }
+
+    fn on() -> OutputStatus {
+        OutputStatus {
+            on: true,
+            off_since: Local::now(),
+        }
+    }
+
+    #[test]
+    fn at_most_one() {
+        let now = Local::now();
+        let rule = Rule::AtMostOne(AtMostOne {
+            outputs: vec!["heat_pump".to_string(), "heater".to_string()],
+        });
+        let mut status = HashMap::new();
+        status.insert("heat_pump".to_string(), off_since(now, 0));
+        status.insert("heater".to_string(), on());
+
+        assert!(rule.permits("heat_pump", &status, now).is_err());
+        assert!(rule.permits("heater", &status, now).is_ok());
+        assert!(rule.permits("fan", &status, now).is_ok());
+    }
+
+    #[test]
+    fn requires_off() {
+        let now = Local::now();
+        let rule = Rule::RequiresOff(RequiresOff {
+            output: "heater".to_string(),
+            requires_off: "heat_pump".to_string(),
+            for_seconds: 300,
+        });
+        let mut status = HashMap::new();
+        status.insert("heat_pump".to_string(), off_since(now, 60));
+        assert!(rule.permits("heater", &status, now).is_err());
+
+        status.insert("heat_pump".to_string(), off_since(now, 301));
+        assert!(rule.permits("heater", &status, now).is_ok());
+
+        status.insert("heat_pump".to_string(), on());
+        assert!(rule.permits("heater", &status, now).is_err());
+
+        // the rule only constrains one direction
+        assert!(rule.permits("heat_pump", &HashMap::new(), now).is_ok());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(
+            Rule::AtMostOne(AtMostOne {
+                outputs: vec!["a".to_string()]
+            })
+            .validate()
+            .is_err()
+        );
+        assert!(
+            Rule::RequiresOff(RequiresOff {
+                output: "a".to_string(),
+                requires_off: "a".to_string(),
+                for_seconds: 1
+            })
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn rename_output() {
+        let mut rule = Rule::RequiresOff(RequiresOff {
+            output: "heater".to_string(),
+            requires_off: "heat_pump".to_string(),
+            for_seconds: 300,
+        });
+        assert!(rule.rename_output("heat_pump", "pump"));
+        assert_eq!(rule.outputs(), vec!["heater", "pump"]);
+        assert!(!rule.rename_output("fan", "blower"));
+    }
+}
@@ -2,9 +2,16 @@ pub mod channel;
 pub mod db;
 pub mod state;
 
+pub mod audit;
+pub mod calibration;
 pub mod device;
 pub mod dimensioned;
+pub mod health;
 pub mod input;
+pub mod interlock;
 pub mod output;
+pub mod site;
+pub mod switching;
+pub mod watchdog;
 
 pub type AppID = String;
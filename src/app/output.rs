@@ -1,10 +1,36 @@
 use crate::app::db::models;
+use crate::app::switching::Pending;
 pub use crate::config::types::{BoolExpr, DateTimeValue, LocationValue, Unit, Value};
 use crate::session::AppContext;
+use diesel_derive_enum::DbEnum;
 use juniper::graphql_object;
+use serde_derive::{Deserialize, Serialize};
 
 use super::dimensioned::Dimensioned;
 
+/// What an output does when its automation script can't be evaluated, e.g. a sensor read fails
+#[derive(Copy, Clone, DbEnum, Serialize, Deserialize, PartialEq, Debug, juniper::GraphQLEnum)]
+pub enum ErrorPolicy {
+    /// Leave the output as it is
+    Hold,
+
+    /// Write the output's safe state
+    Safe,
+
+    /// Write the last value the script evaluated to
+    Last,
+}
+
+/// What can be written to an output
+#[derive(Copy, Clone, DbEnum, Serialize, Deserialize, PartialEq, Debug, juniper::GraphQLEnum)]
+pub enum OutputKind {
+    /// On or off; automation scripts are boolean expressions
+    Boolean,
+
+    /// A duty cycle from 0 to 1, e.g. a PWM channel; automation scripts are value expressions
+    Level,
+}
+
 /// We can write a boolean value to a given device via name
 #[derive(Debug, Clone)]
 pub struct Output {
@@ -17,6 +43,10 @@ impl Output {
         self.data.name.as_str()
     }
 
+    pub fn display_name(&self) -> &str {
+        self.data.display_name.as_str()
+    }
+
     pub async fn device(&self, context: &AppContext) -> Option<crate::app::device::Device> {
         context
             .channel()
@@ -25,6 +55,11 @@ impl Output {
             .ok()
     }
 
+    /// False while the output's device is disabled, when it can't be read or written
+    pub async fn available(&self, context: &AppContext) -> bool {
+        self.device(context).await.is_some_and(|d| !d.disabled())
+    }
+
     pub fn device_id(&self) -> &str {
         &self.data.device_id
     }
@@ -41,7 +76,57 @@ impl Output {
         self.data.automation_script.clone()
     }
 
+    /// Once turned on, keep the output on for at least this many seconds
+    pub fn min_on(&self) -> Option<i32> {
+        self.data.min_on
+    }
+
+    /// Once turned off, keep the output off for at least this many seconds
+    pub fn min_off(&self) -> Option<i32> {
+        self.data.min_off
+    }
+
+    /// Defer changes that would switch the output more often than this within an hour
+    pub fn max_switches_per_hour(&self) -> Option<i32> {
+        self.data.max_switches_per_hour
+    }
+
+    /// Value written at startup, on shutdown, and on errors when the error policy asks for it
+    pub fn safe_state(&self) -> Option<bool> {
+        self.data.safe_state
+    }
+
+    /// What to do when the automation script fails to evaluate
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.data.error_policy
+    }
+
+    /// Whether the output is switched or set to a level
+    pub fn kind(&self) -> OutputKind {
+        self.data.kind
+    }
+
+    /// A change that has been requested but is deferred by the limits above
+    pub async fn pending_change(&self, context: &AppContext) -> Option<Pending> {
+        context
+            .channel()
+            .pending_output_change(self.data.name.clone())
+            .await
+            .ok()
+            .flatten()
+    }
+
     pub async fn value(&self, context: &AppContext) -> Dimensioned {
+        if self.data.kind == OutputKind::Level {
+            return match context
+                .channel()
+                .current_output_level(self.data.name.clone())
+                .await
+            {
+                Ok(v) => Dimensioned::from_ratio(v),
+                Err(e) => Dimensioned::from_error(e.to_string()),
+            };
+        }
         match context
             .channel()
             .current_output_value(self.data.name.clone())
@@ -0,0 +1,428 @@
+use crate::app::calibration::Calibration;
+use crate::app::db::models;
+use crate::app::interlock::Rule;
+use crate::app::output::{ErrorPolicy, OutputKind};
+use crate::app::state::{check_identifier, check_input_slot, check_output_slot};
+use crate::app::{AppID, device, switching};
+use crate::config::parse;
+use crate::error::{Error, Result};
+use crate::rpi;
+use juniper::GraphQLEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Everything set up on a Pi, as one document that can be kept in git or loaded onto another.
+///
+/// Each kind of entity is optional when read, so documents written before a kind existed still
+/// load.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Site {
+    #[serde(default)]
+    pub devices: Vec<SiteDevice>,
+    #[serde(default)]
+    pub inputs: Vec<SiteInput>,
+    #[serde(default)]
+    pub outputs: Vec<SiteOutput>,
+    #[serde(default)]
+    pub interlocks: Vec<SiteInterlock>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SiteDevice {
+    pub name: AppID,
+
+    /// Defaults to the name
+    #[serde(default)]
+    pub display_name: String,
+    pub model: device::Type,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SiteInput {
+    pub name: AppID,
+
+    /// Defaults to the name
+    #[serde(default)]
+    pub display_name: String,
+    pub device_id: AppID,
+    pub device_input_id: i32,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "unit_scale")]
+    pub scale: f64,
+
+    /// JSON list of `[raw, actual]` pairs
+    pub calibration: Option<String>,
+}
+
+fn unit_scale() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SiteOutput {
+    pub name: AppID,
+
+    /// Defaults to the name
+    #[serde(default)]
+    pub display_name: String,
+    pub device_id: AppID,
+    pub device_output_id: i32,
+    #[serde(default)]
+    pub active_low: bool,
+    pub automation_script: Option<String>,
+    pub min_on: Option<i32>,
+    pub min_off: Option<i32>,
+    pub max_switches_per_hour: Option<i32>,
+    pub safe_state: Option<bool>,
+    pub error_policy: Option<ErrorPolicy>,
+    pub kind: Option<OutputKind>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SiteInterlock {
+    pub name: AppID,
+    pub rule: Rule,
+}
+
+/// How a site document is written
+#[derive(Copy, Clone, PartialEq, Debug, GraphQLEnum)]
+pub enum SiteFormat {
+    Toml,
+    Json,
+}
+
+impl SiteFormat {
+    /// JSON for `.json` files, TOML for anything else
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => SiteFormat::Json,
+            _ => SiteFormat::Toml,
+        }
+    }
+}
+
+impl FromStr for SiteFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "toml" => Ok(SiteFormat::Toml),
+            "json" => Ok(SiteFormat::Json),
+            _ => Err(Error::Config(format!("unknown format '{}'", s))),
+        }
+    }
+}
+
+impl Site {
+    pub fn from_models(
+        devices: Vec<models::Device>,
+        inputs: Vec<models::Input>,
+        outputs: Vec<models::Output>,
+        interlocks: Vec<models::Interlock>,
+    ) -> Result<Self> {
+        Ok(Site {
+            devices: devices
+                .into_iter()
+                .map(|d| {
+                    Ok(SiteDevice {
+                        model: serde_json::from_str(&d.model)?,
+                        name: d.name,
+                        display_name: d.display_name,
+                        notes: d.notes,
+                        disabled: d.disabled,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            inputs: inputs
+                .into_iter()
+                .map(|i| SiteInput {
+                    name: i.name,
+                    display_name: i.display_name,
+                    device_id: i.device_id,
+                    device_input_id: i.device_input_id,
+                    offset: i.offset,
+                    scale: i.scale,
+                    calibration: i.calibration,
+                })
+                .collect(),
+            outputs: outputs
+                .into_iter()
+                .map(|o| SiteOutput {
+                    name: o.name,
+                    display_name: o.display_name,
+                    device_id: o.device_id,
+                    device_output_id: o.device_output_id,
+                    active_low: o.active_low,
+                    automation_script: o.automation_script,
+                    min_on: o.min_on,
+                    min_off: o.min_off,
+                    max_switches_per_hour: o.max_switches_per_hour,
+                    safe_state: o.safe_state,
+                    error_policy: Some(o.error_policy),
+                    kind: Some(o.kind),
+                })
+                .collect(),
+            interlocks: interlocks
+                .into_iter()
+                .map(|i| {
+                    Ok(SiteInterlock {
+                        rule: serde_json::from_str(&i.rule)?,
+                        name: i.name,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn parse(text: &str, format: SiteFormat) -> Result<Self> {
+        match format {
+            SiteFormat::Toml => Ok(toml::from_str(text)?),
+            SiteFormat::Json => Ok(serde_json::from_str(text)?),
+        }
+    }
+
+    pub fn render(&self, format: SiteFormat) -> Result<String> {
+        match format {
+            SiteFormat::Toml => Ok(toml::to_string(self)?),
+            SiteFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+        }
+    }
+
+    /// Check that everything in the site can be set up as described: names are unique, every
+    /// model has a driver, inputs and outputs are on slots that can do what they need, and every
+    /// script, calibration table and interlock makes sense.
+    pub fn validate(&self, rapi: &rpi::RpiApi) -> Result<()> {
+        let mut slots = HashMap::new();
+        for d in &self.devices {
+            if slots.contains_key(&d.name) {
+                return Err(Error::NotUnique(format!("device '{}'", d.name)));
+            }
+            check_identifier(&d.name)?;
+            let driven = rpi::device::Device::new(d.model.clone(), rapi.clone())?;
+            slots.insert(d.name.clone(), driven.slots());
+        }
+        let device_slots = |device_id: &AppID| {
+            slots
+                .get(device_id)
+                .ok_or_else(|| Error::NonExistant(format!("device '{}'", device_id)))
+        };
+
+        let mut names = HashSet::new();
+        for i in &self.inputs {
+            if !names.insert(&i.name) {
+                return Err(Error::NotUnique(format!("input '{}'", i.name)));
+            }
+            check_identifier(&i.name)?;
+            check_input_slot(device_slots(&i.device_id)?, &i.device_id, i.device_input_id)?;
+            if let Some(table) = &i.calibration {
+                Calibration::parse_table(table)?;
+            }
+        }
+
+        let mut names = HashSet::new();
+        for o in &self.outputs {
+            if !names.insert(&o.name) {
+                return Err(Error::NotUnique(format!("output '{}'", o.name)));
+            }
+            check_identifier(&o.name)?;
+            switching::check_max_switches(o.max_switches_per_hour)?;
+            let kind = o.kind.unwrap_or(OutputKind::Boolean);
+            check_output_slot(
+                device_slots(&o.device_id)?,
+                &o.name,
+                &o.device_id,
+                o.device_output_id,
+                kind,
+            )?;
+            if let Some(script) = &o.automation_script {
+                match kind {
+                    OutputKind::Boolean => parse::bool_expr(script).map(|_| ())?,
+                    OutputKind::Level => parse::value_expr(script).map(|_| ())?,
+                }
+            }
+        }
+
+        let mut interlock_names = HashSet::new();
+        for i in &self.interlocks {
+            if !interlock_names.insert(&i.name) {
+                return Err(Error::NotUnique(format!("interlock '{}'", i.name)));
+            }
+            i.rule.validate()?;
+            if let Some(missing) = i.rule.outputs().into_iter().find(|o| !names.contains(o)) {
+                return Err(Error::NonExistant(format!(
+                    "output '{}' of interlock '{}'",
+                    missing, i.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new_devices(&self) -> Vec<models::NewDevice> {
+        self.devices
+            .iter()
+            .map(|d| {
+                models::NewDevice::new(
+                    d.model.clone(),
+                    d.name.clone(),
+                    display_name(&d.display_name, &d.name),
+                    d.notes.clone(),
+                    Some(d.disabled),
+                )
+            })
+            .collect()
+    }
+
+    pub fn new_inputs(&self) -> Vec<models::NewInput> {
+        self.inputs
+            .iter()
+            .map(|i| models::NewInput {
+                name: i.name.clone(),
+                display_name: display_name(&i.display_name, &i.name),
+                device_id: i.device_id.clone(),
+                device_input_id: i.device_input_id,
+                offset: Some(i.offset),
+                scale: Some(i.scale),
+                calibration: i.calibration.clone(),
+            })
+            .collect()
+    }
+
+    pub fn new_outputs(&self) -> Vec<models::NewOutput> {
+        self.outputs
+            .iter()
+            .map(|o| models::NewOutput {
+                name: o.name.clone(),
+                display_name: display_name(&o.display_name, &o.name),
+                device_id: o.device_id.clone(),
+                device_output_id: o.device_output_id,
+                active_low: o.active_low,
+                automation_script: o.automation_script.clone(),
+                min_on: o.min_on,
+                min_off: o.min_off,
+                max_switches_per_hour: o.max_switches_per_hour,
+                safe_state: o.safe_state,
+                error_policy: o.error_policy,
+                kind: o.kind,
+            })
+            .collect()
+    }
+
+    pub fn new_interlocks(&self) -> Vec<models::NewInterlock> {
+        self.interlocks
+            .iter()
+            .map(|i| models::NewInterlock::new(i.name.clone(), &i.rule))
+            .collect()
+    }
+}
+
+fn display_name(display_name: &str, name: &str) -> String {
+    if display_name.is_empty() {
+        name.to_string()
+    } else {
+        display_name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::interlock::AtMostOne;
+
+    fn site() -> Site {
+        Site {
+            devices: vec![SiteDevice {
+                name: "relays".to_string(),
+                display_name: "Relay board".to_string(),
+                model: device::Type::MCP23017(device::MCP23017 {
+                    address: 0x20,
+                    bank_a: device::Directions::new(),
+                    bank_b: device::Directions::new(),
+                    interrupt_pin: None,
+                }),
+                notes: String::new(),
+                disabled: false,
+            }],
+            inputs: vec![],
+            outputs: ["heater", "heat_pump"]
+                .iter()
+                .enumerate()
+                .map(|(pin, name)| SiteOutput {
+                    name: name.to_string(),
+                    display_name: String::new(),
+                    device_id: "relays".to_string(),
+                    device_output_id: pin as i32,
+                    active_low: false,
+                    automation_script: Some("hour_of_day(now) > 6".to_string()),
+                    min_on: Some(60),
+                    min_off: None,
+                    max_switches_per_hour: None,
+                    safe_state: Some(false),
+                    error_policy: Some(ErrorPolicy::Safe),
+                    kind: Some(OutputKind::Boolean),
+                })
+                .collect(),
+            interlocks: vec![SiteInterlock {
+                name: "one_heat_source".to_string(),
+                rule: Rule::AtMostOne(AtMostOne {
+                    outputs: vec!["heater".to_string(), "heat_pump".to_string()],
+                }),
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let site = site();
+        for format in [SiteFormat::Toml, SiteFormat::Json] {
+            let text = site.render(format).unwrap();
+            assert_eq!(Site::parse(&text, format).unwrap(), site);
+        }
+    }
+
+    #[test]
+    fn missing_sections_are_empty() {
+        let site = Site::parse("", SiteFormat::Toml).unwrap();
+        assert_eq!(site, Site::default());
+    }
+
+    #[test]
+    fn validates_references() {
+        let rapi = rpi::start(1);
+        let mut site = site();
+        assert!(site.validate(&rapi).is_ok());
+
+        site.interlocks[0].rule = Rule::AtMostOne(AtMostOne {
+            outputs: vec!["heater".to_string(), "fan".to_string()],
+        });
+        assert!(site.validate(&rapi).is_err());
+
+        let mut site = self::site();
+        site.outputs[1].device_id = "pwm".to_string();
+        assert!(site.validate(&rapi).is_err());
+
+        let mut site = self::site();
+        site.outputs[1].name = "heater".to_string();
+        assert!(site.validate(&rapi).is_err());
+    }
+
+    #[test]
+    fn names_must_be_identifiers() {
+        let rapi = rpi::start(1);
+        let mut site = site();
+        site.devices[0].name = "relay board".to_string();
+        assert!(matches!(site.validate(&rapi), Err(Error::Config(_))));
+
+        let mut site = self::site();
+        site.outputs[0].name = "read".to_string();
+        site.interlocks.clear();
+        assert!(matches!(site.validate(&rapi), Err(Error::Config(_))));
+    }
+}
@@ -1,31 +1,147 @@
 extern crate chrono;
 
-use crate::app::{AppID, db, device, input, output};
+use crate::app::audit::{self, AuditEntry};
+use crate::app::calibration::Calibration;
+use crate::app::interlock::{self, OutputStatus, Rule};
+use crate::app::output::{ErrorPolicy, OutputKind};
+use crate::app::site::Site;
+use crate::app::{AppID, db, device, input, output, switching};
 use crate::config;
-use crate::config::types::BoolExpr;
+use crate::config::types::{BoolExpr, Unit, Value};
 use crate::error::{Error, Result};
 use crate::rpi;
 use crate::rpi::device::Device;
+use crate::rpi::handle::DeviceHandle;
+use crate::rpi::health::{DeviceHealth, HealthStatus, RetryPolicy};
+use crate::rpi::i2c::mcp23017::PinChange;
+use crate::rpi::i2c::scan;
+use crate::rpi::w1;
+use crate::webapp::slugify;
 use chrono::prelude::*;
 use db::models;
+use futures::future::BoxFuture;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
 use tracing::{error, info, instrument, warn};
 
+use super::channel::AppMessage;
 use super::dimensioned::Dimensioned;
 
 /// Keep current app state in memory, together with device state
 pub struct State {
     dt: DateTime<Local>,
     db: db::Db,
-    devices: HashMap<AppID, rpi::device::Device>,
+    devices: HashMap<AppID, Running>,
+
+    /// Enabled devices that failed to start, and how they failed. They stay stopped until they're
+    /// enabled again.
+    failed_devices: HashMap<AppID, HealthStatus>,
 
     /// Cached output automation compilations with a flag for mark/sweep
-    output_automation_cache: HashMap<String, (bool, BoolExpr)>,
+    output_automation_cache: HashMap<String, (bool, Automation)>,
+
+    /// Last known transition of each output we have written to
+    output_history: HashMap<AppID, switching::History>,
+
+    /// The interlock each output was last stopped by, so a violation is recorded once rather than
+    /// on every automation pass that retries it
+    interlock_violations: HashMap<AppID, AppID>,
+
+    /// Last value each output's automation script evaluated to, for `ErrorPolicy::Last`
+    last_automation_value: HashMap<AppID, bool>,
+
+    /// Last level each level output's automation script evaluated to, for `ErrorPolicy::Last`
+    last_automation_level: HashMap<AppID, f64>,
+
+    /// Recent input readings, so automations, queries and metrics share one sampling pass. Shared
+    /// with reads that finish off the app loop.
+    reading_cache: Arc<Mutex<Readings>>,
+
+    /// How old a cached reading may be before the input is read again
+    reading_max_age: Duration,
+
+    /// How device reads are retried, and when failing devices are reset
+    retry: RetryPolicy,
+
+    /// When this state was created; outputs we know nothing about are assumed unchanged since
+    started_at: DateTime<Local>,
+
+    /// Lets device tasks tell the app loop about input changes they were interrupted with
+    sender: mpsc::Sender<AppMessage>,
+
+    /// Input changes reported by devices, for subscribers
+    input_changes: broadcast::Sender<input::InputChange>,
 
     i2c: rpi::RpiApi,
     here: (f64, f64),
 }
 
+/// A compiled automation script, of the kind its output needs
+#[derive(Clone, Debug)]
+enum Automation {
+    Switch(BoolExpr),
+    Level(Value),
+}
+
+impl Automation {
+    fn compile(kind: OutputKind, script: &str) -> Result<Self> {
+        match kind {
+            OutputKind::Boolean => Ok(Automation::Switch(config::parse::bool_expr(script)?)),
+            OutputKind::Level => Ok(Automation::Level(config::parse::value_expr(script)?)),
+        }
+    }
+
+    fn kind(&self) -> OutputKind {
+        match self {
+            Automation::Switch(_) => OutputKind::Boolean,
+            Automation::Level(_) => OutputKind::Level,
+        }
+    }
+}
+
+/// A running device, and the ID its input changes are reported under
+struct Running {
+    handle: DeviceHandle,
+
+    /// Shared with the device's task, so a rename doesn't need the device restarted
+    id: Arc<RwLock<AppID>>,
+}
+
+impl Running {
+    fn rename(&self, new_id: &AppID) {
+        *self.id.write().unwrap_or_else(|e| e.into_inner()) = new_id.clone();
+    }
+}
+
+/// Run a device in its own task, reporting interrupt-driven input changes back to the app loop
+fn spawn_device(
+    device: Device,
+    id: &AppID,
+    retry: RetryPolicy,
+    sender: &mpsc::Sender<AppMessage>,
+) -> Running {
+    let id = Arc::new(RwLock::new(id.clone()));
+    let device_id = id.clone();
+    let sender = sender.clone();
+    let handle = DeviceHandle::spawn_watching(
+        device,
+        retry,
+        Box::new(move |change| {
+            let device_id = device_id.read().unwrap_or_else(|e| e.into_inner()).clone();
+            let message = AppMessage::PinChanged {
+                device_id: device_id.clone(),
+                change,
+            };
+            if let Err(e) = sender.try_send(message) {
+                warn!("dropped input change from '{}': {}", device_id, e);
+            }
+        }),
+    );
+    Running { handle, id }
+}
+
 // Internal State machine for the application. this is core logic.
 impl State {
     pub fn lat(&self) -> f64 {
@@ -86,11 +202,45 @@ impl State {
     }
 
     pub fn device_slots(&self, name: &AppID) -> Result<Vec<device::Slot>> {
-        let mdev = self
-            .devices
-            .get(name)
-            .ok_or(Error::NonExistant("can't find device".to_string()))?;
-        Ok(mdev.slots())
+        match self.devices.get(name) {
+            Some(mdev) => Ok(mdev.handle.slots()),
+            // a disabled device isn't running, but its driver still knows its slots
+            None => {
+                let db_device = self.db.device(name)?;
+                let model = serde_json::from_str(&db_device.model)?;
+                Ok(Device::new(model, self.i2c.clone())?.slots())
+            }
+        }
+    }
+
+    /// The running device, or why there isn't one
+    fn live_device(&self, device_id: &AppID) -> Result<&DeviceHandle> {
+        match self.devices.get(device_id) {
+            Some(device) => Ok(&device.handle),
+            None => match self.db.device(device_id) {
+                Ok(db_device) if db_device.disabled => {
+                    Err(Error::DeviceDisabled(device_id.clone()))
+                }
+                Ok(_) if self.failed_devices.contains_key(device_id) => {
+                    Err(Error::DeviceReadError(format!(
+                        "device '{}' failed to start, enable it again to retry",
+                        device_id
+                    )))
+                }
+                _ => Err(Error::NonExistant(format!(
+                    "can't find device {}",
+                    device_id
+                ))),
+            },
+        }
+    }
+
+    pub fn device_health(&self, name: &AppID) -> Result<HealthStatus> {
+        match (self.devices.get(name), self.failed_devices.get(name)) {
+            (Some(mdev), _) => Ok(mdev.handle.health()),
+            (None, Some(failed)) => Ok(failed.clone()),
+            (None, None) => Err(Error::NonExistant("can't find device".to_string())),
+        }
     }
 
     pub async fn add_device(
@@ -100,21 +250,222 @@ impl State {
         description: String,
         disabled: Option<bool>,
     ) -> Result<AppID> {
-        let new_device = models::NewDevice::new(model, name, description, disabled);
+        // make the driver first, so a device nothing can drive is never stored
+        let driven = Device::new(model.clone(), self.i2c.clone())?;
+        let id = unique_id(&name, |id| self.db.device(id).is_ok());
+        let new_device = models::NewDevice::new(model, id, name, description, disabled);
         let db_device = self.db.add_device(&new_device)?;
-        let model = serde_json::from_str(db_device.model.as_str())?;
         let id = db_device.name;
-        let mut device = Device::new(model, self.i2c.clone());
-        device.reset().await?;
+        if db_device.disabled {
+            info!("Adding disabled device id: {}", id);
+            return Ok(id);
+        }
+        let device = spawn_device(driven, &id, self.retry, &self.sender);
+        device.handle.reset().await?;
         info!("Adding device id: {}", id);
         self.devices.insert(id.clone(), device);
         Ok(id.clone())
     }
 
+    /// Bring a device up, or take it down, without restarting. A device is only marked enabled
+    /// once it has been reset; outputs on a device being disabled are put in their safe states
+    /// first.
+    pub async fn set_device_disabled(&mut self, name: &AppID, disabled: bool) -> Result<()> {
+        let db_device = self.db.device(name)?;
+        if disabled {
+            let outputs = if self.devices.contains_key(name) {
+                self.db.outputs_for_device(name)?
+            } else {
+                vec![]
+            };
+            for output in outputs {
+                if let Some(safe) = output.safe_state
+                    && let Err(e) = self.apply_output_bool(&output, safe).await
+                {
+                    error!("failed to put '{}' in its safe state: {}", output.name, e);
+                }
+            }
+            self.db.set_device_disabled(name, true)?;
+            // the device's task stops once its handle is dropped
+            self.devices.remove(name);
+            self.failed_devices.remove(name);
+            self.reading_cache().clear();
+            info!("Disabled device '{}'", name);
+        } else if !self.devices.contains_key(name) {
+            let model = serde_json::from_str(&db_device.model)?;
+            let device = spawn_device(
+                Device::new(model, self.i2c.clone())?,
+                name,
+                self.retry,
+                &self.sender,
+            );
+            device.handle.reset().await?;
+            self.db.set_device_disabled(name, false)?;
+            self.devices.insert(name.clone(), device);
+            self.failed_devices.remove(name);
+            info!("Enabled device '{}'", name);
+        }
+        Ok(())
+    }
+
+    /// Change a device's notes or model settings in place, keeping its inputs and outputs. A
+    /// running device is replaced by one reset with the new model; if that fails, the old one
+    /// keeps running and nothing is changed.
+    pub async fn update_device(
+        &mut self,
+        name: &AppID,
+        fields: models::UpdateDevice,
+    ) -> Result<AppID> {
+        let db_device = self.db.device(name)?;
+        let old_model: crate::app::device::Type = serde_json::from_str(&db_device.model)?;
+        let model = old_model.updated(&fields)?;
+        let display_name = fields.display_name.unwrap_or(db_device.display_name);
+        let notes = fields.notes.unwrap_or(db_device.notes);
+        if model != old_model {
+            let driven = Device::new(model.clone(), self.i2c.clone())?;
+            let slots = driven.slots();
+            for input in self.db.inputs_for_device(name)? {
+                check_input_slot(&slots, name, input.device_input_id)?;
+            }
+            for output in self.db.outputs_for_device(name)? {
+                check_output_slot(
+                    &slots,
+                    &output.name,
+                    &output.device_id,
+                    output.device_output_id,
+                    output.kind,
+                )?;
+            }
+            if self.devices.contains_key(name) {
+                let device = spawn_device(driven, name, self.retry, &self.sender);
+                device.handle.reset().await?;
+                // the old device's task stops once its handle is dropped
+                self.devices.insert(name.clone(), device);
+                self.reading_cache().clear();
+            }
+        }
+        self.db.update_device(name, &model, &display_name, &notes)?;
+        info!("Updated device '{}'", name);
+        Ok(name.clone())
+    }
+
+    /// Give a device a new name, moving its inputs and outputs with it. A running device keeps
+    /// running, and reports input changes under the new name once it is stored.
+    pub async fn rename_device(&mut self, name: &AppID, new_name: &AppID) -> Result<AppID> {
+        self.db.device(name)?;
+        if !check_rename(name, new_name)? {
+            return Ok(new_name.clone());
+        }
+        if self.db.device(new_name).is_ok() {
+            return Err(Error::NotUnique(format!(
+                "a device named '{}' already exists",
+                new_name
+            )));
+        }
+        self.db.rename_device(name, new_name)?;
+        if let Some(device) = self.devices.remove(name) {
+            device.rename(new_name);
+            self.devices.insert(new_name.clone(), device);
+        }
+        if let Some(failed) = self.failed_devices.remove(name) {
+            self.failed_devices.insert(new_name.clone(), failed);
+        }
+        info!("Renamed device '{}' to '{}'", name, new_name);
+        Ok(new_name.clone())
+    }
+
+    /// Start and reset every enabled device in the database. A device that can't be driven or
+    /// fails to reset is left stopped, with why in its health, so one unplugged sensor doesn't
+    /// stop everything else.
+    async fn start_devices(&mut self) -> Result<()> {
+        for db_device in self.db.devices()? {
+            if db_device.disabled {
+                info!("Skipping disabled device '{}'", db_device.name);
+                continue;
+            }
+            let model: crate::app::device::Type = serde_json::from_str(&db_device.model)?;
+            info!("Adding device {:?} named '{}'", model, db_device.name);
+            let health = match Device::new(model, self.i2c.clone()) {
+                Ok(driven) => {
+                    let device = spawn_device(driven, &db_device.name, self.retry, &self.sender);
+                    match device.handle.reset().await {
+                        Ok(()) => {
+                            self.devices.insert(db_device.name, device);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("device '{}' failed to start: {}", db_device.name, e);
+                            device.handle.health()
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("device '{}' can't be driven: {}", db_device.name, e);
+                    let health = DeviceHealth::new();
+                    health.failed(&e);
+                    health.status()
+                }
+            };
+            self.failed_devices.insert(db_device.name, health);
+        }
+        Ok(())
+    }
+
+    pub fn export_site(&self) -> Result<Site> {
+        self.db.export()
+    }
+
+    pub fn record_audit(&self, entry: &models::NewAuditEntry) -> Result<()> {
+        self.db.add_audit_entry(entry)
+    }
+
+    pub fn audit_log(&self, limit: i64, before_id: Option<i32>) -> Result<Vec<AuditEntry>> {
+        Ok(self
+            .db
+            .audit_log(limit, before_id)?
+            .into_iter()
+            .map(|data| AuditEntry { data })
+            .collect())
+    }
+
+    /// Delete audit entries older than `older_than_days`, returning how many were deleted
+    pub fn prune_audit_log(&self, older_than_days: u32) -> Result<usize> {
+        let cutoff = Utc::now().naive_utc() - chrono::Duration::days(older_than_days.into());
+        let pruned = self.db.prune_audit_log(cutoff)?;
+        info!("Pruned {} audit log entries before {}", pruned, cutoff);
+        Ok(pruned)
+    }
+
+    /// Replace the whole configuration with `site`. Outputs are put in their safe states before
+    /// their devices are stopped, then the new devices are started as they would be at startup.
+    /// Once the site is stored it is kept: a new device that fails to reset is left stopped, and
+    /// everything else still gets its safe state and automations.
+    pub async fn import_site(&mut self, site: Site) -> Result<()> {
+        site.validate(&self.i2c)?;
+        self.apply_safe_states().await?;
+        self.db.import(&site)?;
+        self.devices.clear();
+        self.failed_devices.clear();
+        self.reading_cache().clear();
+        self.output_history.clear();
+        self.interlock_violations.clear();
+        self.last_automation_value.clear();
+        self.last_automation_level.clear();
+        self.start_devices().await?;
+        self.apply_safe_states().await?;
+        self.compile_automations().await
+    }
+
     pub async fn add_input(&mut self, config: &models::NewInput) -> Result<AppID> {
-        let mdev = self.devices.get_mut(&config.device_id);
-        if let Some(_dev) = mdev {
-            let db_input = self.db.add_input(config)?;
+        // disabled devices can be given inputs too, ready for when they're enabled
+        if self.db.device(&config.device_id).is_ok() {
+            if let Some(table) = &config.calibration {
+                Calibration::parse_table(table)?;
+            }
+            let mut new_input = config.clone();
+            new_input.display_name = config.name.clone();
+            new_input.name = unique_id(&config.name, |id| self.db.input(id).is_ok());
+            let db_input = self.db.add_input(&new_input)?;
             Ok(db_input.name)
         } else {
             Err(Error::NonExistant(format!(
@@ -124,18 +475,112 @@ impl State {
         }
     }
 
+    /// Change an input's binding or calibration. A new binding must be to a slot that can be read.
+    pub async fn update_input(
+        &mut self,
+        input_id: &AppID,
+        fields: models::UpdateInput,
+    ) -> Result<AppID> {
+        let current = self.db.input(input_id)?;
+        let device_id = fields.device_id.as_ref().unwrap_or(&current.device_id);
+        let device_input_id = fields.device_input_id.unwrap_or(current.device_input_id);
+        if fields.device_id.is_some() || fields.device_input_id.is_some() {
+            if self.db.device(device_id).is_err() {
+                return Err(Error::NonExistant(format!(
+                    "Could not move input to missing device {}",
+                    device_id
+                )));
+            }
+            check_input_slot(&self.device_slots(device_id)?, device_id, device_input_id)?;
+        }
+        if let Some(Some(table)) = &fields.calibration {
+            Calibration::parse_table(table)?;
+        }
+        self.db.update_input(input_id, &fields)?;
+        self.reading_cache().remove(input_id);
+        Ok(input_id.clone())
+    }
+
+    /// Give an input a new name, rewriting the automation scripts that read it
+    pub async fn rename_input(&mut self, input_id: &AppID, new_name: &AppID) -> Result<AppID> {
+        self.db.input(input_id)?;
+        if !check_rename(input_id, new_name)? {
+            return Ok(new_name.clone());
+        }
+        if self.db.input(new_name).is_ok() {
+            return Err(Error::NotUnique(format!(
+                "an input named '{}' already exists",
+                new_name
+            )));
+        }
+        let mut scripts = vec![];
+        for output in self.db.outputs()? {
+            if let Some(script) = &output.automation_script {
+                let renamed = config::parse::rename_identifier(script, input_id, new_name)?;
+                if &renamed != script {
+                    scripts.push((output.name, renamed));
+                }
+            }
+        }
+        self.db.rename_input(input_id, new_name, &scripts)?;
+        let mut cache = self.reading_cache();
+        if let Some(reading) = cache.remove(input_id) {
+            cache.insert(new_name.clone(), reading);
+        }
+        info!(
+            "Renamed input '{}' to '{}', rewriting {} scripts",
+            input_id,
+            new_name,
+            scripts.len()
+        );
+        Ok(new_name.clone())
+    }
+
     pub async fn remove_input(&mut self, input_id: &AppID) -> Result<()> {
+        self.reading_cache().remove(input_id);
         self.db.remove_input(input_id)
     }
 
+    /// Remove an output, unless an interlock refers to it
     pub async fn remove_output(&mut self, output_id: &AppID) -> Result<()> {
+        self.check_not_interlocked(&[output_id])?;
         self.db.remove_output(output_id)
     }
 
+    /// Fail if an interlock refers to any of `outputs`, since it would quietly stop protecting
+    /// anything once they were gone
+    fn check_not_interlocked(&self, outputs: &[&AppID]) -> Result<()> {
+        for interlock in self.db.interlocks()? {
+            let rule: Rule = serde_json::from_str(&interlock.rule)?;
+            if let Some(output) = rule.outputs().into_iter().find(|o| outputs.contains(o)) {
+                return Err(Error::Config(format!(
+                    "interlock '{}' refers to '{}', remove the interlock first",
+                    interlock.name, output
+                )));
+            }
+        }
+        Ok(())
+    }
+
     pub async fn add_output(&mut self, config: &models::NewOutput) -> Result<AppID> {
-        let mdev = self.devices.get_mut(&config.device_id);
-        if let Some(_dev) = mdev {
-            let db_output = self.db.add_output(config)?;
+        switching::check_max_switches(config.max_switches_per_hour)?;
+        if self.db.device(&config.device_id).is_ok() {
+            if config.kind == Some(OutputKind::Level) {
+                let slot = self
+                    .device_slots(&config.device_id)?
+                    .get(config.device_output_id as usize)
+                    .copied();
+                if slot.map(|s| s.unit) != Some(Unit::Ratio) {
+                    return Err(Error::Config(format!(
+                        "slot {} of '{}' can't be set to a level",
+                        config.device_output_id, config.device_id
+                    )));
+                }
+            }
+            let mut new_output = config.clone();
+            new_output.display_name = config.name.clone();
+            new_output.name = unique_id(&config.name, |id| self.db.output(id).is_ok());
+            let db_output = self.db.add_output(&new_output)?;
             Ok(db_output.name)
         } else {
             Err(Error::NonExistant(format!(
@@ -150,6 +595,9 @@ impl State {
         output_id: AppID,
         fields: models::UpdateOutput,
     ) -> Result<AppID> {
+        if let Some(max) = fields.max_switches_per_hour {
+            switching::check_max_switches(max)?;
+        }
         let _mout = self.db.update_output(&output_id, &fields)?;
         if let models::UpdateOutput {
             automation_script: Some(Some(q)),
@@ -161,18 +609,96 @@ impl State {
         Ok(output_id)
     }
 
+    /// Give an output a new name, rewriting the interlocks that refer to it
+    pub async fn rename_output(&mut self, output_id: &AppID, new_name: &AppID) -> Result<AppID> {
+        self.db.output(output_id)?;
+        if !check_rename(output_id, new_name)? {
+            return Ok(new_name.clone());
+        }
+        if self.db.output(new_name).is_ok() {
+            return Err(Error::NotUnique(format!(
+                "an output named '{}' already exists",
+                new_name
+            )));
+        }
+        let mut rules = vec![];
+        for interlock in self.db.interlocks()? {
+            let mut rule: Rule = serde_json::from_str(&interlock.rule)?;
+            if rule.rename_output(output_id, new_name) {
+                rules.push((interlock.name, serde_json::to_string(&rule)?));
+            }
+        }
+        self.db.rename_output(output_id, new_name, &rules)?;
+        if let Some(history) = self.output_history.remove(output_id) {
+            self.output_history.insert(new_name.clone(), history);
+        }
+        if let Some(interlock) = self.interlock_violations.remove(output_id) {
+            self.interlock_violations
+                .insert(new_name.clone(), interlock);
+        }
+        if let Some(value) = self.last_automation_value.remove(output_id) {
+            self.last_automation_value.insert(new_name.clone(), value);
+        }
+        if let Some(level) = self.last_automation_level.remove(output_id) {
+            self.last_automation_level.insert(new_name.clone(), level);
+        }
+        info!("Renamed output '{}' to '{}'", output_id, new_name);
+        Ok(new_name.clone())
+    }
+
+    pub fn interlocks(&self) -> Result<Vec<interlock::Interlock>> {
+        let models = self.db.interlocks()?;
+        Ok(models
+            .iter()
+            .map(|d| interlock::Interlock { data: d.clone() })
+            .collect())
+    }
+
+    pub async fn add_interlock(&mut self, name: String, rule: Rule) -> Result<AppID> {
+        rule.validate()?;
+        for output_id in rule.outputs() {
+            self.db.output(output_id).map_err(|_| {
+                Error::OutputNotFound(format!("interlock refers to missing output {}", output_id))
+            })?;
+        }
+        let db_interlock = self
+            .db
+            .add_interlock(&models::NewInterlock::new(name, &rule))?;
+        info!("Adding interlock id: {}", db_interlock.name);
+        Ok(db_interlock.name)
+    }
+
+    pub async fn remove_interlock(&mut self, name: &AppID) -> Result<()> {
+        info!("Remove interlock: '{}'", name);
+        self.db.remove_interlock(name)
+    }
+
+    /// Probe the I2C bus for devices we could add
+    pub async fn i2c_scan(&self) -> Result<Vec<scan::Detected>> {
+        scan::scan(&self.i2c).await
+    }
+
+    /// ROM IDs of the DS18B20 probes on the 1-Wire bus
+    pub async fn w1_scan(&self) -> Result<Vec<String>> {
+        w1::W1Bus::default().rom_ids().await
+    }
+
     pub async fn reset_device(&mut self, id: &AppID) -> Result<()> {
-        let device = self.devices.get_mut(id).ok_or(Error::NonExistant(
-            format!("reset_device: {}", id).to_string(),
-        ))?;
+        let device = self.live_device(id)?;
         device.reset().await?;
         Ok(())
     }
 
+    /// Remove a device along with its inputs and outputs, unless an interlock refers to one of
+    /// the outputs
     pub async fn remove_device(&mut self, name: &AppID) -> Result<()> {
+        let outputs = self.db.outputs_for_device(name)?;
+        self.check_not_interlocked(&outputs.iter().map(|o| &o.name).collect::<Vec<_>>())?;
         info!("Remove device: '{}'", name);
         self.db.remove_device(name)?;
         self.devices.remove(name);
+        self.failed_devices.remove(name);
+        self.reading_cache().clear();
         Ok(())
     }
 
@@ -196,11 +722,8 @@ impl State {
     pub async fn read_output_bool(&self, output_id: &AppID) -> Result<bool> {
         let output = self.db.output(output_id)?;
 
-        if let Some(device) = self.devices.get(&output.device_id) {
-            Ok(device.read_boolean(output.device_output_id).await?)
-        } else {
-            Err(Error::NonExistant("can't find device".to_string()))
-        }
+        let device = self.live_device(&output.device_id)?;
+        device.read_boolean(output.device_output_id).await
     }
 
     /**
@@ -209,25 +732,259 @@ impl State {
     pub async fn read_input_bool(&self, input_id: &AppID) -> Result<bool> {
         let input = self.db.input(input_id)?;
 
-        if let Some(device) = self.devices.get(&input.device_id) {
-            Ok(device.read_boolean(input.device_input_id).await?)
-        } else {
-            Err(Error::NonExistant("can't find device".to_string()))
-        }
+        let device = self.live_device(&input.device_id)?;
+        device.read_boolean(input.device_input_id).await
     }
 
     /**
-     * Write a particular value to an output
+     * Request a value for an output. If its switching limits don't allow the change yet, it is
+     * deferred and applied by `emit_automations` once they do.
      */
     pub async fn write_output_bool(&mut self, output_id: &AppID, value: bool) -> Result<()> {
         let output = self.db.output(output_id)?;
+        let limits = switching::Limits::from(&output);
+        let now = self.dt;
+        if let Some(history) = self.output_history.get_mut(output_id)
+            && history.on != value
+            && let Some(not_before) = history.earliest_change(&limits, now)
+        {
+            if history.pending.map(|p| p.value) != Some(value) {
+                info!(
+                    "Deferring '{}' turning {} until {}",
+                    output_id,
+                    if value { "on" } else { "off" },
+                    not_before
+                );
+            }
+            history.pending = Some(switching::Pending { value, not_before });
+            return Ok(());
+        }
+        self.apply_output_bool(&output, value).await
+    }
 
-        if let Some(device) = self.devices.get_mut(&output.device_id) {
-            device
-                .write_boolean(output.device_output_id, output.active_low ^ value)
-                .await
-        } else {
-            Err(Error::NonExistant("can't find device".to_string()))
+    /**
+     * Write a particular value to an output, provided no interlock forbids it
+     */
+    async fn apply_output_bool(&mut self, output: &models::Output, value: bool) -> Result<()> {
+        let output_id = &output.name;
+        let was_on = self.output_history.get(output_id).map(|h| h.on);
+        if value && was_on != Some(true) {
+            self.check_interlocks(output_id).await?;
+        }
+
+        let device = self.live_device(&output.device_id)?;
+        match output.kind {
+            OutputKind::Boolean => {
+                device
+                    .write_boolean(output.device_output_id, output.active_low ^ value)
+                    .await?
+            }
+            OutputKind::Level => {
+                let level = if value { 1.0 } else { 0.0 };
+                device
+                    .write_level(output.device_output_id, flip_level(output, level))
+                    .await?
+            }
+        }
+        let now = self.dt;
+        self.output_history
+            .entry(output_id.clone())
+            .and_modify(|h| {
+                h.record(value, now);
+            })
+            .or_insert_with(|| switching::History::new(value, now));
+        Ok(())
+    }
+
+    /// Set a level output to a duty cycle from 0 to 1. Levels aren't subject to switching limits.
+    pub async fn write_output_level(&mut self, output_id: &AppID, level: f64) -> Result<()> {
+        let output = self.db.output(output_id)?;
+        if output.kind != OutputKind::Level {
+            return Err(Error::Config(format!(
+                "'{}' is switched on and off, it has no level",
+                output_id
+            )));
+        }
+        if !(0.0..=1.0).contains(&level) {
+            return Err(Error::Config(format!(
+                "level {} is not between 0 and 1",
+                level
+            )));
+        }
+        self.apply_output_level(&output, level).await
+    }
+
+    /// Write a level to an output, provided no interlock forbids turning it on
+    async fn apply_output_level(&mut self, output: &models::Output, level: f64) -> Result<()> {
+        let output_id = &output.name;
+        let on = level > 0.0;
+        let was_on = self.output_history.get(output_id).map(|h| h.on);
+        if on && was_on != Some(true) {
+            self.check_interlocks(output_id).await?;
+        }
+
+        let device = self.live_device(&output.device_id)?;
+        device
+            .write_level(output.device_output_id, flip_level(output, level))
+            .await?;
+        let now = self.dt;
+        self.output_history
+            .entry(output_id.clone())
+            .and_modify(|h| {
+                h.record(on, now);
+            })
+            .or_insert_with(|| switching::History::new(on, now));
+        Ok(())
+    }
+
+    /// The level an output is set to
+    pub async fn read_output_level(&self, output_id: &AppID) -> Result<f64> {
+        let output = self.db.output(output_id)?;
+        let device = self.live_device(&output.device_id)?;
+        let level = device.read_level(output.device_output_id).await?;
+        Ok(flip_level(&output, level))
+    }
+
+    /// A change to the output held back by its switching limits, if any
+    pub fn pending_output_change(&self, output_id: &AppID) -> Option<switching::Pending> {
+        self.output_history.get(output_id).and_then(|h| h.pending)
+    }
+
+    /// Write any deferred output changes whose time has come
+    async fn apply_pending_outputs(&mut self) -> Result<()> {
+        let now = self.dt;
+        let due: Vec<(AppID, bool)> = self
+            .output_history
+            .iter()
+            .filter_map(|(id, h)| {
+                h.pending
+                    .filter(|p| p.not_before <= now)
+                    .map(|p| (id.clone(), p.value))
+            })
+            .collect();
+        for (output_id, value) in due {
+            if let Some(history) = self.output_history.get_mut(&output_id) {
+                history.pending = None;
+            }
+            if let Err(e) = self.write_output_bool(&output_id, value).await {
+                error!("failed to apply deferred write to '{}': {}", output_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// What interlocks need to know about an output, or None if it no longer exists.
+    /// An output we can't read is assumed to be on.
+    async fn output_status(&self, output_id: &AppID) -> Option<OutputStatus> {
+        if let Some(history) = self.output_history.get(output_id) {
+            return Some(OutputStatus {
+                on: history.on,
+                off_since: history.changed_at,
+            });
+        }
+        let output = self.db.output(output_id).ok()?;
+        let on = match self.read_output_bool(output_id).await {
+            Ok(raw) => raw ^ output.active_low,
+            Err(e) => {
+                warn!("assuming '{}' is on, as it can't be read: {}", output_id, e);
+                true
+            }
+        };
+        Some(OutputStatus {
+            on,
+            off_since: self.started_at,
+        })
+    }
+
+    /// Check that no interlock forbids turning the given output on right now. A violation is
+    /// recorded in the audit log the first time an interlock stops the output.
+    async fn check_interlocks(&mut self, output_id: &AppID) -> Result<()> {
+        for interlock in self.db.interlocks()? {
+            let rule: Rule = serde_json::from_str(&interlock.rule)?;
+            let mut status = HashMap::new();
+            for other in rule.dependencies(output_id) {
+                if let Some(s) = self.output_status(other).await {
+                    status.insert(other.clone(), s);
+                }
+            }
+            if let Err(reason) = rule.permits(output_id, &status, self.dt) {
+                warn!(
+                    "Interlock '{}' prevented '{}' from turning on: {}",
+                    interlock.name, output_id, reason
+                );
+                self.record_violation(output_id, &interlock.name, &reason);
+                return Err(Error::InterlockViolation(format!(
+                    "{}: {}",
+                    interlock.name, reason
+                )));
+            }
+        }
+        self.interlock_violations.remove(output_id);
+        Ok(())
+    }
+
+    fn record_violation(&mut self, output_id: &AppID, interlock_id: &AppID, reason: &str) {
+        if self.interlock_violations.get(output_id) == Some(interlock_id) {
+            return;
+        }
+        self.interlock_violations
+            .insert(output_id.clone(), interlock_id.clone());
+        let entry = models::NewAuditEntry {
+            user: audit::SYSTEM_USER.to_string(),
+            mutation: audit::INTERLOCK_VIOLATION.to_string(),
+            arguments: serde_json::json!({ "interlock": interlock_id, "output": output_id })
+                .to_string(),
+            succeeded: false,
+            error: Some(reason.to_string()),
+        };
+        if let Err(e) = self.db.add_audit_entry(&entry) {
+            error!("failed to record interlock violation: {}", e);
+        }
+    }
+
+    /// Write every output that has a safe state to it, bypassing switching limits
+    pub async fn apply_safe_states(&mut self) -> Result<()> {
+        for output in self.db.outputs()? {
+            if !self.devices.contains_key(&output.device_id) {
+                continue;
+            }
+            if let Some(safe) = output.safe_state {
+                info!("Putting '{}' in its safe state ({})", output.name, safe);
+                if let Err(e) = self.apply_output_bool(&output, safe).await {
+                    error!("failed to put '{}' in its safe state: {}", output.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// React to the output's automation script failing to evaluate
+    async fn apply_error_policy(&mut self, output: &models::Output) {
+        if output.kind == OutputKind::Level && output.error_policy == ErrorPolicy::Last {
+            if let Some(level) = self.last_automation_level.get(&output.name).copied()
+                && let Err(e) = self.apply_output_level(output, level).await
+            {
+                error!("failed to write: {}", e);
+            }
+            return;
+        }
+        let value = match output.error_policy {
+            ErrorPolicy::Hold => None,
+            ErrorPolicy::Safe => {
+                if output.safe_state.is_none() {
+                    warn!(
+                        "'{}' has no safe state to fall back to, holding it instead",
+                        output.name
+                    );
+                }
+                output.safe_state
+            }
+            ErrorPolicy::Last => self.last_automation_value.get(&output.name).copied(),
+        };
+        if let Some(value) = value
+            && let Err(e) = self.write_output_bool(&output.name, value).await
+        {
+            error!("failed to write: {}", e);
         }
     }
 
@@ -239,7 +996,7 @@ impl State {
         let outputs = self.db.outputs()?;
         for output in outputs {
             if let Some(str_expr) = &output.automation_script {
-                match config::parse::bool_expr(str_expr) {
+                match Automation::compile(output.kind, str_expr) {
                     Ok(expr) => {
                         self.output_automation_cache
                             .insert(str_expr.clone(), (false, expr));
@@ -257,6 +1014,8 @@ impl State {
     /// update automation script cache and emit automations
     #[instrument(skip(self))]
     pub async fn emit_automations(&mut self) -> Result<()> {
+        self.apply_pending_outputs().await?;
+
         // Clear mark on all entries
         for (mark, _) in self.output_automation_cache.values_mut() {
             *mark = false
@@ -264,38 +1023,72 @@ impl State {
 
         let outputs = self.db.outputs()?;
         for output in outputs {
+            // outputs on disabled devices are left alone until the device is enabled
+            if !self.devices.contains_key(&output.device_id) {
+                continue;
+            }
             if let Some(str_expr) = &output.automation_script {
-                // get or update the cached boolean expression
-                let expr: Option<BoolExpr> =
-                    if let Some((mark, expr)) = self.output_automation_cache.get_mut(str_expr) {
-                        *mark = true;
-                        Some(expr.clone())
-                    } else {
-                        match config::parse::bool_expr(str_expr) {
-                            Ok(expr) => {
-                                self.output_automation_cache
-                                    .insert(str_expr.clone(), (true, expr.clone()));
-                                Some(expr)
-                            }
-                            Err(e) => {
-                                error!("error parsing automation script: {}", e);
-                                None
-                            }
+                // get or update the cached expression
+                let cached = self
+                    .output_automation_cache
+                    .get_mut(str_expr)
+                    .filter(|(_, expr)| expr.kind() == output.kind);
+                let expr: Option<Automation> = if let Some((mark, expr)) = cached {
+                    *mark = true;
+                    Some(expr.clone())
+                } else {
+                    match Automation::compile(output.kind, str_expr) {
+                        Ok(expr) => {
+                            self.output_automation_cache
+                                .insert(str_expr.clone(), (true, expr.clone()));
+                            Some(expr)
+                        }
+                        Err(e) => {
+                            error!("error parsing automation script: {}", e);
+                            None
                         }
-                    };
+                    }
+                };
 
                 // evaluate the expression and write it to the right output
-                if let Some(expr) = expr {
-                    match config::boolean::evaluate(self, &expr).await {
-                        Ok(result) => {
-                            if let Err(e) = self.write_output_bool(&output.name, result).await {
-                                error!("failed to write: {}", e);
+                match expr {
+                    Some(Automation::Switch(expr)) => {
+                        match config::boolean::evaluate(self, &expr).await {
+                            Ok(result) => {
+                                self.last_automation_value
+                                    .insert(output.name.clone(), result);
+                                if let Err(e) = self.write_output_bool(&output.name, result).await {
+                                    error!("failed to write: {}", e);
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Automation expression {:?} evaluation failed: {}", expr, e);
+                                self.apply_error_policy(&output).await;
                             }
                         }
-                        Err(e) => {
-                            warn!("Automation expression {:?} evaluation failed: {}", expr, e)
+                    }
+                    Some(Automation::Level(expr)) => {
+                        match config::value::evaluate(self, &expr).await {
+                            // a duty cycle can't go beyond fully off or fully on
+                            Ok(result) if !result.is_nan() => {
+                                let level = result.clamp(0.0, 1.0);
+                                self.last_automation_level
+                                    .insert(output.name.clone(), level);
+                                if let Err(e) = self.apply_output_level(&output, level).await {
+                                    error!("failed to write: {}", e);
+                                }
+                            }
+                            Ok(_) => {
+                                warn!("Automation expression {:?} isn't a number", expr);
+                                self.apply_error_policy(&output).await;
+                            }
+                            Err(e) => {
+                                warn!("Automation expression {:?} evaluation failed: {}", expr, e);
+                                self.apply_error_policy(&output).await;
+                            }
                         }
                     }
+                    None => (),
                 }
             }
         }
@@ -315,42 +1108,670 @@ impl State {
         Ok(())
     }
 
+    /// Read an input, using a cached reading if it is recent enough
     pub async fn read_input_value(&self, input_id: &AppID) -> Result<Dimensioned> {
+        self.input_read(input_id, false)?.await
+    }
+
+    /// Read an input from its device, bypassing the cache
+    pub async fn read_input_value_fresh(&self, input_id: &AppID) -> Result<Dimensioned> {
+        self.input_read(input_id, true)?.await
+    }
+
+    /// Start reading an input, from the cache unless `fresh` or the reading is too old.
+    ///
+    /// The returned future doesn't borrow the state, so the app loop can hand it off rather than
+    /// wait for a slow device while other messages queue up behind it.
+    pub fn input_read(
+        &self,
+        input_id: &AppID,
+        fresh: bool,
+    ) -> Result<BoxFuture<'static, Result<Dimensioned>>> {
+        if !fresh {
+            let cached = self
+                .reading_cache()
+                .get(input_id)
+                .filter(|(read_at, _)| read_at.elapsed() <= self.reading_max_age)
+                .map(|(_, value)| value.clone());
+            if let Some(value) = cached {
+                return Ok(Box::pin(async move { Ok(value) }));
+            }
+        }
+
         let input = self.db.input(input_id)?;
+        let device = self.live_device(&input.device_id)?.clone();
+        let calibration = Calibration::from_input(&input)?;
+        let cache = self.reading_cache.clone();
+        Ok(Box::pin(async move {
+            let raw = device.read_sensor(input.device_input_id).await?;
+            let value = calibration.apply_to(raw);
+            lock_readings(&cache).insert(input.name, (Instant::now(), value.clone()));
+            Ok(value)
+        }))
+    }
 
-        if let Some(device) = self.devices.get(&input.device_id) {
-            Ok(device.read_sensor(input.device_input_id).await?)
-        } else {
-            Err(Error::NonExistant("can't find device".to_string()))
+    /// Set the input's offset so that its current reading matches `reference_value`, returning
+    /// the new offset
+    pub async fn calibrate_input(&mut self, input_id: &AppID, reference_value: f64) -> Result<f64> {
+        let input = self.db.input(input_id)?;
+        let device = self.live_device(&input.device_id)?;
+        let raw = device.read_sensor(input.device_input_id).await?;
+        if raw.unit()? == Unit::Boolean {
+            return Err(Error::Config(format!(
+                "'{}' reads a boolean, which can't be calibrated",
+                input_id
+            )));
+        }
+        let calibration = Calibration::from_input(&input)?;
+        let offset = reference_value - calibration.apply_without_offset(raw.value()?);
+        self.db.set_input_offset(input_id, offset)?;
+        self.reading_cache().remove(input_id);
+        info!("Calibrated '{}' with offset {}", input_id, offset);
+        Ok(offset)
+    }
+
+    /// A device interrupt reported that one of its pins changed; update the inputs reading it
+    pub fn pin_changed(&self, device_id: &AppID, change: PinChange) -> Result<()> {
+        let inputs = self.db.inputs_for_device(device_id)?;
+        for input in inputs
+            .into_iter()
+            .filter(|i| i.device_input_id as usize == change.index)
+        {
+            let value =
+                Calibration::from_input(&input)?.apply_to(Dimensioned::from_bool(change.value));
+            self.reading_cache()
+                .insert(input.name.clone(), (Instant::now(), value));
+            // nobody listening is fine
+            let _ = self.input_changes.send(input::InputChange {
+                input_id: input.name,
+                value: change.value,
+                at: self.dt.to_rfc3339(),
+            });
         }
+        Ok(())
+    }
+
+    fn reading_cache(&self) -> MutexGuard<'_, Readings> {
+        lock_readings(&self.reading_cache)
     }
 }
 
-pub async fn new_state(bus: u8, here: (f64, f64), db: crate::app::db::Db) -> Result<State> {
-    let dt = Local::now();
-    let i2c = rpi::start(bus);
+/// Each input's last reading, and when it was taken
+type Readings = HashMap<AppID, (Instant, Dimensioned)>;
 
-    let mut device_instances: HashMap<AppID, Device> = HashMap::new();
+fn lock_readings(readings: &Mutex<Readings>) -> MutexGuard<'_, Readings> {
+    // readings are replaced whole, so a poisoned cache is still consistent
+    readings.lock().unwrap_or_else(|e| e.into_inner())
+}
 
-    let devices = db.devices()?;
-    for db_device in &devices {
-        let model = serde_json::from_str(&db_device.model)?;
-        info!("Adding device {:?} named '{}'", model, db_device.name);
-        let mut new_device = Device::new(model, i2c.clone());
-        new_device.reset().await?;
-        device_instances.insert(db_device.name.clone(), new_device);
-    }
+pub async fn new_state(
+    bus: u8,
+    here: (f64, f64),
+    reading_max_age: Duration,
+    retry: RetryPolicy,
+    db: crate::app::db::Db,
+    sender: mpsc::Sender<AppMessage>,
+    input_changes: broadcast::Sender<input::InputChange>,
+) -> Result<State> {
+    let dt = Local::now();
+    let i2c = rpi::start(bus);
 
     let mut state = State {
         i2c,
         dt,
         db,
         output_automation_cache: HashMap::new(),
-        devices: device_instances,
+        output_history: HashMap::new(),
+        interlock_violations: HashMap::new(),
+        last_automation_value: HashMap::new(),
+        last_automation_level: HashMap::new(),
+        reading_cache: Arc::new(Mutex::new(HashMap::new())),
+        reading_max_age,
+        retry,
+        started_at: dt,
+        devices: HashMap::new(),
+        failed_devices: HashMap::new(),
+        sender,
+        input_changes,
         here,
     };
 
+    state.start_devices().await?;
+    state.apply_safe_states().await?;
     state.compile_automations().await?;
 
     Ok(state)
 }
+
+/// A new ID for something called `name`, one that expressions can use as an identifier and that
+/// isn't `taken` already
+fn unique_id(name: &str, taken: impl Fn(&AppID) -> bool) -> AppID {
+    (0..)
+        .map(|inc| slugify::identifier(name, inc))
+        .find(|id| config::parse::is_identifier(id) && !taken(id))
+        .expect("some suffix is always free")
+}
+
+/// Fail unless expressions can use `name` as an identifier, as they can every ID made here
+pub(crate) fn check_identifier(name: &str) -> Result<()> {
+    if config::parse::is_identifier(name) {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "'{}' can't be used as a name, expressions couldn't refer to it",
+            name
+        )))
+    }
+}
+
+/// Fail unless `new_name` can be used as an ID. Renaming something to the name it already has is
+/// allowed, but there's nothing to do, so that gives `false`.
+fn check_rename(name: &AppID, new_name: &AppID) -> Result<bool> {
+    check_identifier(new_name)?;
+    Ok(name != new_name)
+}
+
+/// Fail unless `device_input_id` is a slot of the device that can be read
+pub(crate) fn check_input_slot(
+    slots: &[device::Slot],
+    device_id: &str,
+    device_input_id: i32,
+) -> Result<()> {
+    match usize::try_from(device_input_id)
+        .ok()
+        .and_then(|index| slots.get(index))
+    {
+        Some(slot) if slot.can_input => Ok(()),
+        Some(_) => Err(Error::Config(format!(
+            "slot {} of '{}' can't be read",
+            device_input_id, device_id
+        ))),
+        None => Err(Error::Config(format!(
+            "'{}' has no slot {}",
+            device_id, device_input_id
+        ))),
+    }
+}
+
+/// Fail unless output `name` can be written to `device_output_id`, as the kind of output it is
+pub(crate) fn check_output_slot(
+    slots: &[device::Slot],
+    name: &str,
+    device_id: &str,
+    device_output_id: i32,
+    kind: OutputKind,
+) -> Result<()> {
+    let slot = usize::try_from(device_output_id)
+        .ok()
+        .and_then(|index| slots.get(index))
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "'{}' has no slot {} for output '{}'",
+                device_id, device_output_id, name
+            ))
+        })?;
+    let writable = match kind {
+        OutputKind::Boolean => slot.can_output,
+        OutputKind::Level => slot.unit == Unit::Ratio,
+    };
+    if writable {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "output '{}' would be on slot {} of '{}', which can't be written",
+            name, device_output_id, device_id
+        )))
+    }
+}
+
+/// Active low level outputs are driven with the complement of their level
+fn flip_level(output: &models::Output, level: f64) -> f64 {
+    if output.active_low {
+        1.0 - level
+    } else {
+        level
+    }
+}
+
+#[cfg(all(test, feature = "mock-gpio"))]
+mod tests {
+    use super::*;
+    use crate::rpi::driver::{self, Driver};
+
+    type Writes = Arc<Mutex<Vec<(i32, bool)>>>;
+
+    /// Two boolean slots that remember every write
+    #[derive(Debug)]
+    struct Relay {
+        writes: Writes,
+    }
+
+    impl Driver for Relay {
+        fn slots(&self) -> Vec<device::Slot> {
+            let slot = device::Slot {
+                can_input: true,
+                can_output: true,
+                unit: Unit::Boolean,
+                direction: None,
+            };
+            vec![slot, slot]
+        }
+
+        fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn read_sensor(&self, _index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+            Box::pin(async { Ok(Dimensioned::from_volts(0.0)) })
+        }
+
+        fn read_boolean(&self, _index: i32) -> BoxFuture<'_, Result<bool>> {
+            Box::pin(async { Ok(false) })
+        }
+
+        fn write_boolean(&mut self, index: i32, value: bool) -> BoxFuture<'_, Result<()>> {
+            self.writes.lock().unwrap().push((index, value));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Register a `Relay` driver under `name`, returning the writes made through it
+    fn relay(name: &str) -> (device::Type, Writes) {
+        let writes = Writes::default();
+        let recorded = writes.clone();
+        driver::register(
+            name,
+            Arc::new(move |_, _| {
+                Ok(Box::new(Relay {
+                    writes: recorded.clone(),
+                }))
+            }),
+        );
+        let model = device::Type::Custom(device::Custom {
+            driver: name.to_string(),
+            config: String::new(),
+        });
+        (model, writes)
+    }
+
+    fn db(name: &str) -> db::Db {
+        let path =
+            std::env::temp_dir().join(format!("restedpi-state-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        db::Db::start_db(&path).unwrap()
+    }
+
+    async fn state(db: db::Db) -> (State, mpsc::Receiver<AppMessage>) {
+        let (sender, receiver) = mpsc::channel(10);
+        let state = new_state(
+            1,
+            (0.0, 0.0),
+            Duration::ZERO,
+            RetryPolicy::default(),
+            db,
+            sender,
+            broadcast::channel(8).0,
+        )
+        .await
+        .unwrap();
+        (state, receiver)
+    }
+
+    #[tokio::test]
+    async fn disabling_puts_outputs_in_their_safe_states() {
+        let (model, writes) = relay("RelayForDisable");
+        let (mut state, _receiver) = state(db("disable")).await;
+        let device_id = state
+            .add_device(model, "relay".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let mut config =
+            models::NewOutput::new("pump".to_string(), device_id.clone(), 1, false, None);
+        config.safe_state = Some(false);
+        let output_id = state.add_output(&config).await.unwrap();
+        state.write_output_bool(&output_id, true).await.unwrap();
+        writes.lock().unwrap().clear();
+
+        state.set_device_disabled(&device_id, true).await.unwrap();
+        assert_eq!(*writes.lock().unwrap(), vec![(1, false)]);
+        assert!(state.db.device(&device_id).unwrap().disabled);
+        assert!(!state.devices.contains_key(&device_id));
+        assert_eq!(
+            state.read_output_bool(&output_id).await,
+            Err(Error::DeviceDisabled(device_id.clone()))
+        );
+
+        // disabling again has no device left to write to
+        writes.lock().unwrap().clear();
+        state.set_device_disabled(&device_id, true).await.unwrap();
+        assert!(writes.lock().unwrap().is_empty());
+
+        state.set_device_disabled(&device_id, false).await.unwrap();
+        assert!(!state.db.device(&device_id).unwrap().disabled);
+        assert_eq!(state.read_output_bool(&output_id).await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn disabled_devices_are_not_started() {
+        let (model, _writes) = relay("RelayForStartup");
+        let db = db("startup");
+        let device_id = {
+            let (mut state, _receiver) = state(db.clone()).await;
+            state
+                .add_device(model, "relay".to_string(), String::new(), Some(true))
+                .await
+                .unwrap()
+        };
+        let input_id = {
+            let (mut state, _receiver) = state(db.clone()).await;
+            state
+                .add_input(&models::NewInput::new(
+                    "door".to_string(),
+                    device_id.clone(),
+                    0,
+                ))
+                .await
+                .unwrap()
+        };
+
+        let (mut state, _receiver) = state(db).await;
+        assert!(!state.devices.contains_key(&device_id));
+        assert_eq!(
+            state.read_input_bool(&input_id).await,
+            Err(Error::DeviceDisabled(device_id.clone()))
+        );
+
+        state.set_device_disabled(&device_id, false).await.unwrap();
+        assert!(state.devices.contains_key(&device_id));
+        assert_eq!(state.read_input_bool(&input_id).await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn renamed_devices_report_changes_under_their_new_name() {
+        let (mut state, mut receiver) = state(db("rename-device")).await;
+        let model = device::Type::MCP23017(device::MCP23017 {
+            address: 0x20,
+            bank_a: device::Directions::new(),
+            bank_b: device::Directions::new(),
+            interrupt_pin: Some(17),
+        });
+        let device_id = state
+            .add_device(model, "expander".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let porch = "porch".to_string();
+        assert_eq!(
+            state.rename_device(&device_id, &porch).await,
+            Ok(porch.clone())
+        );
+        assert!(state.devices.contains_key(&porch));
+
+        // INTFA/INTCAPA: pin 4 went high
+        state.i2c.set_i2c_register(0x20, 0x0E, vec![0b10000]).await;
+        state.i2c.set_i2c_register(0x20, 0x10, vec![0b10000]).await;
+        state.i2c.fire_gpio_interrupt(17).await;
+        let message = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            message,
+            Some(AppMessage::PinChanged { device_id, .. }) if device_id == porch
+        ));
+    }
+
+    #[tokio::test]
+    async fn renames_need_names_expressions_can_use() {
+        let (model, _writes) = relay("RelayForRename");
+        let (mut state, _receiver) = state(db("rename-checks")).await;
+        let device_id = state
+            .add_device(model, "relay".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let input_id = state
+            .add_input(&models::NewInput::new(
+                "door".to_string(),
+                device_id.clone(),
+                0,
+            ))
+            .await
+            .unwrap();
+        let output_id = state
+            .add_output(&models::NewOutput::new(
+                "pump".to_string(),
+                device_id.clone(),
+                1,
+                false,
+                None,
+            ))
+            .await
+            .unwrap();
+
+        let spaced = "front door".to_string();
+        assert!(matches!(
+            state.rename_device(&device_id, &spaced).await,
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            state.rename_input(&input_id, &spaced).await,
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            state.rename_output(&output_id, &"read".to_string()).await,
+            Err(Error::Config(_))
+        ));
+
+        assert_eq!(
+            state.rename_device(&device_id, &device_id).await,
+            Ok(device_id.clone())
+        );
+        assert_eq!(
+            state.rename_input(&input_id, &input_id).await,
+            Ok(input_id.clone())
+        );
+        assert_eq!(
+            state.rename_output(&output_id, &output_id).await,
+            Ok(output_id.clone())
+        );
+        assert!(state.devices.contains_key(&device_id));
+    }
+
+    /// Never gets past its reset
+    #[derive(Debug)]
+    struct Broken;
+
+    impl Driver for Broken {
+        fn slots(&self) -> Vec<device::Slot> {
+            vec![driver::input_slot(Unit::Volts)]
+        }
+
+        fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { Err(Error::DeviceReadError("no answer".to_string())) })
+        }
+
+        fn read_sensor(&self, _index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+            Box::pin(async { Err(Error::DeviceReadError("no answer".to_string())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn starts_when_a_device_fails_to_reset() {
+        driver::register("BrokenForStartup", Arc::new(|_, _| Ok(Box::new(Broken))));
+        let (relay, _writes) = relay("RelayForBrokenStartup");
+        let broken = device::Type::Custom(device::Custom {
+            driver: "BrokenForStartup".to_string(),
+            config: String::new(),
+        });
+        let db = db("broken-startup");
+        let (broken_id, relay_id) = {
+            let (mut state, _receiver) = state(db.clone()).await;
+            let broken_id = state
+                .add_device(broken, "broken".to_string(), String::new(), Some(true))
+                .await
+                .unwrap();
+            let relay_id = state
+                .add_device(relay, "relay".to_string(), String::new(), None)
+                .await
+                .unwrap();
+            (broken_id, relay_id)
+        };
+        db.set_device_disabled(&broken_id, false).unwrap();
+
+        let (mut state, _receiver) = state(db).await;
+        assert!(state.devices.contains_key(&relay_id));
+        assert!(!state.devices.contains_key(&broken_id));
+        let health = state.device_health(&broken_id).unwrap();
+        assert_eq!(health.consecutive_failures, 1);
+        assert!(health.last_error.unwrap().contains("no answer"));
+        assert!(matches!(
+            state.reset_device(&broken_id).await,
+            Err(Error::DeviceReadError(_))
+        ));
+
+        // enabling it again is another try
+        assert!(state.set_device_disabled(&broken_id, false).await.is_err());
+        state.set_device_disabled(&broken_id, true).await.unwrap();
+        assert!(state.device_health(&broken_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn imports_finish_when_a_device_fails_to_start() {
+        use crate::app::site::{SiteDevice, SiteOutput};
+
+        let (relay, writes) = relay("RelayForImport");
+        driver::register("BrokenForImport", Arc::new(|_, _| Ok(Box::new(Broken))));
+        let broken = device::Type::Custom(device::Custom {
+            driver: "BrokenForImport".to_string(),
+            config: String::new(),
+        });
+        let site_device = |name: &str, model| SiteDevice {
+            name: name.to_string(),
+            display_name: String::new(),
+            model,
+            notes: String::new(),
+            disabled: false,
+        };
+        let site = Site {
+            devices: vec![site_device("broken", broken), site_device("relay", relay)],
+            outputs: vec![SiteOutput {
+                name: "pump".to_string(),
+                display_name: String::new(),
+                device_id: "relay".to_string(),
+                device_output_id: 1,
+                active_low: false,
+                automation_script: Some("true".to_string()),
+                min_on: None,
+                min_off: None,
+                max_switches_per_hour: None,
+                safe_state: Some(true),
+                error_policy: None,
+                kind: None,
+            }],
+            ..Site::default()
+        };
+
+        let (mut state, _receiver) = state(db("import")).await;
+        state.import_site(site).await.unwrap();
+        assert!(!state.devices.contains_key("broken"));
+        assert!(state.device_health(&"broken".to_string()).is_ok());
+        assert!(state.devices.contains_key("relay"));
+        assert_eq!(*writes.lock().unwrap(), vec![(1, true)]);
+        assert!(state.output_automation_cache.contains_key("true"));
+    }
+
+    #[tokio::test]
+    async fn interlock_violations_are_recorded_once() {
+        let (model, _writes) = relay("RelayForInterlocks");
+        let (mut state, _receiver) = state(db("violations")).await;
+        let device_id = state
+            .add_device(model, "relay".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let mut outputs = vec![];
+        for (slot, name) in ["heater", "heat_pump"].iter().enumerate() {
+            let config = models::NewOutput::new(
+                name.to_string(),
+                device_id.clone(),
+                slot as i32,
+                false,
+                None,
+            );
+            outputs.push(state.add_output(&config).await.unwrap());
+        }
+        let rule = Rule::AtMostOne(interlock::AtMostOne {
+            outputs: outputs.clone(),
+        });
+        state
+            .add_interlock("one_heat_source".to_string(), rule)
+            .await
+            .unwrap();
+        let violations = |state: &State| {
+            state
+                .audit_log(10, None)
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.data.mutation == audit::INTERLOCK_VIOLATION)
+                .count()
+        };
+
+        state.write_output_bool(&outputs[0], true).await.unwrap();
+        for _ in 0..3 {
+            assert!(matches!(
+                state.write_output_bool(&outputs[1], true).await,
+                Err(Error::InterlockViolation(_))
+            ));
+        }
+        assert_eq!(violations(&state), 1);
+        let entry = state.audit_log(1, None).unwrap().remove(0).data;
+        assert_eq!(entry.user, audit::SYSTEM_USER);
+        assert!(entry.arguments.contains("heat_pump"));
+        assert!(!entry.succeeded);
+
+        state.write_output_bool(&outputs[0], false).await.unwrap();
+        state.write_output_bool(&outputs[1], true).await.unwrap();
+        assert!(state.write_output_bool(&outputs[0], true).await.is_err());
+        assert_eq!(violations(&state), 2);
+    }
+
+    #[tokio::test]
+    async fn interlocked_outputs_are_not_removed() {
+        let (model, _writes) = relay("RelayForRemoval");
+        let (mut state, _receiver) = state(db("interlocked-removal")).await;
+        let device_id = state
+            .add_device(model, "relay".to_string(), String::new(), None)
+            .await
+            .unwrap();
+        let mut outputs = vec![];
+        for (slot, name) in ["heater", "heat_pump"].iter().enumerate() {
+            let config = models::NewOutput::new(
+                name.to_string(),
+                device_id.clone(),
+                slot as i32,
+                false,
+                None,
+            );
+            outputs.push(state.add_output(&config).await.unwrap());
+        }
+        let rule = Rule::AtMostOne(interlock::AtMostOne {
+            outputs: outputs.clone(),
+        });
+        let interlock_id = state
+            .add_interlock("one_heat_source".to_string(), rule)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state.remove_output(&outputs[0]).await,
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            state.remove_device(&device_id).await,
+            Err(Error::Config(_))
+        ));
+        assert!(state.db.output(&outputs[0]).is_ok());
+        assert!(state.devices.contains_key(&device_id));
+
+        state.remove_interlock(&interlock_id).await.unwrap();
+        state.remove_output(&outputs[0]).await.unwrap();
+        state.remove_device(&device_id).await.unwrap();
+    }
+}
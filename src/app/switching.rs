@@ -0,0 +1,190 @@
+use crate::app::db::models;
+use crate::error::{Error, Result};
+use crate::session::AppContext;
+use chrono::Duration;
+use chrono::prelude::*;
+use juniper::graphql_object;
+use std::collections::VecDeque;
+
+/// Limits on how often an output may change, protecting compressors and pumps from short cycling
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Limits {
+    /// Once on, stay on for at least this long
+    pub min_on: Option<Duration>,
+
+    /// Once off, stay off for at least this long
+    pub min_off: Option<Duration>,
+
+    /// Never change more often than this in any hour
+    pub max_switches_per_hour: Option<usize>,
+}
+
+impl From<&models::Output> for Limits {
+    fn from(output: &models::Output) -> Self {
+        Limits {
+            min_on: output.min_on.map(|s| Duration::seconds(s.max(0) as i64)),
+            min_off: output.min_off.map(|s| Duration::seconds(s.max(0) as i64)),
+            max_switches_per_hour: output.max_switches_per_hour.map(|n| n.max(0) as usize),
+        }
+    }
+}
+
+/// Fail unless `max_switches_per_hour` lets the output change at all; leave it unset for no limit
+pub fn check_max_switches(max_switches_per_hour: Option<i32>) -> Result<()> {
+    match max_switches_per_hour {
+        Some(n) if n < 1 => Err(Error::Config(format!(
+            "max_switches_per_hour must be at least 1, not {}; leave it unset for no limit",
+            n
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A change to an output that has been requested, but is held back by its limits
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Pending {
+    pub value: bool,
+    pub not_before: DateTime<Local>,
+}
+
+#[graphql_object(context = AppContext)]
+impl Pending {
+    /// The value the output will be set to
+    pub fn value(&self) -> bool {
+        self.value
+    }
+
+    /// Earliest time the change will be made
+    pub fn not_before(&self) -> String {
+        self.not_before.to_rfc3339()
+    }
+}
+
+/// What we remember about recent transitions of an output
+#[derive(Clone, PartialEq, Debug)]
+pub struct History {
+    /// Last logical value written to the output
+    pub on: bool,
+
+    /// When the output last changed to `on`
+    pub changed_at: DateTime<Local>,
+
+    /// When the output changed within the last hour, oldest first
+    switches: VecDeque<DateTime<Local>>,
+
+    /// A deferred change waiting for the limits to allow it
+    pub pending: Option<Pending>,
+}
+
+impl History {
+    pub fn new(on: bool, changed_at: DateTime<Local>) -> Self {
+        Self {
+            on,
+            changed_at,
+            switches: VecDeque::new(),
+            pending: None,
+        }
+    }
+
+    /// Record a write of `on`, returning true if it changed the output
+    pub fn record(&mut self, on: bool, at: DateTime<Local>) -> bool {
+        self.pending = None;
+        if self.on != on {
+            self.on = on;
+            self.changed_at = at;
+            self.switches.push_back(at);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Earliest time the output may change, if `limits` don't allow it to change at `now`
+    pub fn earliest_change(
+        &mut self,
+        limits: &Limits,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        let hour = Duration::hours(1);
+        while self.switches.front().is_some_and(|t| *t + hour <= now) {
+            self.switches.pop_front();
+        }
+
+        let min_duration = if self.on {
+            limits.min_on
+        } else {
+            limits.min_off
+        };
+        let mut earliest = min_duration.map(|d| self.changed_at + d);
+
+        if let Some(max) = limits.max_switches_per_hour
+            && self.switches.len() >= max
+        {
+            // once the oldest counted switch is an hour old there is room for one more
+            let index = self.switches.len() - max;
+            let freed = self.switches.get(index).map(|t| *t + hour).unwrap_or(now);
+            earliest = Some(earliest.map_or(freed, |e| e.max(freed)));
+        }
+
+        earliest.filter(|e| *e > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_on_and_off() {
+        let start = Local::now();
+        let limits = Limits {
+            min_on: Some(Duration::seconds(60)),
+            min_off: Some(Duration::seconds(120)),
+            max_switches_per_hour: None,
+        };
+        let mut history = History::new(false, start);
+        assert_eq!(
+            history.earliest_change(&limits, start + Duration::seconds(10)),
+            Some(start + Duration::seconds(120))
+        );
+        assert_eq!(
+            history.earliest_change(&limits, start + Duration::seconds(120)),
+            None
+        );
+
+        let on_at = start + Duration::seconds(200);
+        assert!(history.record(true, on_at));
+        assert_eq!(
+            history.earliest_change(&limits, on_at),
+            Some(on_at + Duration::seconds(60))
+        );
+    }
+
+    #[test]
+    fn max_switches_per_hour() {
+        let start = Local::now();
+        let limits = Limits {
+            max_switches_per_hour: Some(2),
+            ..Limits::default()
+        };
+        let mut history = History::new(false, start);
+        history.record(true, start);
+        history.record(false, start + Duration::minutes(10));
+        assert_eq!(
+            history.earliest_change(&limits, start + Duration::minutes(20)),
+            Some(start + Duration::minutes(60))
+        );
+        assert_eq!(
+            history.earliest_change(&limits, start + Duration::minutes(60)),
+            None
+        );
+    }
+
+    #[test]
+    fn zero_switches_per_hour_is_rejected() {
+        assert!(check_max_switches(Some(0)).is_err());
+        assert!(check_max_switches(Some(-1)).is_err());
+        assert!(check_max_switches(Some(1)).is_ok());
+        assert!(check_max_switches(None).is_ok());
+    }
+}
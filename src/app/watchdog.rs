@@ -0,0 +1,107 @@
+use crate::app::health::Health;
+use crate::error::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// A kernel watchdog device such as `/dev/watchdog`.
+///
+/// Once opened, the machine is reset unless it is written to regularly. We only feed it while the
+/// app loop is healthy, so a wedged loop ends in a reboot into a known state.
+pub struct Watchdog {
+    file: File,
+}
+
+impl Watchdog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Feed the watchdog if the app loop is live, returning whether it was fed
+    pub fn feed_if_healthy(&mut self, health: &Health) -> Result<bool> {
+        if health.status().live {
+            self.file.write_all(b"\0")?;
+            self.file.flush()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Disarm the watchdog with the magic close character, for a deliberate shutdown
+    pub fn disarm(mut self) -> Result<()> {
+        self.file.write_all(b"V")?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// A running watchdog feeder, disarmed on a deliberate shutdown
+pub struct WatchdogHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl WatchdogHandle {
+    /// Stop feeding the watchdog, and disarm it so the machine isn't reset
+    pub async fn disarm(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            error!("watchdog task failed: {}", e);
+        }
+    }
+}
+
+/// Feed the watchdog at `path` every `interval` for as long as the app loop stays healthy
+pub fn start(path: &Path, interval: Duration, health: Arc<Health>) -> Result<WatchdogHandle> {
+    let mut watchdog = Watchdog::open(path)?;
+    info!("Feeding watchdog {:?} every {:?}", path, interval);
+    let (stop, mut stopped) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => match watchdog.feed_if_healthy(&health) {
+                    Ok(true) => (),
+                    Ok(false) => warn!("app loop is not healthy, not feeding the watchdog"),
+                    Err(e) => error!("failed to feed the watchdog: {}", e),
+                },
+                _ = &mut stopped => break,
+            }
+        }
+        info!("Disarming watchdog");
+        if let Err(e) = watchdog.disarm() {
+            error!("failed to disarm the watchdog: {}", e);
+        }
+    });
+    Ok(WatchdogHandle { stop, task })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feeds_only_while_healthy() {
+        let path = std::env::temp_dir().join(format!("restedpi-watchdog-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let health = Health::new();
+        let mut watchdog = Watchdog::open(&path).unwrap();
+
+        assert!(!watchdog.feed_if_healthy(&health).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+
+        health.started();
+        assert!(watchdog.feed_if_healthy(&health).unwrap());
+        watchdog.disarm().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0V");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
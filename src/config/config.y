@@ -128,6 +128,11 @@ Unit -> Result<Unit, ()>:
     'degC' { Ok(Unit::DegC) }
   | 'bool' { Ok(Unit::Boolean) }
   | 'kpa' { Ok(Unit::KPa) }
+  | 'volts' { Ok(Unit::Volts) }
+  | 'amps' { Ok(Unit::Amps) }
+  | 'watts' { Ok(Unit::Watts) }
+  | 'ratio' { Ok(Unit::Ratio) }
+  | 'rh' { Ok(Unit::RelHumidity) }
   ;
 
 DegNS -> Result<f64, ()>:
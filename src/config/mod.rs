@@ -48,6 +48,37 @@ pub struct Config {
 
     // Map from username to hashed passwords
     pub users: Option<HashMap<String, String>>,
+
+    // Users who may read and prune the audit log, nobody if unset
+    pub admins: Option<Vec<String>>,
+
+    // How long an input reading may be reused before reading the device again, defaults to 1000
+    pub reading_max_age_ms: Option<u64>,
+
+    // How many times a device read is tried before giving up, defaults to 3. Lowered if the waits
+    // between tries would take more than half the 2s device timeout.
+    pub device_read_attempts: Option<u32>,
+
+    // Milliseconds to wait before retrying a device read, doubling each retry, defaults to 10
+    pub device_retry_backoff_ms: Option<u64>,
+
+    // Reset a device after this many failures in a row, 0 to never, defaults to 5
+    pub device_reset_after_failures: Option<u32>,
+
+    // Hardware watchdog device (e.g. /dev/watchdog), fed only while the app loop is healthy
+    pub watchdog_device: Option<PathBuf>,
+
+    // Seconds between feeding the watchdog, defaults to 5
+    pub watchdog_interval_secs: Option<u64>,
+
+    // Directory to back the database up into while the server runs, no backups if unset
+    pub backup_dir: Option<PathBuf>,
+
+    // Seconds between backups, defaults to a day
+    pub backup_interval_secs: Option<u64>,
+
+    // Backups to keep in backup_dir before deleting the oldest, defaults to 7
+    pub backups_kept: Option<usize>,
 }
 
 impl Default for Config {
@@ -64,6 +95,16 @@ impl Default for Config {
             tls_key_path: None,
             tls_cert_path: None,
             users: None,
+            admins: None,
+            reading_max_age_ms: None,
+            device_read_attempts: None,
+            device_retry_backoff_ms: None,
+            device_reset_after_failures: None,
+            watchdog_device: None,
+            watchdog_interval_secs: None,
+            backup_dir: None,
+            backup_interval_secs: None,
+            backups_kept: None,
         }
     }
 }
@@ -86,6 +127,8 @@ impl Config {
     /// - `RESTEDPI_APP_SECRET_PATH=/etc/restedpi/secret`
     /// - `RESTEDPI_TLS_KEY_PATH=/etc/restedpi/key.pem`
     /// - `RESTEDPI_TLS_CERT_PATH=/etc/restedpi/cert.pem`
+    /// - `RESTEDPI_WATCHDOG_DEVICE=/dev/watchdog`
+    /// - `RESTEDPI_BACKUP_DIR=/var/backups/restedpi`
     pub fn load(config_file: Option<&Path>) -> Result<Self, Box<figment::Error>> {
         let mut figment = Figment::from(Serialized::defaults(Config::default()));
 
@@ -143,4 +186,82 @@ pub enum ConfigError {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::parse;
+    use super::types::{BoolExpr, Unit, Value};
+
+    #[test]
+    fn parses_volts_readings() {
+        let expr = parse::bool_expr("read(moisture, volts) < 1.2").unwrap();
+        match expr {
+            BoolExpr::LessThan(_, Value::ReadInput(name, Unit::Volts), Value::Const(c)) => {
+                assert_eq!(name, "moisture");
+                assert_eq!(c, 1.2);
+            }
+            other => panic!("unexpected parse: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_level_value_expressions() {
+        let value = parse::value_expr("read(light, ratio)").unwrap();
+        match value {
+            Value::ReadInput(name, Unit::Ratio) => assert_eq!(name, "light"),
+            other => panic!("unexpected parse: {:?}", other),
+        }
+        assert!(parse::value_expr("read(light, ratio) > 1").is_err());
+    }
+
+    #[test]
+    fn parses_humidity_readings() {
+        let expr = parse::bool_expr("read(greenhouse, rh) > 85").unwrap();
+        match expr {
+            BoolExpr::MoreThan(_, Value::ReadInput(name, Unit::RelHumidity), Value::Const(c)) => {
+                assert_eq!(name, "greenhouse");
+                assert_eq!(c, 85.0);
+            }
+            other => panic!("unexpected parse: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_power_readings() {
+        for (source, unit) in [
+            ("read(battery, volts) < 11.8", Unit::Volts),
+            ("read(load, amps) < 11.8", Unit::Amps),
+            ("read(load, watts) < 11.8", Unit::Watts),
+        ] {
+            match parse::bool_expr(source).unwrap() {
+                BoolExpr::LessThan(_, Value::ReadInput(_, read_unit), _) => {
+                    assert_eq!(read_unit, unit)
+                }
+                other => panic!("unexpected parse: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn renames_identifier_tokens_only() {
+        let script = "read(attic, degC) > 30 and attic_fan or not attic";
+        assert_eq!(
+            parse::rename_identifier(script, "attic", "loft").unwrap(),
+            "read(loft, degC) > 30 and attic_fan or not loft"
+        );
+        assert_eq!(
+            parse::rename_identifier("'attic' or attic2", "attic", "loft").unwrap(),
+            "'attic' or attic2"
+        );
+        assert!(parse::rename_identifier(script, "attic", "the loft").is_err());
+        assert!(parse::rename_identifier("cellar", "attic", "the loft").is_ok());
+    }
+
+    #[test]
+    fn recognises_identifiers() {
+        assert!(parse::is_identifier("attic_fan"));
+        assert!(parse::is_identifier("'attic fan'"));
+        assert!(!parse::is_identifier("attic fan"));
+        assert!(!parse::is_identifier("read"));
+        assert!(!parse::is_identifier("2nd"));
+        assert!(!parse::is_identifier(""));
+    }
+}
@@ -1,8 +1,8 @@
-use crate::config::types::BoolExpr;
+use crate::config::types::{BoolExpr, Value};
 use crate::error::Error;
 use crate::error::Result;
 use lrlex::lrlex_mod;
-use lrpar::lrpar_mod;
+use lrpar::{Lexeme, Lexer, NonStreamingLexer, lrpar_mod};
 use tracing::{Level, instrument, span, trace, warn};
 
 lrlex_mod!("config/config.l");
@@ -28,3 +28,51 @@ pub fn bool_expr(as_str: &str) -> Result<BoolExpr> {
         _ => Err(Error::ParseError),
     }
 }
+
+/// Parse a value expression, by parsing it as the left side of a comparison
+pub fn value_expr(as_str: &str) -> Result<Value> {
+    match bool_expr(&format!("{} == 0", as_str))? {
+        BoolExpr::Equal(_, value, Value::Const(_)) => Ok(value),
+        _ => Err(Error::ParseError),
+    }
+}
+
+/// Does `name` lex as a single identifier, so that expressions can refer to it as it is
+pub fn is_identifier(name: &str) -> bool {
+    let lexerdef = config_l::lexerdef();
+    let lexer = lexerdef.lexer(name);
+    let lexemes: Vec<_> = lexer.iter().collect();
+    match lexemes.as_slice() {
+        [Ok(lexeme)] => {
+            lexeme.tok_id() == config_l::T_IDENTIFIER && lexer.span_str(lexeme.span()) == name
+        }
+        _ => false,
+    }
+}
+
+/// `script` with every identifier token naming `from` replaced by `to`, leaving everything else
+/// (including keywords and quoted text that merely contains `from`) untouched. Fails if `from`
+/// is used but `to` couldn't be.
+pub fn rename_identifier(script: &str, from: &str, to: &str) -> Result<String> {
+    let lexerdef = config_l::lexerdef();
+    let lexer = lexerdef.lexer(script);
+    let mut renamed = String::with_capacity(script.len());
+    let mut copied_to = 0;
+    for lexeme in lexer.iter() {
+        let lexeme = lexeme.map_err(|_| Error::ParseError)?;
+        let span = lexeme.span();
+        if lexeme.tok_id() == config_l::T_IDENTIFIER && lexer.span_str(span) == from {
+            if !is_identifier(to) {
+                return Err(Error::Config(format!(
+                    "'{}' can't be used in expressions as a name",
+                    to
+                )));
+            }
+            renamed.push_str(&script[copied_to..span.start()]);
+            renamed.push_str(to);
+            copied_to = span.end();
+        }
+    }
+    renamed.push_str(&script[copied_to..]);
+    Ok(renamed)
+}
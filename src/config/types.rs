@@ -8,6 +8,11 @@ pub enum Unit {
     Boolean,
     DegC,
     KPa,
+    Volts,
+    Amps,
+    Watts,
+    Ratio,
+    RelHumidity,
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -1,4 +1,7 @@
-use crate::app::dimensioned::{DimBool, DimDegC, DimKPa, DimMessage, Dimensioned};
+use crate::app::dimensioned::{
+    DimAmps, DimBool, DimDegC, DimKPa, DimMessage, DimRatio, DimRelHumidity, DimVolts, DimWatts,
+    Dimensioned,
+};
 use crate::app::state::State;
 use crate::config::sched;
 use crate::config::types::{DateTimeValue, LocationValue, Unit, Value};
@@ -57,6 +60,11 @@ impl FromStr for Unit {
         match s {
             "degc" => Ok(Unit::DegC),
             "kpa" => Ok(Unit::KPa),
+            "volts" => Ok(Unit::Volts),
+            "amps" => Ok(Unit::Amps),
+            "watts" => Ok(Unit::Watts),
+            "ratio" => Ok(Unit::Ratio),
+            "rh" => Ok(Unit::RelHumidity),
             _ => Err(ParseUnitError::NotKnown),
         }
     }
@@ -76,6 +84,41 @@ pub async fn evaluate(app: &State, expr: &Value) -> Result<f64> {
                     Err(Error::UnitError("Expected kPa".to_string()))
                 }
             }
+            Dimensioned::Volts(DimVolts { value }) => {
+                if *unit == Unit::Volts {
+                    Ok(value)
+                } else {
+                    Err(Error::UnitError("Expected Volts".to_string()))
+                }
+            }
+            Dimensioned::Amps(DimAmps { value }) => {
+                if *unit == Unit::Amps {
+                    Ok(value)
+                } else {
+                    Err(Error::UnitError("Expected Amps".to_string()))
+                }
+            }
+            Dimensioned::Watts(DimWatts { value }) => {
+                if *unit == Unit::Watts {
+                    Ok(value)
+                } else {
+                    Err(Error::UnitError("Expected Watts".to_string()))
+                }
+            }
+            Dimensioned::Ratio(DimRatio { value }) => {
+                if *unit == Unit::Ratio {
+                    Ok(value)
+                } else {
+                    Err(Error::UnitError("Expected Ratio".to_string()))
+                }
+            }
+            Dimensioned::RelHumidity(DimRelHumidity { value }) => {
+                if *unit == Unit::RelHumidity {
+                    Ok(value)
+                } else {
+                    Err(Error::UnitError("Expected %RH".to_string()))
+                }
+            }
             Dimensioned::DegC(DimDegC { value }) => {
                 if *unit == Unit::DegC {
                     Ok(value)
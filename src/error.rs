@@ -3,6 +3,8 @@ use juniper::graphql_value;
 use juniper::{FieldError, IntoFieldError};
 #[cfg(feature = "raspberrypi")]
 use rppal::i2c;
+#[cfg(feature = "raspberrypi")]
+use rppal::spi;
 use serde_derive::Serialize;
 use std::collections::HashMap;
 use std::error;
@@ -20,6 +22,7 @@ pub enum Error {
     IoError(String),
     InputNotFound(String),
     OutputNotFound(String),
+    InterlockViolation(String),
     DbError(String),
     InvalidPinDirection,
     ParseError,
@@ -27,7 +30,10 @@ pub enum Error {
     TokenIssue,
     PasswordIssue,
     NotLoggedIn,
+    NotAdmin,
     DeviceReadError(String),
+    DeviceTimeout(String),
+    DeviceDisabled(String),
     PbkError(String),
     NonExistant(String),
     NotUnique(String),
@@ -36,6 +42,8 @@ pub enum Error {
     TzError(String),
     #[cfg(feature = "raspberrypi")]
     I2cError(String),
+    #[cfg(feature = "raspberrypi")]
+    SpiError(String),
     RecvError(String),
     SendError(String),
     StorageError(String),
@@ -52,6 +60,12 @@ impl IntoFieldError for Error {
             Error::DeviceReadError(err) => {
                 FieldError::new(err, graphql_value!({"slug": "device-read"}))
             }
+            Error::DeviceTimeout(err) => {
+                FieldError::new(err, graphql_value!({"slug": "device-timeout"}))
+            }
+            Error::DeviceDisabled(name) => {
+                FieldError::new(name, graphql_value!({"slug": "device-disabled"}))
+            }
             Error::TzError(err) => FieldError::new(err, graphql_value!({"slug": "TZ"})),
             Error::NonExistant(name) => {
                 FieldError::new(name, graphql_value!({"slug": "Existance"}))
@@ -60,6 +74,8 @@ impl IntoFieldError for Error {
             Error::OutOfBounds(index) => FieldError::new(index, graphql_value!({"slug": "Bounds"})),
             #[cfg(feature = "raspberrypi")]
             Error::I2cError(err) => FieldError::new(err, graphql_value!({"slug": "I2C"})),
+            #[cfg(feature = "raspberrypi")]
+            Error::SpiError(err) => FieldError::new(err, graphql_value!({"slug": "SPI"})),
             Error::UnitError(err) => FieldError::new(err, graphql_value!({"slug": "Units"})),
             Error::RecvError(err) => FieldError::new(err, graphql_value!({"slug": "Recv"})),
             Error::SendError(err) => FieldError::new(err, graphql_value!({"slug": "Send"})),
@@ -67,6 +83,9 @@ impl IntoFieldError for Error {
             Error::EncodingError(err) => FieldError::new(err, graphql_value!({"slug": "Encoding"})),
             Error::InputNotFound(n) => FieldError::new(n, graphql_value!({"slug": "Input"})),
             Error::OutputNotFound(n) => FieldError::new(n, graphql_value!({"slug": "Output"})),
+            Error::InterlockViolation(n) => {
+                FieldError::new(n, graphql_value!({"slug": "Interlock"}))
+            }
             Error::InvalidPinDirection => FieldError::new(
                 "Direction incorrect",
                 graphql_value!({"slug": "InvalidPinDirection"}),
@@ -85,6 +104,7 @@ impl IntoFieldError for Error {
             Error::NotLoggedIn => {
                 FieldError::new("Not Logged In", graphql_value!({"slug": "Login"}))
             }
+            Error::NotAdmin => FieldError::new("Not an admin", graphql_value!({"slug": "Admin"})),
         }
     }
 }
@@ -102,11 +122,15 @@ impl fmt::Display for Error {
             Error::InvalidPinDirection => write!(f, "Invalid pin direction"),
             Error::ParseError => write!(f, "Parse error"),
             Error::DeviceReadError(err) => write!(f, "Failed to read device: {}", err),
+            Error::DeviceTimeout(err) => write!(f, "Device timed out: {}", err),
+            Error::DeviceDisabled(name) => write!(f, "Device '{}' is disabled", name),
             Error::NonExistant(name) => write!(f, "'{}' does not exist", name),
             Error::NotUnique(msg) => write!(f, "non-unique: {}", msg),
             Error::OutOfBounds(index) => write!(f, "Index '{:#?}' out of bounds", index),
             #[cfg(feature = "raspberrypi")]
             Error::I2cError(err) => write!(f, "I2C Bus Error: {}", err),
+            #[cfg(feature = "raspberrypi")]
+            Error::SpiError(err) => write!(f, "SPI Bus Error: {}", err),
             Error::UnitError(err) => write!(f, "Unit expected {:#?}", err),
             Error::RecvError(err) => write!(f, "Failed to read: {}", err),
             Error::SendError(err) => write!(f, "Failed to send: {}", err),
@@ -114,10 +138,12 @@ impl fmt::Display for Error {
             Error::EncodingError(err) => write!(f, "Encoding error: {}", err),
             Error::InputNotFound(n) => write!(f, "Input not found: {}", n),
             Error::OutputNotFound(n) => write!(f, "Output not found: {}", n),
+            Error::InterlockViolation(n) => write!(f, "Interlock violated: {}", n),
             Error::UserNotFound => write!(f, "User not found"),
             Error::TokenIssue => write!(f, "Issue with token"),
             Error::PasswordIssue => write!(f, "Password issue"),
             Error::NotLoggedIn => write!(f, "Not Logged In"),
+            Error::NotAdmin => write!(f, "Not an admin"),
         }
     }
 }
@@ -226,6 +252,13 @@ impl From<i2c::Error> for Error {
     }
 }
 
+#[cfg(feature = "raspberrypi")]
+impl From<spi::Error> for Error {
+    fn from(err: spi::Error) -> Error {
+        Error::SpiError(format!("spi error: {}", err))
+    }
+}
+
 impl From<r2d2::Error> for Error {
     fn from(err: r2d2::Error) -> Error {
         Error::DbError(format!("r2d2: {}", err))
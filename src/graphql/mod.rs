@@ -1,20 +1,31 @@
 use crate::app::AppID;
+use crate::app::audit;
 use crate::app::db::models;
 use crate::app::db::models::UpdateOutput;
 use crate::app::device;
-use crate::app::input::Input;
+use crate::app::input::{Input, InputChange};
+use crate::app::interlock;
 use crate::app::output::Output;
+use crate::app::site::{Site, SiteFormat};
 use crate::error::Error;
+use crate::rpi::i2c::scan::Detected;
+use crate::rpi::i2c::{ads1x15, ina219, pca9685};
+use crate::rpi::spi::mcp3008;
+use crate::rpi::w1;
 use crate::session::{AppContext, authenticate};
 use futures::Stream;
-use juniper::{FieldError, FieldResult, RootNode, graphql_object, graphql_subscription};
+use juniper::{
+    DefaultScalarValue, Executor, FieldError, FieldResult, LookAheadValue, RootNode,
+    graphql_object, graphql_subscription,
+};
+use std::future::Future;
 use std::pin::Pin;
 use std::time::Duration;
 
 #[cfg(feature = "raspberrypi")]
 use rppal::system::DeviceInfo;
 
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 pub struct Query;
 
@@ -80,19 +91,69 @@ impl Query {
         let devices = context.channel().all_devices().await?;
         Ok(devices)
     }
+
+    /// Probe the I2C bus, suggesting a device for each address that answers
+    pub async fn i2c_scan(context: &AppContext) -> FieldResult<Vec<Detected>> {
+        check_session(context)?;
+        Ok(context.channel().i2c_scan().await?)
+    }
+
+    /// ROM IDs of the DS18B20 probes on the 1-Wire bus, for `addDs18b20`
+    pub async fn w1_scan(context: &AppContext) -> FieldResult<Vec<String>> {
+        check_session(context)?;
+        Ok(context.channel().w1_scan().await?)
+    }
+
+    /// The whole configuration (devices, inputs, outputs and interlocks) as one document, for
+    /// `importConfig` on this or another Pi
+    pub async fn export_config(
+        context: &AppContext,
+        format: Option<SiteFormat>,
+    ) -> FieldResult<String> {
+        check_session(context)?;
+        let site = context.channel().export_site().await?;
+        Ok(site.render(format.unwrap_or(SiteFormat::Toml))?)
+    }
+
+    /// Mutations made through the API, newest first: `limit` of them (100 unless given), starting
+    /// before `before_id` to page back. Only admins can read it.
+    pub async fn audit_log(
+        context: &AppContext,
+        limit: Option<i32>,
+        before_id: Option<i32>,
+    ) -> FieldResult<Vec<audit::AuditEntry>> {
+        check_admin(context)?;
+        let limit = limit.unwrap_or(100).max(0).into();
+        Ok(context.channel().audit_log(limit, before_id).await?)
+    }
+
+    /// Retrieve all interlocks
+    pub async fn interlocks(context: &AppContext) -> FieldResult<Vec<interlock::Interlock>> {
+        let interlocks = context.channel().all_interlocks().await?;
+        Ok(interlocks)
+    }
 }
 
 pub struct Mutation;
 
-#[graphql_object(Context = AppContext)]
+#[graphql_object(Context = AppContext, scalar = DefaultScalarValue)]
 impl Mutation {
     /// Generate a new token that can be used to access protected endpoints
     pub async fn sign_in(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         email: String,
         plaintext_password: String,
     ) -> FieldResult<String> {
-        Ok(authenticate(context, &email, &plaintext_password).await?)
+        // anyone can fail to sign in, so only sign-ins are audited, or the audit log would grow
+        // with every anonymous attempt
+        match authenticate(context, &email, &plaintext_password).await {
+            Ok(token) => audit(executor, email, async { Ok(token) }).await,
+            Err(e) => {
+                warn!("failed sign in as '{}': {}", email, e);
+                Err(e.into())
+            }
+        }
     }
 
     /// Sign out from the system.
@@ -100,127 +161,591 @@ impl Mutation {
     /// Note: This API uses stateless JWT tokens, so server-side session invalidation
     /// is not possible. This endpoint verifies the session is valid but always returns
     /// false. Clients should discard their token to complete sign-out.
-    pub fn sign_out(context: &AppContext) -> FieldResult<bool> {
-        check_session(context)?;
-        // JWT tokens are stateless - client must discard token to sign out
-        Ok(false)
+    pub async fn sign_out(executor: &Executor<'_, '_, AppContext>) -> FieldResult<bool> {
+        audited(executor, async {
+            // JWT tokens are stateless - client must discard token to sign out
+            Ok(false)
+        })
+        .await
     }
 
     /// Add a new mcp9808 at a given address
     pub async fn add_mcp9808(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         address: i32,
         name: String,
         description: String,
         disabled: Option<bool>,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        let model = device::Type::MCP9808(device::MCP9808 { address });
-        Ok(context
-            .channel()
-            .add_device(model, name, description, disabled)
-            .await?)
+        audited(executor, async {
+            let model = device::Type::MCP9808(device::MCP9808 { address });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add a DS18B20 1-Wire temperature probe by its ROM ID, e.g. 28-0316a2791aff
+    pub async fn add_ds18b20(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        rom_id: String,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            w1::validate_rom_id(&rom_id)?;
+            let model = device::Type::DS18B20(device::DS18B20 { rom_id });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add an SHT31 temperature and humidity sensor, at 0x44 unless given
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_sht31(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        address: Option<i32>,
+        heater: Option<bool>,
+        clock_stretching: Option<bool>,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let model = device::Type::SHT31(device::SHT31 {
+                address: address.unwrap_or(0x44),
+                heater: heater.unwrap_or(false),
+                clock_stretching: clock_stretching.unwrap_or(false),
+            });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add an INA219 current and power monitor. Its shunt and the largest current expected
+    /// through it set the current resolution.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_ina219(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        address: i32,
+        shunt_ohms: f64,
+        max_current: f64,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let config = device::INA219 {
+                address,
+                shunt_ohms,
+                max_current,
+            };
+            ina219::calibration(&config)?;
+            let model = device::Type::INA219(config);
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add an MCP3008 ADC on SPI0, on chip select 0 or 1, with `vref` volts on its VREF pin
+    pub async fn add_mcp3008(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        chip_select: i32,
+        vref: f64,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let config = device::MCP3008 { chip_select, vref };
+            mcp3008::validate(&config)?;
+            let model = device::Type::MCP3008(config);
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add a device driven by an out-of-tree driver, given the name it was registered under and
+    /// its driver-specific configuration
+    pub async fn add_custom_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        driver: String,
+        config: String,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let model = device::Type::Custom(device::Custom { driver, config });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add a HTU21D temperature and humidity sensor
+    pub async fn add_htu21d(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        heater: Option<bool>,
+        clock_stretching: Option<bool>,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let model = device::Type::HTU21D(device::HTU21D {
+                address: 0x40,
+                heater: heater.unwrap_or(false),
+                clock_stretching: clock_stretching.unwrap_or(false),
+            });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
     }
 
     /// Add a new bmp085 at a given address
     pub async fn add_bmp085(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         address: i32,
         mode: device::SamplingMode,
         name: String,
         description: String,
         disabled: Option<bool>,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        let model = device::Type::BMP085(device::BMP085 { address, mode });
-        Ok(context
-            .channel()
-            .add_device(model, name, description, disabled)
-            .await?)
+        audited(executor, async {
+            let model = device::Type::BMP085(device::BMP085 { address, mode });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
     }
 
-    /// Add an MCP23017 device at the given address.
+    /// Add an ADS1015 or ADS1115 analog-to-digital converter at the given address. Unless
+    /// `channels` lists four channels, each slot reads its own input against ground.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_ads1x15(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        address: i32,
+        chip: device::AdcChip,
+        gain: device::AdcGain,
+        data_rate: i32,
+        channels: Option<Vec<device::AdcMux>>,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let channels = match channels.as_deref() {
+                None => device::AdcChannels::default(),
+                Some(&[c0, c1, c2, c3]) => device::AdcChannels { c0, c1, c2, c3 },
+                Some(_) => {
+                    return Err(Error::Config("an ADS1x15 has four channels".to_string()).into());
+                }
+            };
+            let config = device::ADS1x15 {
+                address,
+                chip,
+                gain,
+                data_rate,
+                channels,
+            };
+            ads1x15::validate(&config)?;
+            let model = device::Type::ADS1x15(config);
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add a PCA9685 16 channel PWM driver at the given address, running at `frequency` Hz
+    pub async fn add_pca9685(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        address: i32,
+        frequency: i32,
+        name: String,
+        description: String,
+        disabled: Option<bool>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            pca9685::prescale(frequency)?;
+            let model = device::Type::PCA9685(device::PCA9685 { address, frequency });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
+    }
+
+    /// Add an MCP23017 device at the given address. If `interrupt_pin` is the GPIO pin wired to
+    /// its INTA line, input changes are picked up as they happen.
+    #[allow(clippy::too_many_arguments)]
     pub async fn add_mcp23017(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         address: i32,
         name: String,
         description: String,
         bank_a: Option<device::InputDirections>,
         bank_b: Option<device::InputDirections>,
+        interrupt_pin: Option<i32>,
         disabled: Option<bool>,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        let model = device::Type::MCP23017(device::MCP23017 {
-            address,
-            bank_a: bank_a.map_or(device::Directions::new(), |x| x.into()),
-            bank_b: bank_b.map_or(device::Directions::new(), |x| x.into()),
-        });
-        Ok(context
-            .channel()
-            .add_device(model, name, description, disabled)
-            .await?)
+        audited(executor, async {
+            let model = device::Type::MCP23017(device::MCP23017 {
+                address,
+                bank_a: bank_a.map_or(device::Directions::new(), |x| x.into()),
+                bank_b: bank_b.map_or(device::Directions::new(), |x| x.into()),
+                interrupt_pin,
+            });
+            Ok(context
+                .channel()
+                .add_device(model, name, description, disabled)
+                .await?)
+        })
+        .await
     }
 
-    /// Remove the specified device and any inputs or outputs that use it
-    pub async fn remove_device(context: &AppContext, device_id: AppID) -> FieldResult<bool> {
-        check_session(context)?;
-        context.channel().remove_device(device_id).await?;
-        Ok(true)
+    /// Start using a disabled device, resetting it first
+    pub async fn enable_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        device_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context
+                .channel()
+                .set_device_disabled(device_id, false)
+                .await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Stop using a device, putting its outputs in their safe states. Its inputs and outputs are
+    /// kept, but read as unavailable until it is enabled again.
+    pub async fn disable_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        device_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context
+                .channel()
+                .set_device_disabled(device_id, true)
+                .await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Change a device's notes, I2C address or MCP23017 pin directions, keeping its inputs and
+    /// outputs. A running device is reset with its new settings.
+    pub async fn update_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        device_id: AppID,
+        fields: models::UpdateDevice,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            info!("Updating device {} with {:?}", device_id, fields);
+            Ok(context.channel().update_device(device_id, fields).await?)
+        })
+        .await
+    }
+
+    /// Give a device a new name, keeping its inputs and outputs. Fails if the name is taken.
+    pub async fn rename_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        device_id: AppID,
+        new_name: AppID,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            Ok(context.channel().rename_device(device_id, new_name).await?)
+        })
+        .await
+    }
+
+    /// Replace the whole configuration with a document from `exportConfig`. Nothing changes if
+    /// any of it is invalid; otherwise every device is restarted.
+    pub async fn import_config(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        config: String,
+        format: Option<SiteFormat>,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            let site = Site::parse(&config, format.unwrap_or(SiteFormat::Toml))?;
+            context.channel().import_site(site).await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Remove the specified device and any inputs or outputs that use it, unless an interlock
+    /// refers to one of its outputs
+    pub async fn remove_device(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        device_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context.channel().remove_device(device_id).await?;
+            Ok(true)
+        })
+        .await
     }
 
     /// Remove the specified input
-    pub async fn remove_input(context: &AppContext, input_id: AppID) -> FieldResult<bool> {
-        check_session(context)?;
-        context.channel().remove_input(input_id).await?;
-        Ok(true)
+    pub async fn remove_input(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        input_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context.channel().remove_input(input_id).await?;
+            Ok(true)
+        })
+        .await
     }
 
-    /// Remove the specified output
-    pub async fn remove_output(context: &AppContext, output_id: AppID) -> FieldResult<bool> {
-        check_session(context)?;
-        context.channel().remove_output(output_id).await?;
-        Ok(true)
+    /// Remove the specified output, unless an interlock refers to it
+    pub async fn remove_output(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        output_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context.channel().remove_output(output_id).await?;
+            Ok(true)
+        })
+        .await
     }
 
     /// Add an input to a device. This input is a way to read data from a device
     pub async fn add_input(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         new_input: models::NewInput,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        Ok(context.channel().add_input(new_input).await?)
+        audited(executor, async {
+            Ok(context.channel().add_input(new_input).await?)
+        })
+        .await
+    }
+
+    /// Move an input to another slot or device, or change its calibration
+    pub async fn update_input(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        input_id: AppID,
+        fields: models::UpdateInput,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            info!("Updating input {} with {:?}", input_id, fields);
+            Ok(context.channel().update_input(input_id, fields).await?)
+        })
+        .await
+    }
+
+    /// Give an input a new name, rewriting the automation scripts that read it. Fails if the name
+    /// is taken, or is used by a script but can't be written in one.
+    pub async fn rename_input(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        input_id: AppID,
+        new_name: AppID,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            Ok(context.channel().rename_input(input_id, new_name).await?)
+        })
+        .await
+    }
+
+    /// Adjust an input's offset so that it currently reads `reference_value`, e.g. the reading of
+    /// a trusted thermometer next to the sensor. Returns the new offset.
+    pub async fn calibrate_input(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        input_id: AppID,
+        reference_value: f64,
+    ) -> FieldResult<f64> {
+        audited(executor, async {
+            Ok(context
+                .channel()
+                .calibrate_input(input_id, reference_value)
+                .await?)
+        })
+        .await
     }
 
     /// Set the output to a given boolean value
     pub async fn set_output(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         output_id: AppID,
         value: bool,
     ) -> FieldResult<bool> {
-        check_session(context)?;
-        context.channel().write_boolean(output_id, value).await?;
-        Ok(true)
+        audited(executor, async {
+            context.channel().write_boolean(output_id, value).await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Set a level output to a duty cycle between 0 and 1
+    pub async fn set_output_level(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        output_id: AppID,
+        level: f64,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context.channel().write_level(output_id, level).await?;
+            Ok(true)
+        })
+        .await
     }
 
     pub async fn update_output(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         output_id: AppID,
         fields: UpdateOutput,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        info!("Updating output {} with {:?}", output_id, fields);
-        let outp = context.channel().update_output(output_id, fields).await?;
-        Ok(outp)
+        audited(executor, async {
+            info!("Updating output {} with {:?}", output_id, fields);
+            let outp = context.channel().update_output(output_id, fields).await?;
+            Ok(outp)
+        })
+        .await
+    }
+
+    /// Give an output a new name, rewriting the interlocks that refer to it. Fails if the name is
+    /// taken.
+    pub async fn rename_output(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        output_id: AppID,
+        new_name: AppID,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            Ok(context.channel().rename_output(output_id, new_name).await?)
+        })
+        .await
+    }
+
+    /// Add an interlock so that at most one of the given outputs can be on at a time
+    pub async fn add_interlock_at_most_one(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        name: String,
+        outputs: Vec<AppID>,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let rule = interlock::Rule::AtMostOne(interlock::AtMostOne { outputs });
+            Ok(context.channel().add_interlock(name, rule).await?)
+        })
+        .await
+    }
+
+    /// Add an interlock so that `output` can only turn on after `requires_off` has been off for
+    /// `for_seconds`
+    pub async fn add_interlock_requires_off(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        name: String,
+        output: AppID,
+        requires_off: AppID,
+        for_seconds: i32,
+    ) -> FieldResult<AppID> {
+        audited(executor, async {
+            let rule = interlock::Rule::RequiresOff(interlock::RequiresOff {
+                output,
+                requires_off,
+                for_seconds,
+            });
+            Ok(context.channel().add_interlock(name, rule).await?)
+        })
+        .await
+    }
+
+    /// Delete audit log entries older than `older_than_days`, returning how many were deleted.
+    /// Only admins can prune it.
+    pub async fn prune_audit_log(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        older_than_days: i32,
+    ) -> FieldResult<i32> {
+        audited(executor, async {
+            check_admin(context)?;
+            let days = u32::try_from(older_than_days)
+                .map_err(|_| Error::Config("olderThanDays can't be negative".to_string()))?;
+            let pruned = context.channel().prune_audit_log(days).await?;
+            Ok(pruned as i32)
+        })
+        .await
+    }
+
+    /// Remove the specified interlock
+    pub async fn remove_interlock(
+        context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
+        interlock_id: AppID,
+    ) -> FieldResult<bool> {
+        audited(executor, async {
+            context.channel().remove_interlock(interlock_id).await?;
+            Ok(true)
+        })
+        .await
     }
 
     /// Add an output to a device. Outputs denote ways to send data to a device. this output will permit automations.
     pub async fn add_output(
         context: &AppContext,
+        executor: &Executor<'_, '_, AppContext>,
         new_output: models::NewOutput,
     ) -> FieldResult<AppID> {
-        check_session(context)?;
-        info!("Adding output {:?}", new_output);
-        Ok(context.channel().add_output(new_output).await?)
+        audited(executor, async {
+            info!("Adding output {:?}", new_output);
+            Ok(context.channel().add_output(new_output).await?)
+        })
+        .await
     }
 }
 
@@ -339,6 +864,27 @@ impl Subscription {
         Box::pin(stream)
     }
 
+    /// Subscribe to input changes as devices report them by interrupt, without polling
+    async fn input_changes(
+        context: &AppContext,
+    ) -> Pin<Box<dyn Stream<Item = Result<InputChange, FieldError>> + Send>> {
+        let mut changes = context.channel().subscribe_input_changes();
+
+        let stream = async_stream::stream! {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => yield Ok(change),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Input change subscriber missed {} changes", missed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        };
+
+        Box::pin(stream)
+    }
+
     /// Subscribe to output value changes - polls every 3 seconds
     async fn output_updates(
         context: &AppContext,
@@ -372,10 +918,88 @@ impl Subscription {
 
 pub type Schema = RootNode<'static, Query, Mutation, Subscription>;
 
-fn check_session(context: &AppContext) -> FieldResult<()> {
+/// The signed-in user's name
+fn check_session(context: &AppContext) -> FieldResult<String> {
     match &context.session {
         None => Err(Error::NotLoggedIn)?,
-        Some(_u) => Ok(()),
+        Some(session) => Ok(session.user.clone()),
+    }
+}
+
+/// The signed-in user, if they're an admin
+fn check_admin(context: &AppContext) -> FieldResult<String> {
+    let user = check_session(context)?;
+    if context.channel().is_admin(&user) {
+        Ok(user)
+    } else {
+        Err(Error::NotAdmin)?
+    }
+}
+
+/// Run a mutation for the signed-in user, recording it in the audit log
+async fn audited<T>(
+    executor: &Executor<'_, '_, AppContext>,
+    run: impl Future<Output = FieldResult<T>>,
+) -> FieldResult<T> {
+    let user = check_session(executor.context())?;
+    audit(executor, user, run).await
+}
+
+/// Run a mutation, then record who made it, its arguments and how it went in the audit log.
+///
+/// The arguments are read from the query as sent, so every mutation is covered without listing
+/// them, and anything named like a password is redacted. Failing to record a mutation is logged
+/// rather than failing a mutation that has already happened.
+async fn audit<T>(
+    executor: &Executor<'_, '_, AppContext>,
+    user: String,
+    run: impl Future<Output = FieldResult<T>>,
+) -> FieldResult<T> {
+    let (mutation, arguments) = {
+        let field = executor.look_ahead();
+        let mut arguments = serde_json::Value::Object(
+            field
+                .arguments()
+                .map(|arg| (arg.name().to_string(), argument_json(arg.value())))
+                .collect(),
+        );
+        audit::redact(&mut arguments);
+        (
+            field.field_original_name().to_string(),
+            arguments.to_string(),
+        )
+    };
+    let result = run.await;
+    let entry = models::NewAuditEntry {
+        user,
+        mutation,
+        arguments,
+        succeeded: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.message().to_string()),
+    };
+    let mutation = entry.mutation.clone();
+    if let Err(e) = executor.context().channel().record_audit(entry).await {
+        error!("failed to record {} in the audit log: {}", mutation, e);
+    }
+    result
+}
+
+fn argument_json(value: LookAheadValue<'_, DefaultScalarValue>) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        LookAheadValue::Null => Value::Null,
+        LookAheadValue::Scalar(DefaultScalarValue::Int(i)) => Value::from(*i),
+        LookAheadValue::Scalar(DefaultScalarValue::Float(f)) => Value::from(*f),
+        LookAheadValue::Scalar(DefaultScalarValue::String(s)) => Value::from(s.as_str()),
+        LookAheadValue::Scalar(DefaultScalarValue::Boolean(b)) => Value::from(*b),
+        LookAheadValue::Enum(e) => Value::from(e),
+        LookAheadValue::List(items) => items.iter().map(|v| argument_json(v.item)).collect(),
+        LookAheadValue::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.item.to_string(), argument_json(v.item)))
+                .collect(),
+        ),
     }
 }
 
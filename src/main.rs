@@ -1,9 +1,13 @@
 use color_eyre::eyre;
 use color_eyre::owo_colors::OwoColorize;
 use librpi::app;
+use librpi::app::db::Db;
+use librpi::app::site::{Site, SiteFormat};
 use librpi::auth::password;
 use librpi::config::Config;
 use librpi::config::parse;
+use librpi::rpi::handle::DEVICE_TIMEOUT;
+use librpi::rpi::health::RetryPolicy;
 use librpi::webapp;
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
@@ -12,10 +16,11 @@ use std::env;
 use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 use warp::Filter;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 // big picture:
 // read configuration and decide what sensors and switches are available. start up application, then
@@ -47,6 +52,39 @@ enum Command {
     /// A REPL that shows how boolean expressions parse.
     BooleanRepl,
 
+    /// Probe the I2C bus and suggest devices for the addresses that answer
+    I2cScan {
+        /// Bus to scan, instead of the configured one
+        #[structopt(short, long)]
+        bus: Option<u8>,
+    },
+
+    /// List the DS18B20 probes on the 1-Wire bus
+    W1Scan,
+
+    /// Print all devices, inputs, outputs and interlocks as one document
+    Export {
+        /// toml or json
+        #[structopt(short, long, default_value = "toml")]
+        format: SiteFormat,
+    },
+
+    /// Replace all devices, inputs, outputs and interlocks with those in a file from `export`
+    Import {
+        file: PathBuf,
+
+        /// toml or json, instead of guessing from the file's extension
+        #[structopt(short, long)]
+        format: Option<SiteFormat>,
+    },
+
+    /// Copy the database to a file, safely even while the server is running
+    Backup { file: PathBuf },
+
+    /// Replace the database with a backup, keeping the current one as rpi.sql3.before-restore.
+    /// Stop the server first.
+    Restore { file: PathBuf },
+
     /// Add a user to the config file
     AddUser {
         /// Username to use for this password
@@ -56,6 +94,10 @@ enum Command {
         /// Specify password on argument list instead of prompting (note: bash_history)
         #[structopt(short, long)]
         password: Option<String>,
+
+        /// Let the user read and prune the audit log
+        #[structopt(long)]
+        admin: bool,
     },
 }
 
@@ -93,15 +135,32 @@ fn get_config(config_file: Option<&PathBuf>) -> Result<Config, color_eyre::Repor
     })
 }
 
+/// Directory holding the database: the configured one, or the config file's, or the current one
+fn get_db_path(config: &Config, config_file: Option<&PathBuf>) -> PathBuf {
+    config.db_path.clone().unwrap_or_else(|| {
+        config_file
+            .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+            .unwrap_or_else(|| PathBuf::from("."))
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), eyre::Error> {
     let (command, config_file) = setup();
     let _ = command.bright_white();
     match command {
-        Command::AddUser { username, password } => {
-            add_user(config_file.as_ref(), password, username)
-        }
+        Command::AddUser {
+            username,
+            password,
+            admin,
+        } => add_user(config_file.as_ref(), password, username, admin),
         Command::BooleanRepl => bool_repl(config_file.as_ref()),
+        Command::I2cScan { bus } => i2c_scan(config_file.as_ref(), bus).await,
+        Command::W1Scan => w1_scan().await,
+        Command::Export { format } => export(config_file.as_ref(), format),
+        Command::Import { file, format } => import(config_file.as_ref(), &file, format),
+        Command::Backup { file } => backup(config_file.as_ref(), &file),
+        Command::Restore { file } => restore(config_file.as_ref(), &file),
         Command::Server => server(config_file).await,
     }
 }
@@ -127,33 +186,68 @@ async fn server(config_file: Option<PathBuf>) -> Result<(), color_eyre::Report>
     let bus = config.i2cbus.unwrap_or(1);
     let tls_key_path = config.tls_key_path.clone();
     let tls_cert_path = config.tls_cert_path.clone();
-    // For db_path: use config value, or config file directory, or current directory
-    let db_path = config.db_path.unwrap_or_else(|| {
-        config_file
-            .as_ref()
-            .and_then(|p| p.parent().map(|p| p.to_path_buf()))
-            .unwrap_or_else(|| PathBuf::from("."))
-    });
+    let db_path = get_db_path(&config, config_file.as_ref());
     let users = config.users.unwrap_or_else(HashMap::new).clone();
+    let admins = config.admins.unwrap_or_default().into_iter().collect();
+    let watchdog_device = config.watchdog_device.clone();
+    let watchdog_interval = Duration::from_secs(config.watchdog_interval_secs.unwrap_or(5));
+    let backup_dir = config.backup_dir.clone();
+    let backup_interval = Duration::from_secs(config.backup_interval_secs.unwrap_or(86400).max(1));
+    let backups_kept = config.backups_kept.unwrap_or(7).max(1);
     let here = (config.lat, config.long);
+    let reading_max_age = Duration::from_millis(config.reading_max_age_ms.unwrap_or(1000));
+    let defaults = RetryPolicy::default();
+    let retry = RetryPolicy {
+        attempts: config
+            .device_read_attempts
+            .unwrap_or(defaults.attempts)
+            .max(1),
+        backoff: config
+            .device_retry_backoff_ms
+            .map_or(defaults.backoff, Duration::from_millis),
+        reset_after: config
+            .device_reset_after_failures
+            .unwrap_or(defaults.reset_after),
+    };
+    let fitted = retry.within(DEVICE_TIMEOUT);
+    if fitted != retry {
+        warn!(
+            "device_read_attempts lowered from {} to {} so that retries fit in the {:?} device timeout",
+            retry.attempts, fitted.attempts, DEVICE_TIMEOUT
+        );
+    }
+    let retry = fitted;
 
     info!("Starting RestedPi server");
     info!("  I2C bus: {}", bus);
     info!("  Database path: {:?}", db_path);
     info!("  Location: ({}, {})", here.0, here.1);
 
-    let app = app::channel::start_app(bus, here, &db_path, users)
+    // opened once, for the app and for backups of the same file
+    let db = Db::start_db(&db_path).map_err(|e| {
+        eyre::eyre!(
+            "Failed to open the database: {}\n\n\
+             Hint: Check that the database path is accessible.\n\
+             You can set 'db_path' in your config.toml or use --config-file.",
+            e
+        )
+    })?;
+    let app = app::channel::start_app(bus, here, reading_max_age, retry, db.clone(), users, admins)
         .await
-        .map_err(|e| {
-            eyre::eyre!(
-                "Failed to start app: {}\n\n\
-                 Hint: Check that the database path is accessible.\n\
-                 You can set 'db_path' in your config.toml or use --config-file.",
-                e
-            )
-        })?;
+        .map_err(|e| eyre::eyre!("Failed to start app: {}", e))?;
+
+    let watchdog = match watchdog_device {
+        Some(device) => Some(
+            app::watchdog::start(&device, watchdog_interval, app.health().clone())
+                .map_err(|e| eyre::eyre!("Failed to open watchdog {:?}: {}", device, e))?,
+        ),
+        None => None,
+    };
 
-    let api = webapp::filters::graphql_api(app);
+    let backups =
+        backup_dir.map(|dir| app::db::backup::start(db, dir, backup_interval, backups_kept));
+
+    let api = webapp::filters::graphql_api(app.clone());
 
     let addr: SocketAddr = format!("{}:{}", listen, port)
         .parse()
@@ -161,17 +255,20 @@ async fn server(config_file: Option<PathBuf>) -> Result<(), color_eyre::Report>
     let serve = warp::serve((api.with(warp::log("web"))).recover(webapp::handle_rejection));
     match (tls_key_path, tls_cert_path) {
         (Some(key_path), Some(cert_path)) => {
-            info!("RestedPi listening: https://{}", addr);
-            serve
+            let (addr, server) = serve
                 .tls()
                 .cert_path(cert_path)
                 .key_path(key_path)
-                .run(addr)
-                .await
+                .bind_with_graceful_shutdown(addr, shutdown_signal());
+            info!("RestedPi listening: https://{}", addr);
+            server.await
         }
         (None, None) => {
+            let (addr, server) = serve
+                .try_bind_with_graceful_shutdown(addr, shutdown_signal())
+                .map_err(|e| eyre::eyre!("Failed to listen on {}: {}", addr, e))?;
             info!("RestedPi listening: http://{} (no TLS)", addr);
-            serve.run(addr).await
+            server.await
         }
         (Some(_), None) => {
             return Err(eyre::eyre!(
@@ -184,6 +281,143 @@ async fn server(config_file: Option<PathBuf>) -> Result<(), color_eyre::Report>
             ));
         }
     }
+
+    info!("Shutting down, putting outputs in their safe states");
+    app.terminate()
+        .await
+        .map_err(|e| eyre::eyre!("Failed to shut down cleanly: {}", e))?;
+    if let Some(backups) = backups {
+        backups.abort();
+    }
+    if let Some(watchdog) = watchdog {
+        watchdog.disarm().await;
+    }
+    Ok(())
+}
+
+/// Resolves when the process is asked to stop, by SIGTERM or ctrl-c
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received ctrl-c"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+async fn i2c_scan(
+    config_file: Option<&PathBuf>,
+    bus: Option<u8>,
+) -> Result<(), color_eyre::Report> {
+    let config = get_config(config_file)?;
+    let bus = bus.or(config.i2cbus).unwrap_or(1);
+    let rapi = librpi::rpi::start(bus);
+    let found = librpi::rpi::i2c::scan::scan(&rapi)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to scan I2C bus {}: {}", bus, e))?;
+    if found.is_empty() {
+        println!("No devices found on I2C bus {}", bus);
+    }
+    for detected in found {
+        match (&detected.chip, &detected.suggestion) {
+            (Some(chip), Some(suggestion)) => println!(
+                "0x{:02x}: {} ({})",
+                detected.address,
+                chip,
+                serde_json::to_string(suggestion)?
+            ),
+            _ => println!("0x{:02x}: unknown device", detected.address),
+        }
+    }
+    Ok(())
+}
+
+async fn w1_scan() -> Result<(), color_eyre::Report> {
+    let rom_ids = librpi::rpi::w1::W1Bus::default()
+        .rom_ids()
+        .await
+        .map_err(|e| eyre::eyre!("Failed to list 1-Wire devices: {}", e))?;
+    if rom_ids.is_empty() {
+        println!(
+            "No DS18B20s found in {}; is the w1-gpio overlay enabled?",
+            librpi::rpi::w1::SYSFS_ROOT
+        );
+    }
+    for rom_id in rom_ids {
+        println!("{}", rom_id);
+    }
+    Ok(())
+}
+
+fn export(config_file: Option<&PathBuf>, format: SiteFormat) -> Result<(), color_eyre::Report> {
+    let config = get_config(config_file)?;
+    let db = Db::start_db(&get_db_path(&config, config_file))?;
+    print!("{}", db.export()?.render(format)?);
+    Ok(())
+}
+
+fn import(
+    config_file: Option<&PathBuf>,
+    file: &PathBuf,
+    format: Option<SiteFormat>,
+) -> Result<(), color_eyre::Report> {
+    let config = get_config(config_file)?;
+    let text =
+        fs::read_to_string(file).map_err(|e| eyre::eyre!("Failed to read {:?}: {}", file, e))?;
+    let site = Site::parse(&text, format.unwrap_or_else(|| SiteFormat::for_path(file)))?;
+    site.validate(&librpi::rpi::start(config.i2cbus.unwrap_or(1)))
+        .map_err(|e| eyre::eyre!("{:?} can't be imported: {}", file, e))?;
+    let db = Db::start_db(&get_db_path(&config, config_file))?;
+    db.import(&site)?;
+    println!(
+        "Imported {} devices, {} inputs, {} outputs and {} interlocks",
+        site.devices.len(),
+        site.inputs.len(),
+        site.outputs.len(),
+        site.interlocks.len()
+    );
+    println!("A running server only picks these up once restarted; importConfig avoids that.");
+    Ok(())
+}
+
+fn backup(config_file: Option<&PathBuf>, file: &PathBuf) -> Result<(), color_eyre::Report> {
+    let config = get_config(config_file)?;
+    let db = Db::start_db(&get_db_path(&config, config_file))?;
+    db.backup(file)
+        .map_err(|e| eyre::eyre!("Failed to back up to {:?}: {}", file, e))?;
+    println!("Backed up to {:?}", file);
+    Ok(())
+}
+
+fn restore(config_file: Option<&PathBuf>, file: &PathBuf) -> Result<(), color_eyre::Report> {
+    let config = get_config(config_file)?;
+    let db_path = get_db_path(&config, config_file);
+    let version = app::db::backup::restore(&db_path, file)
+        .map_err(|e| eyre::eyre!("{:?} can't be restored: {}", file, e))?;
+    println!(
+        "Restored {:?} (schema version {}) into {:?}",
+        file, version, db_path
+    );
     Ok(())
 }
 
@@ -236,6 +470,7 @@ fn add_user(
     config_file: Option<&PathBuf>,
     password: Option<String>,
     username: String,
+    admin: bool,
 ) -> Result<(), color_eyre::Report> {
     let config_file = config_file.ok_or_else(|| {
         eyre::eyre!(
@@ -261,6 +496,10 @@ fn add_user(
     );
     match password::hash(&password) {
         Ok(hashed) => {
+            let admins = config.admins.get_or_insert_with(Vec::new);
+            if admin && !admins.contains(&username) {
+                admins.push(username.clone());
+            }
             let users = config.users.get_or_insert_with(HashMap::new);
             users.insert(username, hashed);
             // write config file back
@@ -302,7 +541,10 @@ fn setup() -> (Command, Option<PathBuf>) {
     if std::env::var("RUST_LOG").is_err() {
         unsafe { env::set_var("RUST_LOG", log_level) };
     }
-    tracing_subscriber::fmt::init();
+    // logs go to stderr, so that commands like `export` can be redirected to a file
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     let config_file = get_config_path(config_file);
     if let Some(ref path) = config_file {
         info!("Using config file: {:?}", path);
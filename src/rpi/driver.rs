@@ -0,0 +1,239 @@
+use super::RpiApi;
+use super::i2c::mcp23017::PinChange;
+use super::i2c::{ads1x15, bmp085, htu21d, ina219, mcp9808, mcp23017, pca9685, sht31};
+use super::spi::mcp3008;
+use super::w1;
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A chip, and whatever state it needs to drive it. Each driver owns one device, and is only
+/// ever used from that device's task.
+///
+/// Indexes are slot indexes, as given by `slots`. Everything other than `slots`, `reset` and
+/// `read_sensor` has a default for chips without booleans, levels or interrupts.
+pub trait Driver: Send + Sync + std::fmt::Debug {
+    /// What each of the device's slots can be used for
+    fn slots(&self) -> Vec<device::Slot>;
+
+    /// Bring the chip to a known state, as at startup
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>>;
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>>;
+
+    fn read_boolean(&self, index: i32) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move { Err(Error::OutOfBounds(index as usize)) })
+    }
+
+    fn write_boolean(&mut self, index: i32, _value: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { Err(Error::OutOfBounds(index as usize)) })
+    }
+
+    /// The duty cycle a level output was last set to
+    fn read_level(&self, index: i32) -> Result<f64> {
+        Err(Error::OutOfBounds(index as usize))
+    }
+
+    /// Set a level output's duty cycle, from 0 to 1
+    fn write_level(&mut self, index: i32, _value: f64) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { Err(Error::OutOfBounds(index as usize)) })
+    }
+
+    /// The GPIO pin this device raises interrupts on, if it has one wired up
+    fn interrupt_pin(&self) -> Option<u8> {
+        None
+    }
+
+    /// Inputs that changed since the last interrupt, with the values they changed to
+    fn take_pin_changes(&self) -> BoxFuture<'_, Result<Vec<PinChange>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    /// How many slots read as numbers
+    fn sensor_count(&self) -> u32 {
+        self.slots()
+            .iter()
+            .filter(|s| s.can_input && s.unit != Unit::Boolean)
+            .count() as u32
+    }
+
+    /// How many slots can be read or written as booleans
+    fn boolean_count(&self) -> u32 {
+        self.slots()
+            .iter()
+            .filter(|s| s.unit == Unit::Boolean || s.can_output)
+            .count() as u32
+    }
+}
+
+/// Makes the driver for a device. Given the device's whole model so that one constructor can
+/// serve several variants, or read a custom device's configuration.
+pub type Constructor = Arc<dyn Fn(&device::Type, RpiApi) -> Result<Box<dyn Driver>> + Send + Sync>;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<String, Constructor>> = RwLock::new(builtin());
+}
+
+fn builtin() -> HashMap<String, Constructor> {
+    let drivers: [(&str, Constructor); 10] = [
+        ("MCP9808", Arc::new(mcp9808::driver)),
+        ("BMP085", Arc::new(bmp085::driver)),
+        ("MCP23017", Arc::new(mcp23017::driver)),
+        ("ADS1x15", Arc::new(ads1x15::driver)),
+        ("PCA9685", Arc::new(pca9685::driver)),
+        ("DS18B20", Arc::new(w1::driver)),
+        ("SHT31", Arc::new(sht31::driver)),
+        ("HTU21D", Arc::new(htu21d::driver)),
+        ("INA219", Arc::new(ina219::driver)),
+        ("MCP3008", Arc::new(mcp3008::driver)),
+    ];
+    drivers
+        .into_iter()
+        .map(|(name, constructor)| (name.to_string(), constructor))
+        .collect()
+}
+
+/// Make `constructor` the driver for devices named `name`: a `device::Type` variant, or the
+/// `driver` of a `device::Custom`. Out-of-tree drivers register themselves this way before the
+/// app starts, and can replace built-in ones.
+pub fn register(name: &str, constructor: Constructor) {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string(), constructor);
+}
+
+/// Make the driver for a device
+pub fn create(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    let name = model.driver_name();
+    let constructor = REGISTRY
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
+        .ok_or_else(|| Error::NonExistant(format!("no driver registered for '{}'", name)))?;
+    constructor(model, rapi)
+}
+
+/// For constructors handed a model they don't drive
+pub fn wrong_model(expected: &str, model: &device::Type) -> Error {
+    Error::Config(format!(
+        "the {} driver can't drive a {}",
+        expected,
+        model.driver_name()
+    ))
+}
+
+/// A slot that only reads numbers, in `unit`
+pub fn input_slot(unit: Unit) -> device::Slot {
+    device::Slot {
+        can_input: true,
+        can_output: false,
+        unit,
+        direction: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpi;
+
+    /// An out-of-tree driver: a constant voltage, configured by JSON
+    #[derive(Debug)]
+    struct Constant {
+        volts: f64,
+        resets: u32,
+    }
+
+    fn constant(model: &device::Type, _rapi: RpiApi) -> Result<Box<dyn Driver>> {
+        match model {
+            device::Type::Custom(custom) => {
+                let volts: f64 = serde_json::from_str(&custom.config)?;
+                Ok(Box::new(Constant { volts, resets: 0 }))
+            }
+            _ => Err(wrong_model("Constant", model)),
+        }
+    }
+
+    impl Driver for Constant {
+        fn slots(&self) -> Vec<device::Slot> {
+            vec![input_slot(Unit::Volts)]
+        }
+
+        fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+            self.resets += 1;
+            Box::pin(async { Ok(()) })
+        }
+
+        fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+            Box::pin(async move {
+                match index {
+                    0 => Ok(Dimensioned::from_volts(self.volts)),
+                    _ => Err(Error::OutOfBounds(index as usize)),
+                }
+            })
+        }
+    }
+
+    fn custom(driver: &str, config: &str) -> device::Type {
+        device::Type::Custom(device::Custom {
+            driver: driver.to_string(),
+            config: config.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn registered_drivers_drive_custom_devices() {
+        register("Constant", Arc::new(constant));
+        let mut driver = create(&custom("Constant", "2.5"), rpi::start(1)).unwrap();
+        driver.reset().await.unwrap();
+        assert_eq!(driver.sensor_count(), 1);
+        assert_eq!(driver.boolean_count(), 0);
+        assert_eq!(
+            driver.read_sensor(0).await.unwrap(),
+            Dimensioned::from_volts(2.5)
+        );
+        assert_eq!(driver.read_boolean(0).await, Err(Error::OutOfBounds(0)));
+        assert!(driver.read_level(0).is_err());
+        assert_eq!(driver.interrupt_pin(), None);
+    }
+
+    #[test]
+    fn unregistered_drivers_are_rejected() {
+        assert!(matches!(
+            create(&custom("NoSuchChip", "{}"), rpi::start(1)),
+            Err(Error::NonExistant(_))
+        ));
+        assert!(matches!(
+            mcp9808::driver(&custom("NoSuchChip", "{}"), rpi::start(1)),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn builtin_models_count_their_slots() {
+        let mcp23017 = device::Type::MCP23017(device::MCP23017 {
+            address: 0x20,
+            bank_a: device::Directions::new(),
+            bank_b: device::Directions::new(),
+            interrupt_pin: None,
+        });
+        let driver = create(&mcp23017, rpi::start(1)).unwrap();
+        assert_eq!(driver.slots().len(), 16);
+        assert_eq!(driver.sensor_count(), 0);
+        assert_eq!(driver.boolean_count(), 16);
+
+        let pca9685 = device::Type::PCA9685(device::PCA9685 {
+            address: 0x40,
+            frequency: 1000,
+        });
+        let driver = create(&pca9685, rpi::start(1)).unwrap();
+        assert_eq!(driver.sensor_count(), 16);
+        assert_eq!(driver.boolean_count(), 16);
+    }
+}
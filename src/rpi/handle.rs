@@ -0,0 +1,540 @@
+use super::device::Device;
+use super::health::{DeviceHealth, HealthStatus, RetryPolicy, is_transient};
+use super::i2c::mcp23017::PinChange;
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// How many requests may wait for a device before callers are turned away
+const QUEUE_DEPTH: usize = 8;
+
+/// How long to wait for a device to answer a request. Generous enough for a BMP085 UltraHighRes
+/// pressure read, short enough that a hung bus doesn't stall the app loop.
+pub const DEVICE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Work for a device task, answered on `response`
+#[derive(Debug)]
+pub enum DeviceRequest {
+    Reset {
+        response: oneshot::Sender<Result<()>>,
+    },
+    ReadBoolean {
+        index: i32,
+        response: oneshot::Sender<Result<bool>>,
+    },
+    ReadSensor {
+        index: i32,
+        response: oneshot::Sender<Result<Dimensioned>>,
+    },
+    WriteBoolean {
+        index: i32,
+        value: bool,
+        response: oneshot::Sender<Result<()>>,
+    },
+    ReadLevel {
+        index: i32,
+        response: oneshot::Sender<Result<f64>>,
+    },
+    WriteLevel {
+        index: i32,
+        value: f64,
+        response: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Talks to a `Device` running in its own task, so one slow or hung device only delays requests
+/// for itself.
+///
+/// The task exits once every handle to it has been dropped.
+#[derive(Clone, Debug)]
+pub struct DeviceHandle {
+    sender: mpsc::Sender<DeviceRequest>,
+    slots: Vec<device::Slot>,
+    timeout: Duration,
+    health: Arc<DeviceHealth>,
+}
+
+/// Called from the device task for each input change reported by an interrupt
+pub type OnChange = Box<dyn Fn(PinChange) + Send>;
+
+/// A device and how to look after it, owned by the device's task
+struct Task {
+    device: Device,
+    policy: RetryPolicy,
+    health: Arc<DeviceHealth>,
+}
+
+impl Task {
+    /// Record how an operation went, resetting the device if it keeps failing
+    async fn record<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Some(failures) = self.health.record(&result)
+            && self.policy.reset_after > 0
+            && failures % self.policy.reset_after == 0
+        {
+            warn!("device failed {} times in a row, resetting it", failures);
+            self.health.reset_after_failures();
+            // the failures only stop counting once the device answers again
+            if let Err(e) = self.device.reset().await {
+                warn!("device reset failed: {}", e);
+            }
+        }
+        result
+    }
+
+    /// Read from the device, retrying transient failures with backoff
+    async fn read<T>(
+        &mut self,
+        read: impl for<'a> Fn(&'a Device) -> BoxFuture<'a, Result<T>>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            let result = read(&self.device).await;
+            match self.record(result).await {
+                Err(e) if is_transient(&e) && attempt + 1 < self.policy.attempts => {
+                    debug!("retrying device read after: {}", e);
+                    tokio::time::sleep(self.policy.delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn take_pin_changes(&mut self) -> Result<Vec<PinChange>> {
+        let changes = self.device.take_pin_changes().await;
+        self.record(changes).await
+    }
+
+    async fn answer(&mut self, request: DeviceRequest) {
+        // a caller that gave up waiting has dropped its receiver, so send errors are expected
+        match request {
+            DeviceRequest::Reset { response } => {
+                let result = self.device.reset().await;
+                self.health.record(&result);
+                let _ = response.send(result);
+            }
+            DeviceRequest::ReadBoolean { index, response } => {
+                let result = self.read(|d| Box::pin(d.read_boolean(index))).await;
+                let _ = response.send(result);
+            }
+            DeviceRequest::ReadSensor { index, response } => {
+                let result = self.read(|d| Box::pin(d.read_sensor(index))).await;
+                let _ = response.send(result);
+            }
+            DeviceRequest::WriteBoolean {
+                index,
+                value,
+                response,
+            } => {
+                let result = self.device.write_boolean(index, value).await;
+                let _ = response.send(self.record(result).await);
+            }
+            DeviceRequest::ReadLevel { index, response } => {
+                let _ = response.send(self.device.read_level(index));
+            }
+            DeviceRequest::WriteLevel {
+                index,
+                value,
+                response,
+            } => {
+                let result = self.device.write_level(index, value).await;
+                let _ = response.send(self.record(result).await);
+            }
+        }
+    }
+}
+
+/// Wait for the next interrupt, or forever if there is nothing to watch
+async fn next_interrupt(interrupts: &mut Option<mpsc::UnboundedReceiver<()>>) -> Option<()> {
+    match interrupts {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl DeviceHandle {
+    pub fn spawn(device: Device) -> Self {
+        Self::start(device, DEVICE_TIMEOUT, RetryPolicy::default(), None)
+    }
+
+    pub fn spawn_with_timeout(device: Device, timeout: Duration) -> Self {
+        Self::start(device, timeout, RetryPolicy::default(), None)
+    }
+
+    pub fn spawn_with_policy(device: Device, policy: RetryPolicy) -> Self {
+        Self::start(device, DEVICE_TIMEOUT, policy, None)
+    }
+
+    /// Like `spawn`, but if the device has an interrupt line, `on_change` is told about each
+    /// input change as soon as the device reports it.
+    pub fn spawn_watching(device: Device, policy: RetryPolicy, on_change: OnChange) -> Self {
+        Self::start(device, DEVICE_TIMEOUT, policy, Some(on_change))
+    }
+
+    fn start(
+        device: Device,
+        timeout: Duration,
+        policy: RetryPolicy,
+        on_change: Option<OnChange>,
+    ) -> Self {
+        let slots = device.slots();
+        let health = Arc::new(DeviceHealth::new());
+        let mut task = Task {
+            device,
+            policy: policy.within(timeout),
+            health: health.clone(),
+        };
+        let (sender, mut receiver) = mpsc::channel(QUEUE_DEPTH);
+        tokio::spawn(async move {
+            let mut interrupts = match on_change {
+                Some(_) => task.device.watch_interrupts().await.unwrap_or_else(|e| {
+                    warn!(
+                        "can't watch device interrupts, changes will only be polled: {}",
+                        e
+                    );
+                    None
+                }),
+                None => None,
+            };
+            loop {
+                tokio::select! {
+                    request = receiver.recv() => match request {
+                        Some(request) => task.answer(request).await,
+                        None => break,
+                    },
+                    interrupt = next_interrupt(&mut interrupts) => match interrupt {
+                        Some(()) => match task.take_pin_changes().await {
+                            Ok(changes) => {
+                                if let Some(on_change) = &on_change {
+                                    changes.into_iter().for_each(on_change);
+                                }
+                            }
+                            Err(e) => warn!("failed to read interrupt changes: {}", e),
+                        },
+                        None => interrupts = None,
+                    },
+                }
+            }
+            debug!("device task exiting");
+        });
+        Self {
+            sender,
+            slots,
+            timeout,
+            health,
+        }
+    }
+
+    pub fn slots(&self) -> Vec<device::Slot> {
+        self.slots.clone()
+    }
+
+    /// How well the device has been answering
+    pub fn health(&self) -> HealthStatus {
+        self.health.status()
+    }
+
+    async fn request<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<Result<T>>) -> DeviceRequest,
+    ) -> Result<T> {
+        let (response, receiver) = oneshot::channel();
+        self.sender.try_send(make(response)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                Error::DeviceTimeout("too many requests waiting for device".to_string())
+            }
+            mpsc::error::TrySendError::Closed(_) => {
+                Error::SendError("device task has stopped".to_string())
+            }
+        })?;
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(result) => result?,
+            Err(_) => {
+                // the task is still stuck on the device, so can't record this itself
+                let e = Error::DeviceTimeout(format!(
+                    "no answer from device within {:?}",
+                    self.timeout
+                ));
+                self.health.failed(&e);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn reset(&self) -> Result<()> {
+        self.request(|response| DeviceRequest::Reset { response })
+            .await
+    }
+
+    pub async fn read_boolean(&self, index: i32) -> Result<bool> {
+        self.request(|response| DeviceRequest::ReadBoolean { index, response })
+            .await
+    }
+
+    pub async fn read_sensor(&self, index: i32) -> Result<Dimensioned> {
+        self.request(|response| DeviceRequest::ReadSensor { index, response })
+            .await
+    }
+
+    pub async fn write_boolean(&self, index: i32, value: bool) -> Result<()> {
+        self.request(|response| DeviceRequest::WriteBoolean {
+            index,
+            value,
+            response,
+        })
+        .await
+    }
+
+    pub async fn read_level(&self, index: i32) -> Result<f64> {
+        self.request(|response| DeviceRequest::ReadLevel { index, response })
+            .await
+    }
+
+    pub async fn write_level(&self, index: i32, value: f64) -> Result<()> {
+        self.request(|response| DeviceRequest::WriteLevel {
+            index,
+            value,
+            response,
+        })
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "mock-gpio"))]
+mod tests {
+    use super::*;
+    use crate::config::types::Unit;
+    use crate::rpi;
+    use crate::rpi::driver::{self, Driver};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn mcp9808() -> Device {
+        Device::new(
+            device::Type::MCP9808(device::MCP9808 { address: 0x18 }),
+            rpi::start(1),
+        )
+        .unwrap()
+    }
+
+    fn bmp085() -> Device {
+        Device::new(
+            device::Type::BMP085(device::BMP085 {
+                address: 0x77,
+                mode: device::SamplingMode::UltraHighRes,
+            }),
+            rpi::start(1),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_by_the_device_task() {
+        let handle = DeviceHandle::spawn(mcp9808());
+        assert_eq!(handle.slots().len(), 1);
+        handle.reset().await.unwrap();
+        assert!(handle.read_sensor(0).await.is_ok());
+        assert_eq!(handle.read_sensor(1).await, Err(Error::OutOfBounds(1)));
+    }
+
+    #[tokio::test]
+    async fn interrupts_report_pin_changes() {
+        let rapi = rpi::start(1);
+        let device = Device::new(
+            device::Type::MCP23017(device::MCP23017 {
+                address: 0x20,
+                bank_a: device::Directions::new(),
+                bank_b: device::Directions::new(),
+                interrupt_pin: Some(17),
+            }),
+            rapi.clone(),
+        )
+        .unwrap();
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        let handle = DeviceHandle::spawn_watching(
+            device,
+            RetryPolicy::default(),
+            Box::new(move |change| {
+                let _ = sender.send(change);
+            }),
+        );
+        handle.reset().await.unwrap();
+
+        // INTFA/INTCAPA: pin 4 went high
+        rapi.set_i2c_register(0x20, 0x0E, vec![0b10000]).await;
+        rapi.set_i2c_register(0x20, 0x10, vec![0b10000]).await;
+        rapi.fire_gpio_interrupt(17).await;
+
+        let change = tokio::time::timeout(Duration::from_secs(1), changes.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            change,
+            Some(PinChange {
+                index: 4,
+                value: true
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn polled_reads_keep_changes_for_the_interrupt() {
+        let rapi = rpi::start(1);
+        let device = Device::new(
+            device::Type::MCP23017(device::MCP23017 {
+                address: 0x20,
+                bank_a: device::Directions {
+                    p3: device::Dir::In,
+                    p4: device::Dir::In,
+                    ..device::Directions::new()
+                },
+                bank_b: device::Directions::new(),
+                interrupt_pin: Some(17),
+            }),
+            rapi.clone(),
+        )
+        .unwrap();
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        let handle = DeviceHandle::spawn_watching(
+            device,
+            RetryPolicy::default(),
+            Box::new(move |change| {
+                let _ = sender.send(change);
+            }),
+        );
+        handle.reset().await.unwrap();
+        assert!(!handle.read_boolean(4).await.unwrap());
+
+        // pin 4 went high, and a poll read GPIOA before the interrupt was taken, leaving
+        // INTFA clear
+        rapi.set_i2c_register(0x20, 0x12, vec![0b10000]).await;
+        assert!(!handle.read_boolean(3).await.unwrap());
+        rapi.fire_gpio_interrupt(17).await;
+
+        let change = tokio::time::timeout(Duration::from_secs(1), changes.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            change,
+            Some(PinChange {
+                index: 4,
+                value: true
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_devices_time_out() {
+        // a temperature conversion takes 5ms
+        let handle = DeviceHandle::spawn_with_timeout(bmp085(), Duration::from_millis(1));
+        assert!(matches!(
+            handle.read_sensor(0).await,
+            Err(Error::DeviceTimeout(_))
+        ));
+    }
+
+    /// Fails its first `failures` reads with a NACK, and counts its resets
+    #[derive(Debug)]
+    struct Flaky {
+        failures: Arc<AtomicU32>,
+        resets: Arc<AtomicU32>,
+    }
+
+    impl Driver for Flaky {
+        fn slots(&self) -> Vec<device::Slot> {
+            vec![driver::input_slot(Unit::Volts)]
+        }
+
+        fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+            self.resets.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+            Box::pin(async move {
+                if index != 0 {
+                    return Err(Error::OutOfBounds(index as usize));
+                }
+                let left = self.failures.load(Ordering::SeqCst);
+                if left > 0 {
+                    self.failures.store(left - 1, Ordering::SeqCst);
+                    return Err(Error::IoError("NACK".to_string()));
+                }
+                Ok(Dimensioned::from_volts(1.0))
+            })
+        }
+    }
+
+    /// A flaky device that fails `failures` reads, and a count of its resets
+    fn flaky(name: &str, failures: u32) -> (Device, Arc<AtomicU32>) {
+        let failures = Arc::new(AtomicU32::new(failures));
+        let resets = Arc::new(AtomicU32::new(0));
+        let counted = resets.clone();
+        driver::register(
+            name,
+            Arc::new(move |_, _| {
+                Ok(Box::new(Flaky {
+                    failures: failures.clone(),
+                    resets: counted.clone(),
+                }))
+            }),
+        );
+        let model = device::Type::Custom(device::Custom {
+            driver: name.to_string(),
+            config: String::new(),
+        });
+        (Device::new(model, rpi::start(1)).unwrap(), resets)
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let (device, _) = flaky("FlakyRetried", 2);
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            reset_after: 0,
+        };
+        let handle = DeviceHandle::spawn_with_policy(device, policy);
+        assert!(handle.read_sensor(0).await.is_ok());
+
+        let health = handle.health();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.failures, 2.0);
+        assert_eq!(health.successes, 1.0);
+        assert!(health.last_success_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn failing_devices_are_reset() {
+        let (device, resets) = flaky("FlakyReset", 100);
+        let policy = RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(1),
+            reset_after: 2,
+        };
+        let handle = DeviceHandle::spawn_with_policy(device, policy);
+        for _ in 0..5 {
+            assert!(matches!(
+                handle.read_sensor(0).await,
+                Err(Error::IoError(_))
+            ));
+        }
+        assert_eq!(resets.load(Ordering::SeqCst), 2);
+        let health = handle.health();
+        assert_eq!(health.consecutive_failures, 5);
+        assert_eq!(health.resets, 2);
+        assert_eq!(health.error_rate, 1.0);
+    }
+
+    #[tokio::test]
+    async fn request_mistakes_are_not_retried() {
+        let (device, _) = flaky("FlakyBounds", 0);
+        let handle = DeviceHandle::spawn(device);
+        assert_eq!(handle.read_sensor(1).await, Err(Error::OutOfBounds(1)));
+        assert_eq!(handle.health().failures, 0.0);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::error::{Error, Result};
+use juniper::GraphQLObject;
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many of a device's most recent operations its error rate is taken over
+const RECENT_OPERATIONS: usize = 100;
+
+/// How device reads are retried, and when a failing device is reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a read is tried before its error is returned, at least 1
+    pub attempts: u32,
+
+    /// How long to wait before the first retry, doubling for each one after
+    pub backoff: Duration,
+
+    /// Reset the device after this many failures in a row, or never if 0
+    pub reset_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        // all retries fit well within DEVICE_TIMEOUT; `within` holds other policies to that
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            reset_after: 5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after a failed attempt, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+
+    /// Time spent waiting between attempts when every attempt fails
+    pub fn total_backoff(&self) -> Duration {
+        (0..self.attempts.saturating_sub(1))
+            .map(|attempt| self.delay(attempt))
+            .fold(Duration::ZERO, Duration::saturating_add)
+    }
+
+    /// This policy with no more attempts than fit in `timeout`, keeping half of it for the
+    /// attempts themselves. Otherwise a caller gives up while the device is still retrying, and
+    /// the one failure is counted twice.
+    pub fn within(mut self, timeout: Duration) -> Self {
+        let budget = timeout / 2;
+        while self.attempts > 1 && self.total_backoff() > budget {
+            self.attempts -= 1;
+        }
+        self
+    }
+}
+
+/// Is this the kind of error a device can recover from, like a NACK or a bad CRC, rather than a
+/// mistake in the request
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::IoError(_) | Error::DeviceReadError(_) | Error::DeviceTimeout(_) => true,
+        #[cfg(feature = "raspberrypi")]
+        Error::I2cError(_) | Error::SpiError(_) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    consecutive_failures: u32,
+    successes: u64,
+    failures: u64,
+    resets: u32,
+    last_success: Option<Instant>,
+    last_error: Option<String>,
+
+    /// Whether each recent operation failed, oldest first
+    recent: VecDeque<bool>,
+}
+
+impl Counters {
+    fn push_recent(&mut self, failed: bool) {
+        if self.recent.len() == RECENT_OPERATIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(failed);
+    }
+}
+
+/// How well a device has been answering, shared between its task and its handles
+#[derive(Debug, Default)]
+pub struct DeviceHealth {
+    counters: Mutex<Counters>,
+}
+
+/// A point in time summary of `DeviceHealth`
+#[derive(GraphQLObject, Serialize, Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    /// Failed operations since the last one that succeeded
+    pub consecutive_failures: i32,
+
+    /// Fraction of the last 100 operations that failed
+    pub error_rate: f64,
+
+    /// Milliseconds since an operation last succeeded, if one has
+    pub last_success_ms: Option<f64>,
+
+    /// The most recent failure
+    pub last_error: Option<String>,
+
+    /// How many times the device has been reset after failing repeatedly
+    pub resets: i32,
+
+    /// Operations that succeeded since the device was started
+    pub successes: f64,
+
+    /// Operations that failed since the device was started, counting each retry
+    pub failures: f64,
+}
+
+impl DeviceHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_counters<T>(&self, f: impl FnOnce(&mut Counters) -> T) -> T {
+        // counters are only ever bumped, so a poisoned lock still holds usable numbers
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut counters)
+    }
+
+    pub fn succeeded(&self) {
+        self.with_counters(|c| {
+            c.consecutive_failures = 0;
+            c.successes += 1;
+            c.last_success = Some(Instant::now());
+            c.push_recent(false);
+        });
+    }
+
+    /// Record a failure, returning how many there have been in a row
+    pub fn failed(&self, error: &Error) -> u32 {
+        self.with_counters(|c| {
+            c.consecutive_failures += 1;
+            c.failures += 1;
+            c.last_error = Some(error.to_string());
+            c.push_recent(true);
+            c.consecutive_failures
+        })
+    }
+
+    /// Record an operation's outcome. Only transient errors count against the device.
+    pub fn record<T>(&self, result: &Result<T>) -> Option<u32> {
+        match result {
+            Ok(_) => {
+                self.succeeded();
+                None
+            }
+            Err(e) if is_transient(e) => Some(self.failed(e)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn reset_after_failures(&self) {
+        self.with_counters(|c| c.resets += 1);
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        self.status_at(Instant::now())
+    }
+
+    pub fn status_at(&self, now: Instant) -> HealthStatus {
+        self.with_counters(|c| {
+            let failed = c.recent.iter().filter(|f| **f).count();
+            HealthStatus {
+                consecutive_failures: i32::try_from(c.consecutive_failures).unwrap_or(i32::MAX),
+                error_rate: if c.recent.is_empty() {
+                    0.0
+                } else {
+                    failed as f64 / c.recent.len() as f64
+                },
+                last_success_ms: c
+                    .last_success
+                    .map(|i| now.saturating_duration_since(i).as_secs_f64() * 1000.0),
+                last_error: c.last_error.clone(),
+                resets: i32::try_from(c.resets).unwrap_or(i32::MAX),
+                successes: c.successes as f64,
+                failures: c.failures as f64,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_fit_within_the_timeout() {
+        let timeout = Duration::from_secs(2);
+        assert_eq!(
+            RetryPolicy::default().within(timeout),
+            RetryPolicy::default()
+        );
+
+        // 100 + 200 + 400 + 800 + 1600ms of backoff would outlast the timeout
+        let policy = RetryPolicy {
+            attempts: 6,
+            backoff: Duration::from_millis(100),
+            reset_after: 5,
+        };
+        let within = policy.within(timeout);
+        assert_eq!(within.attempts, 4);
+        assert_eq!(within.total_backoff(), Duration::from_millis(700));
+
+        let policy = RetryPolicy {
+            backoff: Duration::from_secs(5),
+            ..policy
+        };
+        assert_eq!(policy.within(timeout).attempts, 1);
+    }
+
+    #[test]
+    fn counts_failures_until_a_success() {
+        let health = DeviceHealth::new();
+        let nack = || Err::<(), _>(Error::IoError("nack".to_string()));
+        assert_eq!(health.record(&nack()), Some(1));
+        assert_eq!(health.record(&nack()), Some(2));
+        let status = health.status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.error_rate, 1.0);
+        assert_eq!(status.last_success_ms, None);
+        assert!(status.last_error.is_some());
+
+        assert_eq!(health.record(&Ok(())), None);
+        let status = health.status();
+        assert_eq!(status.consecutive_failures, 0);
+        assert!((status.error_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert!(status.last_success_ms.is_some());
+        assert_eq!(status.failures, 2.0);
+        assert_eq!(status.successes, 1.0);
+    }
+
+    #[test]
+    fn request_mistakes_are_not_device_failures() {
+        let health = DeviceHealth::new();
+        assert_eq!(health.record::<()>(&Err(Error::OutOfBounds(3))), None);
+        assert_eq!(health.status().failures, 0.0);
+        assert_eq!(health.status().error_rate, 0.0);
+    }
+
+    #[test]
+    fn error_rate_covers_recent_operations() {
+        let health = DeviceHealth::new();
+        for _ in 0..RECENT_OPERATIONS {
+            health.failed(&Error::IoError("nack".to_string()));
+        }
+        for _ in 0..RECENT_OPERATIONS / 2 {
+            health.succeeded();
+        }
+        assert_eq!(health.status().error_rate, 0.5);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy {
+            attempts: 4,
+            backoff: Duration::from_millis(10),
+            reset_after: 0,
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,169 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use super::util::{iv2be, uv2be};
+use super::{I2cAddress, to_i2c_addr};
+use crate::app::device::{self, ADS1x15, AdcChip, AdcGain, AdcMux};
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+const CONVERSION_REGISTER: u8 = 0x00;
+const CONFIG_REGISTER: u8 = 0x01;
+
+/// OS: writing starts a conversion, reading is set once the chip is idle again
+const CONFIG_OS: u16 = 0x8000;
+/// MODE: convert once, then power down
+const CONFIG_SINGLE_SHOT: u16 = 0x0100;
+/// COMP_QUE: comparator disabled, ALERT/RDY left high-impedance
+const CONFIG_COMPARATOR_OFF: u16 = 0x0003;
+
+/// How many sample periods to wait for a conversion before giving up
+const CONVERSION_POLLS: usize = 4;
+
+/// Data rates in samples per second, indexed by their DR bits
+const ADS1015_RATES: [i32; 7] = [128, 250, 490, 920, 1600, 2400, 3300];
+const ADS1115_RATES: [i32; 8] = [8, 16, 32, 64, 128, 250, 475, 860];
+
+fn mux_bits(mux: AdcMux) -> u16 {
+    let bits = match mux {
+        AdcMux::Diff0To1 => 0b000,
+        AdcMux::Diff0To3 => 0b001,
+        AdcMux::Diff1To3 => 0b010,
+        AdcMux::Diff2To3 => 0b011,
+        AdcMux::Single0 => 0b100,
+        AdcMux::Single1 => 0b101,
+        AdcMux::Single2 => 0b110,
+        AdcMux::Single3 => 0b111,
+    };
+    bits << 12
+}
+
+fn gain_bits(gain: AdcGain) -> u16 {
+    let bits = match gain {
+        AdcGain::TwoThirds => 0b000,
+        AdcGain::One => 0b001,
+        AdcGain::Two => 0b010,
+        AdcGain::Four => 0b011,
+        AdcGain::Eight => 0b100,
+        AdcGain::Sixteen => 0b101,
+    };
+    bits << 9
+}
+
+/// The voltage of a full-scale reading at `gain`
+pub fn full_scale_volts(gain: AdcGain) -> f64 {
+    match gain {
+        AdcGain::TwoThirds => 6.144,
+        AdcGain::One => 4.096,
+        AdcGain::Two => 2.048,
+        AdcGain::Four => 1.024,
+        AdcGain::Eight => 0.512,
+        AdcGain::Sixteen => 0.256,
+    }
+}
+
+fn rate_bits(chip: AdcChip, data_rate: i32) -> Result<u16> {
+    let rates: &[i32] = match chip {
+        AdcChip::ADS1015 => &ADS1015_RATES,
+        AdcChip::ADS1115 => &ADS1115_RATES,
+    };
+    match rates.iter().position(|r| *r == data_rate) {
+        Some(bits) => Ok((bits as u16) << 5),
+        None => Err(Error::Config(format!(
+            "the {:?} can't sample at {} per second; it supports {:?}",
+            chip, data_rate, rates
+        ))),
+    }
+}
+
+/// Check that `config` is something the chip can do
+pub fn validate(config: &ADS1x15) -> Result<()> {
+    rate_bits(config.chip, config.data_rate)?;
+    Ok(())
+}
+
+/// Convert the conversion register to volts. The ADS1015 left-aligns its 12 bits.
+pub fn to_volts(chip: AdcChip, gain: AdcGain, raw: i16) -> f64 {
+    let (counts, full_scale_counts) = match chip {
+        AdcChip::ADS1015 => ((raw >> 4) as f64, 2048.0),
+        AdcChip::ADS1115 => (raw as f64, 32768.0),
+    };
+    counts * full_scale_volts(gain) / full_scale_counts
+}
+
+/// ADS1015 / ADS1115
+/// 12/16-bit 4 channel ADC with programmable gain
+/// https://www.ti.com/lit/ds/symlink/ads1115.pdf
+///
+/// Runs a single-shot conversion of `mux` and returns the result in volts.
+pub async fn read_volts(
+    rapi: &RpiApi,
+    address: I2cAddress,
+    config: &ADS1x15,
+    mux: AdcMux,
+) -> Result<f64> {
+    let word = CONFIG_OS
+        | mux_bits(mux)
+        | gain_bits(config.gain)
+        | CONFIG_SINGLE_SHOT
+        | rate_bits(config.chip, config.data_rate)?
+        | CONFIG_COMPARATOR_OFF;
+    rapi.write_i2c(address, CONFIG_REGISTER, word.to_be_bytes().to_vec())
+        .await?;
+
+    // one sample period, plus 10% for the internal oscillator's tolerance
+    let period = Duration::from_micros(1_100_000 / config.data_rate as u64);
+    for _ in 0..CONVERSION_POLLS {
+        tokio::time::sleep(period).await;
+        let status = rapi.read_i2c(address, CONFIG_REGISTER, 2).await?;
+        if uv2be(&status) & CONFIG_OS != 0 {
+            let raw = rapi.read_i2c(address, CONVERSION_REGISTER, 2).await?;
+            return Ok(to_volts(config.chip, config.gain, iv2be(&raw)));
+        }
+    }
+    Err(Error::DeviceReadError(
+        "ADS1x15 conversion did not finish".to_string(),
+    ))
+}
+
+#[derive(Debug)]
+struct Ads1x15 {
+    config: ADS1x15,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::ADS1x15(config) => Ok(Box::new(Ads1x15 {
+            config: *config,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("ADS1x15", model)),
+    }
+}
+
+impl Driver for Ads1x15 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![driver::input_slot(Unit::Volts); 4]
+    }
+
+    // conversions are single-shot, so there is nothing to set up beforehand
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { validate(&self.config) })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            let mux = self
+                .config
+                .channels
+                .get(index as usize)
+                .ok_or(Error::OutOfBounds(index as usize))?;
+            let volts = read_volts(&self.rapi, addr, &self.config, mux).await?;
+            Ok(Dimensioned::from_volts(volts))
+        })
+    }
+}
@@ -1,9 +1,12 @@
 use super::super::RpiApi;
-use super::I2cAddress;
+use super::super::driver::{self, Driver};
 use super::util::{iv2be, uv2be};
-use crate::app::device::SamplingMode;
-use crate::error::Result;
-use std::thread;
+use super::{I2cAddress, to_i2c_addr};
+use crate::app::device::{self, SamplingMode};
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
 use std::time::Duration;
 
 /// How long should we accumulate before returning a result?
@@ -147,7 +150,7 @@ impl Bmp085State {
             vec![Control::ReadTemp as u8],
         )
         .await?;
-        thread::sleep(Duration::from_millis(5)); // sleep for 4.5 ms
+        tokio::time::sleep(Duration::from_millis(5)).await; // sleep for 4.5 ms
         let data = rapi.read_i2c(address, Register::Data as u8, 2).await?;
 
         let ut: i32 = iv2be(&data) as i32;
@@ -193,7 +196,7 @@ impl Bmp085State {
             SamplingMode::UltraHighRes => Duration::from_millis(26),
         };
 
-        thread::sleep(duration);
+        tokio::time::sleep(duration).await;
 
         let msbv = rapi.read_i2c(address, Register::Data as u8, 1).await?;
         let lsbv = rapi.read_i2c(address, Register::Data as u8 + 1, 1).await?;
@@ -207,3 +210,57 @@ impl Bmp085State {
         Ok(up)
     }
 }
+
+#[derive(Debug)]
+struct Bmp085 {
+    config: device::BMP085,
+    state: Bmp085State,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::BMP085(config) => Ok(Box::new(Bmp085 {
+            config: *config,
+            state: Bmp085State::new(),
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("BMP085", model)),
+    }
+}
+
+impl Driver for Bmp085 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![
+            driver::input_slot(Unit::DegC),
+            driver::input_slot(Unit::KPa),
+        ]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            self.state.reset(addr, &self.rapi).await
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            match index {
+                0 => {
+                    let v = self.state.temperature_in_c(addr, &self.rapi).await?;
+                    Ok(Dimensioned::from_degc(v.into()))
+                }
+                1 => {
+                    let v = self
+                        .state
+                        .pressure_kpa(addr, self.config.mode, &self.rapi)
+                        .await?;
+                    Ok(Dimensioned::from_kpa(v.into()))
+                }
+                _ => Err(Error::OutOfBounds(index as usize)),
+            }
+        })
+    }
+}
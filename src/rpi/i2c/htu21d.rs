@@ -0,0 +1,143 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr, util::checked_words};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// Measurements holding the bus with clock stretching until they're done
+const TEMPERATURE_HOLD: u8 = 0xE3;
+const HUMIDITY_HOLD: u8 = 0xE5;
+/// Measurements that are read once they're done
+const TEMPERATURE_NO_HOLD: u8 = 0xF3;
+const HUMIDITY_NO_HOLD: u8 = 0xF5;
+
+const WRITE_USER_REGISTER: u8 = 0xE6;
+const READ_USER_REGISTER: u8 = 0xE7;
+const SOFT_RESET: u8 = 0xFE;
+
+/// User register bit that turns on the on-chip heater
+const USER_HEATER: u8 = 0x04;
+
+/// Worst case conversion times at the default 14 bit temperature and 12 bit humidity resolution
+const TEMPERATURE_TIME: Duration = Duration::from_millis(50);
+const HUMIDITY_TIME: Duration = Duration::from_millis(16);
+const RESET_TIME: Duration = Duration::from_millis(15);
+
+const CRC_INIT: u8 = 0x00;
+
+/// The low two bits of a measurement are status, not data
+const STATUS_BITS: u16 = 0x0003;
+
+/// HTU21D (also SHT21 and Si7021)
+/// Digital relative humidity sensor with temperature output, ±2%RH, ±0.3°C
+/// https://cdn-shop.adafruit.com/datasheets/1899_HTU21D.pdf
+///
+/// Reset the sensor and switch its heater on or off
+pub async fn reset(rapi: &RpiApi, address: I2cAddress, heater: bool) -> Result<()> {
+    rapi.write_i2c(address, SOFT_RESET, vec![]).await?;
+    tokio::time::sleep(RESET_TIME).await;
+    let user = rapi.read_i2c(address, READ_USER_REGISTER, 1).await?;
+    let user = match user.first() {
+        Some(user) if heater => user | USER_HEATER,
+        Some(user) => user & !USER_HEATER,
+        None => return Err(Error::DeviceReadError("HTU21D user register".to_string())),
+    };
+    rapi.write_i2c(address, WRITE_USER_REGISTER, vec![user])
+        .await
+}
+
+async fn measure(
+    rapi: &RpiApi,
+    address: I2cAddress,
+    (hold, no_hold, time): (u8, u8, Duration),
+    clock_stretching: bool,
+) -> Result<f64> {
+    let data = if clock_stretching {
+        rapi.read_i2c(address, hold, 3).await?
+    } else {
+        rapi.write_i2c(address, no_hold, vec![]).await?;
+        tokio::time::sleep(time).await;
+        rapi.read_i2c_raw(address, 3).await?
+    };
+    match checked_words(CRC_INIT, &data).as_deref() {
+        Some(&[word]) => Ok((word & !STATUS_BITS) as f64 / 65536.0),
+        _ => Err(Error::DeviceReadError(format!(
+            "HTU21D at 0x{:02x} failed its CRC check: {:02x?}",
+            address, data
+        ))),
+    }
+}
+
+/// Temperature in °C
+pub async fn read_temperature(
+    rapi: &RpiApi,
+    address: I2cAddress,
+    clock_stretching: bool,
+) -> Result<f64> {
+    let command = (TEMPERATURE_HOLD, TEMPERATURE_NO_HOLD, TEMPERATURE_TIME);
+    let fraction = measure(rapi, address, command, clock_stretching).await?;
+    Ok(-46.85 + 175.72 * fraction)
+}
+
+/// Relative humidity in %RH. The conversion can stray just outside 0 to 100 near saturation.
+pub async fn read_humidity(
+    rapi: &RpiApi,
+    address: I2cAddress,
+    clock_stretching: bool,
+) -> Result<f64> {
+    let command = (HUMIDITY_HOLD, HUMIDITY_NO_HOLD, HUMIDITY_TIME);
+    let fraction = measure(rapi, address, command, clock_stretching).await?;
+    Ok((-6.0 + 125.0 * fraction).clamp(0.0, 100.0))
+}
+
+#[derive(Debug)]
+struct Htu21d {
+    config: device::HTU21D,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::HTU21D(config) => Ok(Box::new(Htu21d {
+            config: *config,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("HTU21D", model)),
+    }
+}
+
+impl Driver for Htu21d {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![
+            driver::input_slot(Unit::DegC),
+            driver::input_slot(Unit::RelHumidity),
+        ]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            reset(&self.rapi, addr, self.config.heater).await
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            let stretch = self.config.clock_stretching;
+            match index {
+                0 => Ok(Dimensioned::from_degc(
+                    read_temperature(&self.rapi, addr, stretch).await?,
+                )),
+                1 => Ok(Dimensioned::from_rel_humidity(
+                    read_humidity(&self.rapi, addr, stretch).await?,
+                )),
+                _ => Err(Error::OutOfBounds(index as usize)),
+            }
+        })
+    }
+}
@@ -0,0 +1,185 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+
+const CONFIG: u8 = 0x00;
+const BUS_VOLTAGE: u8 = 0x02;
+const POWER: u8 = 0x03;
+const CURRENT: u8 = 0x04;
+const CALIBRATION: u8 = 0x05;
+
+/// CONFIG: 32V bus range, 12 bit bus and shunt conversions, both measured continuously. The shunt
+/// gain (PGA) is chosen to suit the shunt.
+const CONFIG_BUS_32V: u16 = 1 << 13;
+const CONFIG_PGA_SHIFT: u16 = 11;
+const CONFIG_BADC_12BIT: u16 = 0x3 << 7;
+const CONFIG_SADC_12BIT: u16 = 0x3 << 3;
+const CONFIG_SHUNT_AND_BUS_CONTINUOUS: u16 = 0x7;
+
+/// Full scale shunt voltage for each PGA setting
+const PGA_RANGES: [f64; 4] = [0.04, 0.08, 0.16, 0.32];
+
+/// Bus voltage LSB, and the flag set when power or current overflowed
+const BUS_VOLTS_PER_BIT: f64 = 0.004;
+const BUS_OVERFLOW: u16 = 0x0001;
+
+/// From the datasheet's calibration equation: CAL = trunc(0.04096 / (current LSB * shunt ohms))
+const CALIBRATION_SCALE: f64 = 0.04096;
+/// The power register's LSB is 20 times the current LSB
+const POWER_LSB_FACTOR: f64 = 20.0;
+
+/// Register values, and the current LSB they give, for a shunt and the current it's sized for
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Calibration {
+    pub config: u16,
+    pub calibration: u16,
+    pub amps_per_bit: f64,
+}
+
+/// INA219
+/// Zero-drift, bidirectional current/power monitor with I2C interface
+/// https://www.ti.com/lit/ds/symlink/ina219.pdf
+///
+/// Work out the registers for a shunt. The current resolution is the maximum current over 2^15.
+pub fn calibration(config: &device::INA219) -> Result<Calibration> {
+    if !(config.shunt_ohms > 0.0 && config.max_current > 0.0) {
+        return Err(Error::Config(
+            "INA219 shunt resistance and maximum current must be positive".to_string(),
+        ));
+    }
+    let max_shunt_volts = config.shunt_ohms * config.max_current;
+    let pga = PGA_RANGES
+        .iter()
+        // 0.1Ω at 3.2A should fit 0.32V, whatever floating point makes of the product
+        .position(|range| max_shunt_volts <= range * (1.0 + f64::EPSILON * 4.0))
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "{}A through {}Ω is {}V across the shunt, over the INA219's 0.32V",
+                config.max_current, config.shunt_ohms, max_shunt_volts
+            ))
+        })? as u16;
+    let amps_per_bit = config.max_current / 32768.0;
+    let calibration = (CALIBRATION_SCALE / (amps_per_bit * config.shunt_ohms)).trunc();
+    if !(1.0..=65534.0).contains(&calibration) {
+        return Err(Error::Config(format!(
+            "an INA219 can't be calibrated for {}A through {}Ω",
+            config.max_current, config.shunt_ohms
+        )));
+    }
+    Ok(Calibration {
+        config: CONFIG_BUS_32V
+            | (pga << CONFIG_PGA_SHIFT)
+            | CONFIG_BADC_12BIT
+            | CONFIG_SADC_12BIT
+            | CONFIG_SHUNT_AND_BUS_CONTINUOUS,
+        // bit 0 of the register isn't used
+        calibration: calibration as u16 & !1,
+        amps_per_bit,
+    })
+}
+
+/// Configure the chip and write its calibration, which it forgets on power loss
+pub async fn reset(rapi: &RpiApi, address: I2cAddress, config: &device::INA219) -> Result<()> {
+    let cal = calibration(config)?;
+    rapi.write_i2c(address, CONFIG, cal.config.to_be_bytes().to_vec())
+        .await?;
+    rapi.write_i2c(address, CALIBRATION, cal.calibration.to_be_bytes().to_vec())
+        .await
+}
+
+async fn read_register(rapi: &RpiApi, address: I2cAddress, register: u8) -> Result<u16> {
+    match rapi.read_i2c(address, register, 2).await?.as_slice() {
+        &[hi, lo] => Ok(u16::from_be_bytes([hi, lo])),
+        other => Err(Error::DeviceReadError(format!(
+            "INA219 register 0x{:02x} read {} bytes",
+            register,
+            other.len()
+        ))),
+    }
+}
+
+/// Voltage on the bus side of the shunt, relative to ground
+pub async fn read_bus_volts(rapi: &RpiApi, address: I2cAddress) -> Result<f64> {
+    let raw = read_register(rapi, address, BUS_VOLTAGE).await?;
+    if raw & BUS_OVERFLOW != 0 {
+        return Err(Error::DeviceReadError(format!(
+            "INA219 at 0x{:02x} overflowed; is the shunt sized for the current?",
+            address
+        )));
+    }
+    Ok((raw >> 3) as f64 * BUS_VOLTS_PER_BIT)
+}
+
+/// Current through the shunt, negative when it flows backwards
+pub async fn read_amps(rapi: &RpiApi, address: I2cAddress, config: &device::INA219) -> Result<f64> {
+    let cal = calibration(config)?;
+    let raw = read_register(rapi, address, CURRENT).await? as i16;
+    Ok(raw as f64 * cal.amps_per_bit)
+}
+
+/// Power delivered to the load, from the bus voltage and the size of the current
+pub async fn read_watts(
+    rapi: &RpiApi,
+    address: I2cAddress,
+    config: &device::INA219,
+) -> Result<f64> {
+    let cal = calibration(config)?;
+    let raw = read_register(rapi, address, POWER).await?;
+    Ok(raw as f64 * POWER_LSB_FACTOR * cal.amps_per_bit)
+}
+
+#[derive(Debug)]
+struct Ina219 {
+    config: device::INA219,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::INA219(config) => Ok(Box::new(Ina219 {
+            config: *config,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("INA219", model)),
+    }
+}
+
+impl Driver for Ina219 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![
+            driver::input_slot(Unit::Volts),
+            driver::input_slot(Unit::Amps),
+            driver::input_slot(Unit::Watts),
+        ]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            reset(&self.rapi, addr, &self.config).await
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            match index {
+                0 => Ok(Dimensioned::from_volts(
+                    read_bus_volts(&self.rapi, addr).await?,
+                )),
+                1 => Ok(Dimensioned::from_amps(
+                    read_amps(&self.rapi, addr, &self.config).await?,
+                )),
+                2 => Ok(Dimensioned::from_watts(
+                    read_watts(&self.rapi, addr, &self.config).await?,
+                )),
+                _ => Err(Error::OutOfBounds(index as usize)),
+            }
+        })
+    }
+}
@@ -1,8 +1,12 @@
 use super::super::RpiApi;
-use super::I2cAddress;
-use crate::app::device::{Dir, Directions};
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr};
+use crate::app::device::{self, Dir, Directions};
+use crate::app::dimensioned::Dimensioned;
 use crate::error::{Error, Result};
 use bit_array::BitArray;
+use futures::future::BoxFuture;
+use std::sync::Mutex;
 use tracing::debug;
 
 type Bits = BitArray<u32, typenum::U8>;
@@ -11,13 +15,36 @@ const DIRECTION_A: u8 = 0x00;
 const DIRECTION_B: u8 = 0x01;
 const IN_POLARITY_A: u8 = 0x02;
 const IN_POLARITY_B: u8 = 0x03;
+const INT_ENABLE_A: u8 = 0x04;
+const INT_ENABLE_B: u8 = 0x05;
+const INT_DEFAULT_A: u8 = 0x06;
+const INT_DEFAULT_B: u8 = 0x07;
+const INT_CONTROL_A: u8 = 0x08;
+const INT_CONTROL_B: u8 = 0x09;
+const IO_CONFIG: u8 = 0x0A;
 const PULLUP_A: u8 = 0x0C;
 const PULLUP_B: u8 = 0x0D;
+const INT_FLAG_A: u8 = 0x0E;
+const INT_FLAG_B: u8 = 0x0F;
+const INT_CAPTURE_A: u8 = 0x10;
+const INT_CAPTURE_B: u8 = 0x11;
 const READ_A: u8 = 0x12;
 const READ_B: u8 = 0x13;
 const WRITE_A: u8 = 0x14;
 const WRITE_B: u8 = 0x15;
 
+/// IOCON MIRROR: INTA and INTB both fire for changes on either bank, so one GPIO line will do
+const IO_CONFIG_MIRROR: u8 = 0x40;
+
+/// An input that changed, as captured by the chip when it raised an interrupt, or seen by a
+/// read that cleared the interrupt first
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PinChange {
+    /// Device-wide pin index, 0-7 for bank A and 8-15 for bank B
+    pub index: usize,
+    pub value: bool,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub enum Pin {
     Pin0,
@@ -68,7 +95,7 @@ pub fn ordinal_to_pin(p: usize) -> Pin {
 pub fn bank_pin_to_index(bank: Bank, pin: Pin) -> usize {
     match bank {
         Bank::A => pin_to_ordinal(pin),
-        Bank::B => pin_to_ordinal(pin) * 2,
+        Bank::B => pin_to_ordinal(pin) + 8,
     }
 }
 
@@ -128,35 +155,35 @@ impl State {
         as_word(&self.values)
     }
 
-    pub fn pullup_word(&self) -> u8 {
+    /// Bits set for each pin whose direction has `flag`
+    fn word_where(&self, flag: impl Fn(Dir) -> bool) -> u8 {
         let mut ba = Bits::new();
-        for (dex, pinord) in (0..7usize).enumerate() {
-            let dir = self.direction.get(pinord);
-            if let Dir::InWithPD = dir {
-                ba.set(7 - dex, true);
+        for pinord in 0..8usize {
+            if flag(*self.direction.get(pinord)) {
+                ba.set(7 - pinord, true);
             }
         }
         as_word(&ba)
     }
 
+    /// GPPU: pins with the internal pull-up enabled
+    pub fn pullup_word(&self) -> u8 {
+        self.word_where(Dir::pull_up)
+    }
+
+    /// IPOL: pins whose readings the chip inverts
     pub fn input_polarity_word(&self) -> u8 {
-        let mut ba = Bits::new();
-        ba.clear();
-        as_word(&ba)
+        self.word_where(Dir::inverted)
+    }
+
+    /// Pins that should raise an interrupt when they change; every input
+    pub fn interrupt_word(&self) -> u8 {
+        self.inout_word()
     }
 
+    /// IODIR: pins that are inputs
     pub fn inout_word(&self) -> u8 {
-        let mut ba = Bits::new();
-        for (dex, pinord) in (0..7usize).enumerate() {
-            let dir = self.direction.get(pinord);
-            if let Dir::InWithPD = dir {
-                ba.set(7 - dex, true);
-            };
-            if let Dir::In = dir {
-                ba.set(7 - dex, true);
-            };
-        }
-        as_word(&ba)
+        self.word_where(Dir::is_input)
     }
 }
 
@@ -269,9 +296,11 @@ impl Mcp23017State {
         let bank_state = self.mut_state_for_bank(bank);
         bank_state.direction = *directions;
         self.write_gpio_dir(address, bank, rapi).await?;
-        for p in 0..7 {
-            self.set_pin(address, bank, ordinal_to_pin(p), false, rapi)
-                .await?;
+        for p in 0..8 {
+            if !directions.get(p).is_input() {
+                self.set_pin(address, bank, ordinal_to_pin(p), false, rapi)
+                    .await?;
+            }
         }
         Ok(())
     }
@@ -311,8 +340,9 @@ impl Mcp23017State {
         rapi: &RpiApi,
     ) -> Result<()> {
         match self.get_pin_direction(bank, pin) {
-            Dir::In => Err(Error::InvalidPinDirection),
-            Dir::InWithPD => Err(Error::InvalidPinDirection),
+            Dir::In | Dir::InPullUp | Dir::InWithPD | Dir::InInverted | Dir::InPullUpInverted => {
+                Err(Error::InvalidPinDirection)
+            }
             Dir::OutH => {
                 debug!(
                     "set_pin: a:{} b:{:?} p:{:?} <- {}",
@@ -340,6 +370,62 @@ impl Mcp23017State {
         }
     }
 
+    /// Raise an interrupt whenever an input on either bank changes, on both INT lines
+    pub async fn enable_interrupts(&self, address: I2cAddress, rapi: &RpiApi) -> Result<()> {
+        rapi.write_i2c(address, IO_CONFIG, vec![IO_CONFIG_MIRROR])
+            .await?;
+        for bank in [Bank::A, Bank::B] {
+            let (enable_reg, default_reg, control_reg) = match bank {
+                Bank::A => (INT_ENABLE_A, INT_DEFAULT_A, INT_CONTROL_A),
+                Bank::B => (INT_ENABLE_B, INT_DEFAULT_B, INT_CONTROL_B),
+            };
+            // compare against the previous value rather than DEFVAL, so any change interrupts
+            rapi.write_i2c(address, control_reg, vec![0]).await?;
+            rapi.write_i2c(address, default_reg, vec![0]).await?;
+            rapi.write_i2c(
+                address,
+                enable_reg,
+                vec![self.state_for_bank(bank).interrupt_word()],
+            )
+            .await?;
+        }
+        // clear anything that was pending before we were listening
+        self.take_pin_changes(address, rapi).await?;
+        Ok(())
+    }
+
+    /// Read which inputs caused an interrupt and their captured values, which also clears it
+    pub async fn take_pin_changes(
+        &self,
+        address: I2cAddress,
+        rapi: &RpiApi,
+    ) -> Result<Vec<PinChange>> {
+        let mut changes = Vec::new();
+        for bank in [Bank::A, Bank::B] {
+            let (flag_reg, capture_reg) = match bank {
+                Bank::A => (INT_FLAG_A, INT_CAPTURE_A),
+                Bank::B => (INT_FLAG_B, INT_CAPTURE_B),
+            };
+            let flags = rapi.read_i2c(address, flag_reg, 1).await?;
+            let flags = flags.first().copied().unwrap_or(0);
+            if flags == 0 {
+                continue;
+            }
+            let captured = rapi.read_i2c(address, capture_reg, 1).await?;
+            let captured = captured.first().copied().unwrap_or(0);
+            for ordinal in 0..8usize {
+                if flags & (1 << ordinal) != 0 {
+                    changes.push(PinChange {
+                        index: bank_pin_to_index(bank, ordinal_to_pin(ordinal)),
+                        value: captured & (1 << ordinal) != 0,
+                    });
+                }
+            }
+        }
+        debug!("interrupt changes: {}: {:?}", address, changes);
+        Ok(changes)
+    }
+
     pub fn get_pin_direction(&self, bank: Bank, pin: Pin) -> Dir {
         self.state_for_bank(bank).get_direction(pin)
     }
@@ -355,14 +441,169 @@ impl Mcp23017State {
         match state.get_direction(pin) {
             Dir::OutL => Ok(!state.pin_value(pin)),
             Dir::OutH => Ok(state.pin_value(pin)),
-            Dir::In => {
+            // inverted inputs are inverted by the chip (IPOL), so read the same as any other
+            Dir::In | Dir::InPullUp | Dir::InWithPD | Dir::InInverted | Dir::InPullUpInverted => {
                 let value = self.read_gpio_value(address, bank, rapi).await?;
                 Ok(value[7 - pin_to_ordinal(pin)])
             }
-            Dir::InWithPD => {
-                let value = self.read_gpio_value(address, bank, rapi).await?;
-                Ok(value[7 - pin_to_ordinal(pin)])
+        }
+    }
+
+    /// Read a whole bank of inputs, as `(index, value)` for each input pin
+    pub async fn read_inputs(
+        &self,
+        address: I2cAddress,
+        bank: Bank,
+        rapi: &RpiApi,
+    ) -> Result<Vec<(usize, bool)>> {
+        let state = self.state_for_bank(bank);
+        let value = self.read_gpio_value(address, bank, rapi).await?;
+        Ok((0..8usize)
+            .map(ordinal_to_pin)
+            .filter(|pin| state.get_direction(*pin).is_input())
+            .map(|pin| (bank_pin_to_index(bank, pin), value[7 - pin_to_ordinal(pin)]))
+            .collect())
+    }
+}
+
+/// Input changes seen by polled reads, which clear the interrupt before it can be taken
+#[derive(Debug, Default)]
+struct Polled {
+    /// Last value seen for each input, by polling or interrupt
+    known: [Option<bool>; 16],
+    /// Changes to hand out with the next interrupt
+    pending: Vec<PinChange>,
+}
+
+impl Polled {
+    fn saw(&mut self, index: usize, value: bool) -> bool {
+        self.known[index]
+            .replace(value)
+            .is_some_and(|old| old != value)
+    }
+}
+
+#[derive(Debug)]
+struct Mcp23017 {
+    config: device::MCP23017,
+    state: Mcp23017State,
+    rapi: RpiApi,
+    polled: Mutex<Polled>,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::MCP23017(config) => Ok(Box::new(Mcp23017 {
+            config: *config,
+            state: Mcp23017State::new(),
+            rapi,
+            polled: Mutex::default(),
+        })),
+        _ => Err(driver::wrong_model("MCP23017", model)),
+    }
+}
+
+impl Mcp23017 {
+    async fn read_pin(&self, index: i32) -> Result<bool> {
+        let addr = to_i2c_addr(self.config.address)?;
+        let (bank, pin) = index_to_bank_pin(index as usize);
+        if self.interrupt_pin().is_none() || !self.state.get_pin_direction(bank, pin).is_input() {
+            return self.state.get_pin(addr, bank, pin, &self.rapi).await;
+        }
+        // reading GPIO clears the interrupt, so whatever changed on the bank has to be kept
+        // for the interrupt handler, which will find nothing flagged
+        let inputs = self.state.read_inputs(addr, bank, &self.rapi).await?;
+        let mut polled = self.polled();
+        for (changed, value) in &inputs {
+            if polled.saw(*changed, *value) {
+                polled.pending.push(PinChange {
+                    index: *changed,
+                    value: *value,
+                });
             }
         }
+        inputs
+            .into_iter()
+            .find_map(|(i, value)| (i == index as usize).then_some(value))
+            .ok_or(Error::OutOfBounds(index as usize))
+    }
+
+    fn polled(&self) -> std::sync::MutexGuard<'_, Polled> {
+        // only ever holds last seen values, so a poisoned lock is still usable
+        self.polled.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Driver for Mcp23017 {
+    fn slots(&self) -> Vec<device::Slot> {
+        [self.config.bank_a, self.config.bank_b]
+            .iter()
+            .flat_map(|bank| (0..8).map(move |pin| device::Slot::from_dir(*bank.get(pin))))
+            .collect()
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            *self.polled() = Polled::default();
+            self.state.reset(addr, &self.rapi).await?;
+            self.state
+                .set_pin_directions(addr, Bank::A, &self.config.bank_a, &self.rapi)
+                .await?;
+            self.state
+                .set_pin_directions(addr, Bank::B, &self.config.bank_b, &self.rapi)
+                .await?;
+            if self.interrupt_pin().is_some() {
+                self.state.enable_interrupts(addr, &self.rapi).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move { Ok(Dimensioned::from_bool(self.read_pin(index).await?)) })
+    }
+
+    fn read_boolean(&self, index: i32) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(self.read_pin(index))
+    }
+
+    fn write_boolean(&mut self, index: i32, value: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            let (bank, pin) = index_to_bank_pin(index as usize);
+            let old_dir = self.state.get_pin_direction(bank, pin);
+            let dir_bank = match bank {
+                Bank::A => self.config.bank_a,
+                Bank::B => self.config.bank_b,
+            };
+
+            if old_dir != *dir_bank.get(index as usize) {
+                self.state
+                    .set_pin_direction(addr, bank, pin, *dir_bank.get(index as usize), &self.rapi)
+                    .await?;
+            }
+            self.state.set_pin(addr, bank, pin, value, &self.rapi).await
+        })
+    }
+
+    fn interrupt_pin(&self) -> Option<u8> {
+        self.config
+            .interrupt_pin
+            .and_then(|pin| u8::try_from(pin).ok())
+    }
+
+    fn take_pin_changes(&self) -> BoxFuture<'_, Result<Vec<PinChange>>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            let taken = self.state.take_pin_changes(addr, &self.rapi).await?;
+            let mut polled = self.polled();
+            let mut changes = std::mem::take(&mut polled.pending);
+            for change in taken {
+                polled.saw(change.index, change.value);
+                changes.push(change);
+            }
+            Ok(changes)
+        })
     }
 }
@@ -1,6 +1,11 @@
 use super::super::RpiApi;
-use super::{I2cAddress, util::uv2be};
-use crate::error::Result;
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr, util::uv2be};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
 
 /// MCP 9808
 /// High-accuracy temperature Sensor -40°C to +125°C ±0.5°C
@@ -19,3 +24,42 @@ pub async fn read_temp(rapi: &RpiApi, address: I2cAddress) -> Result<f32> {
     let temp = sig_part * sign;
     Ok(temp)
 }
+
+#[derive(Debug)]
+struct Mcp9808 {
+    address: i32,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::MCP9808(device::MCP9808 { address }) => Ok(Box::new(Mcp9808 {
+            address: *address,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("MCP9808", model)),
+    }
+}
+
+impl Driver for Mcp9808 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![driver::input_slot(Unit::DegC)]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.address)?;
+            match index {
+                0 => {
+                    let temp = read_temp(&self.rapi, addr).await?;
+                    Ok(Dimensioned::from_degc(temp.into()))
+                }
+                _ => Err(Error::OutOfBounds(index as usize)),
+            }
+        })
+    }
+}
@@ -1,11 +1,26 @@
+pub mod ads1x15;
 pub mod bmp085;
+pub mod htu21d;
+pub mod ina219;
 pub mod mcp23017;
 pub mod mcp9808;
+pub mod pca9685;
+pub mod scan;
+pub mod sht31;
 pub mod util;
 
 pub type I2cAddress = u16;
 pub type I2cCommand = u8;
 
+/// Convert i32 address to u16, returning error if out of valid I2C range
+pub fn to_i2c_addr(address: i32) -> crate::error::Result<I2cAddress> {
+    if !(0..=0x7F).contains(&address) {
+        Err(crate::error::Error::OutOfBounds(address as usize))
+    } else {
+        Ok(address as I2cAddress)
+    }
+}
+
 #[cfg(all(test, feature = "mock-gpio"))]
 mod tests {
     use super::*;
@@ -279,6 +294,294 @@ mod tests {
         assert!(result.is_ok(), "UltraLowPower sampling mode should work");
     }
 
+    // ==================== ADS1x15 ADC Tests ====================
+
+    fn ads1115() -> crate::app::device::ADS1x15 {
+        use crate::app::device::{ADS1x15, AdcChannels, AdcChip, AdcGain};
+        ADS1x15 {
+            address: 0x48,
+            chip: AdcChip::ADS1115,
+            gain: AdcGain::One,
+            data_rate: 860,
+            channels: AdcChannels::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ads1115_single_ended_read() {
+        use crate::app::device::AdcMux;
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x48;
+        rpi_api
+            .set_i2c_register(address, 0x00, vec![0x40, 0x00])
+            .await;
+
+        let volts = ads1x15::read_volts(&rpi_api, address, &ads1115(), AdcMux::Single2)
+            .await
+            .unwrap();
+        assert!((volts - 2.048).abs() < 1e-9);
+
+        // OS, MUX=110, PGA=001, MODE=1, DR=111, comparator off
+        let config = rpi_api.read_i2c(address, 0x01, 2).await.unwrap();
+        assert_eq!(config, vec![0b1110_0011, 0b1110_0011]);
+    }
+
+    #[tokio::test]
+    async fn test_ads1015_differential_negative_read() {
+        use crate::app::device::{AdcChip, AdcGain, AdcMux};
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x48;
+        // -1024 counts, left aligned
+        rpi_api
+            .set_i2c_register(address, 0x00, vec![0xC0, 0x00])
+            .await;
+        let config = crate::app::device::ADS1x15 {
+            chip: AdcChip::ADS1015,
+            gain: AdcGain::Two,
+            data_rate: 3300,
+            ..ads1115()
+        };
+
+        let volts = ads1x15::read_volts(&rpi_api, address, &config, AdcMux::Diff0To1)
+            .await
+            .unwrap();
+        assert!((volts - -1.024).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ads1x15_rejects_unsupported_rates() {
+        use crate::app::device::AdcChip;
+        assert!(ads1x15::validate(&ads1115()).is_ok());
+        let config = crate::app::device::ADS1x15 {
+            chip: AdcChip::ADS1015,
+            ..ads1115()
+        };
+        assert!(ads1x15::validate(&config).is_err());
+    }
+
+    // ==================== PCA9685 PWM Tests ====================
+
+    #[test]
+    fn test_pca9685_prescale() {
+        assert_eq!(pca9685::prescale(50).unwrap(), 121);
+        assert_eq!(pca9685::prescale(1000).unwrap(), 5);
+        assert!(pca9685::prescale(10).is_err());
+        assert!(pca9685::prescale(2000).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pca9685_set_level_registers() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x40;
+        let mut state = pca9685::Pca9685State::new();
+        state.reset(address, 50, &rpi_api).await.unwrap();
+        let prescale = rpi_api.read_i2c(address, 0xFE, 1).await.unwrap();
+        assert_eq!(prescale, vec![121]);
+
+        // channel 3 starts at LED0_ON_L + 12
+        state.set_level(address, 3, 0.5, &rpi_api).await.unwrap();
+        let regs = rpi_api.read_i2c(address, 0x12, 4).await.unwrap();
+        assert_eq!(regs, vec![0x00, 0x00, 0x00, 0x08]);
+        assert_eq!(state.level(3).unwrap(), 0.5);
+
+        state.set_level(address, 3, 1.0, &rpi_api).await.unwrap();
+        let regs = rpi_api.read_i2c(address, 0x12, 4).await.unwrap();
+        assert_eq!(regs, vec![0x00, 0x10, 0x00, 0x00]);
+
+        state.set_level(address, 3, 0.0, &rpi_api).await.unwrap();
+        let regs = rpi_api.read_i2c(address, 0x12, 4).await.unwrap();
+        assert_eq!(regs, vec![0x00, 0x00, 0x00, 0x10]);
+    }
+
+    #[tokio::test]
+    async fn test_pca9685_rejects_bad_levels_and_channels() {
+        let rpi_api = create_mock_rpi();
+        let mut state = pca9685::Pca9685State::new();
+        assert!(state.set_level(0x40, 0, 1.5, &rpi_api).await.is_err());
+        assert!(state.set_level(0x40, 0, -0.1, &rpi_api).await.is_err());
+        assert!(state.set_level(0x40, 16, 0.5, &rpi_api).await.is_err());
+        assert_eq!(state.level(0).unwrap(), 0.0);
+    }
+
+    // ==================== SHT31 / HTU21D Humidity Sensor Tests ====================
+
+    /// A measurement word followed by its CRC
+    fn with_crc(init: u8, word: u16) -> Vec<u8> {
+        let [hi, lo] = word.to_be_bytes();
+        vec![hi, lo, util::crc8_31(init, &[hi, lo])]
+    }
+
+    #[tokio::test]
+    async fn test_sht31_read() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x44;
+        let mut data = with_crc(0xFF, 0x6666);
+        data.extend(with_crc(0xFF, 0x8000));
+        rpi_api.set_i2c_raw_read(address, data).await;
+
+        let reading = sht31::read(&rpi_api, address, true).await.unwrap();
+        assert!((reading.temperature - 25.0).abs() < 0.01);
+        assert!((reading.humidity - 50.0).abs() < 0.01);
+        // single shot, high repeatability, clock stretching
+        let command = rpi_api.read_i2c(address, 0x2C, 1).await.unwrap();
+        assert_eq!(command, vec![0x06]);
+    }
+
+    #[tokio::test]
+    async fn test_sht31_crc_failure_is_a_read_error() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x44;
+        let mut data = with_crc(0xFF, 0x6666);
+        data.extend(with_crc(0xFF, 0x8000));
+        data[4] ^= 0x01;
+        rpi_api.set_i2c_raw_read(address, data).await;
+
+        assert!(matches!(
+            sht31::read(&rpi_api, address, false).await,
+            Err(crate::error::Error::DeviceReadError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sht31_heater() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x45;
+        sht31::reset(&rpi_api, address, true).await.unwrap();
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x30, 1).await.unwrap(),
+            vec![0x6D]
+        );
+        sht31::reset(&rpi_api, address, false).await.unwrap();
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x30, 1).await.unwrap(),
+            vec![0x66]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_htu21d_hold_master_read() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x40;
+        // datasheet examples; the status bits are masked off
+        rpi_api
+            .set_i2c_register(address, 0xE3, with_crc(0x00, 0x683A))
+            .await;
+        rpi_api
+            .set_i2c_register(address, 0xE5, with_crc(0x00, 0x4E85))
+            .await;
+
+        let temp = htu21d::read_temperature(&rpi_api, address, true)
+            .await
+            .unwrap();
+        assert!((temp - 24.69).abs() < 0.01);
+        let rh = htu21d::read_humidity(&rpi_api, address, true)
+            .await
+            .unwrap();
+        assert!((rh - 32.34).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn test_htu21d_polled_read_checks_crc() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x40;
+        let mut data = with_crc(0x00, 0x683A);
+        data[2] ^= 0xFF;
+        rpi_api.set_i2c_raw_read(address, data).await;
+
+        assert!(matches!(
+            htu21d::read_temperature(&rpi_api, address, false).await,
+            Err(crate::error::Error::DeviceReadError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_htu21d_heater_keeps_other_user_bits() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x40;
+        rpi_api.set_i2c_register(address, 0xE7, vec![0x02]).await;
+        htu21d::reset(&rpi_api, address, true).await.unwrap();
+        assert_eq!(
+            rpi_api.read_i2c(address, 0xE6, 1).await.unwrap(),
+            vec![0x06]
+        );
+    }
+
+    // ==================== INA219 Power Monitor Tests ====================
+
+    fn ina219() -> crate::app::device::INA219 {
+        crate::app::device::INA219 {
+            address: 0x41,
+            shunt_ohms: 0.1,
+            max_current: 3.2,
+        }
+    }
+
+    #[test]
+    fn test_ina219_calibration() {
+        let cal = ina219::calibration(&ina219()).unwrap();
+        // the datasheet's 32V, 320mV, 12 bit default, and 4194 truncated to an even value
+        assert_eq!(cal.config, 0x399F);
+        assert_eq!(cal.calibration, 4194);
+
+        let low_current = crate::app::device::INA219 {
+            max_current: 0.4,
+            ..ina219()
+        };
+        // 40mV across the shunt fits the smallest range
+        assert_eq!(ina219::calibration(&low_current).unwrap().config, 0x219F);
+
+        let too_much = crate::app::device::INA219 {
+            max_current: 10.0,
+            ..ina219()
+        };
+        assert!(ina219::calibration(&too_much).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ina219_reads() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x41;
+        ina219::reset(&rpi_api, address, &ina219()).await.unwrap();
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x05, 2).await.unwrap(),
+            4194u16.to_be_bytes().to_vec()
+        );
+
+        // 12V, with the conversion ready flag set
+        rpi_api
+            .set_i2c_register(address, 0x02, vec![0x5D, 0xC2])
+            .await;
+        let volts = ina219::read_bus_volts(&rpi_api, address).await.unwrap();
+        assert!((volts - 12.0).abs() < 1e-9);
+
+        // -1024 counts is current flowing back into the battery
+        rpi_api
+            .set_i2c_register(address, 0x04, vec![0xFC, 0x00])
+            .await;
+        let amps = ina219::read_amps(&rpi_api, address, &ina219())
+            .await
+            .unwrap();
+        assert!((amps - -0.1).abs() < 1e-9);
+
+        rpi_api
+            .set_i2c_register(address, 0x03, vec![0x00, 100])
+            .await;
+        let watts = ina219::read_watts(&rpi_api, address, &ina219())
+            .await
+            .unwrap();
+        assert!((watts - 0.1953125).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_ina219_overflow_is_a_read_error() {
+        let rpi_api = create_mock_rpi();
+        rpi_api.set_i2c_register(0x41, 0x02, vec![0x5D, 0xC3]).await;
+        assert!(matches!(
+            ina219::read_bus_volts(&rpi_api, 0x41).await,
+            Err(crate::error::Error::DeviceReadError(_))
+        ));
+    }
+
     // ==================== MCP23017 GPIO Expander Tests ====================
 
     #[tokio::test]
@@ -614,6 +917,125 @@ mod tests {
         assert!(value, "OutL pin should return logical true");
     }
 
+    #[tokio::test]
+    async fn test_mcp23017_pullup_and_polarity_registers() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x20;
+
+        let mut state = mcp23017::Mcp23017State::new();
+        state.reset(address, &rpi_api).await.unwrap();
+        let directions = Directions {
+            p0: Dir::In,
+            p1: Dir::InPullUp,
+            p2: Dir::InInverted,
+            p3: Dir::InPullUpInverted,
+            p4: Dir::OutH,
+            p5: Dir::OutL,
+            p6: Dir::OutH,
+            p7: Dir::InPullUp,
+        };
+        state
+            .set_pin_directions(address, mcp23017::Bank::B, &directions, &rpi_api)
+            .await
+            .unwrap();
+
+        // IODIRB, IPOLB, GPPUB
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x01, 1).await.unwrap(),
+            vec![0b1000_1111]
+        );
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x03, 1).await.unwrap(),
+            vec![0b0000_1100]
+        );
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x0D, 1).await.unwrap(),
+            vec![0b1000_1010]
+        );
+        // OLATB: outputs are off, so the active low one is high
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x15, 1).await.unwrap(),
+            vec![0b0010_0000]
+        );
+    }
+
+    #[test]
+    fn test_dir_accepts_old_pulldown_name() {
+        let dir: Dir = serde_json::from_str("\"InWithPD\"").unwrap();
+        assert_eq!(dir, Dir::InPullUp);
+        assert!(dir.pull_up() && dir.is_input() && !dir.inverted());
+    }
+
+    #[tokio::test]
+    async fn test_mcp23017_enable_interrupts() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x20;
+
+        let mut state = mcp23017::Mcp23017State::new();
+        state.reset(address, &rpi_api).await.unwrap();
+        for pin in [mcp23017::Pin::Pin0, mcp23017::Pin::Pin2] {
+            state
+                .set_pin_direction(address, mcp23017::Bank::A, pin, Dir::In, &rpi_api)
+                .await
+                .unwrap();
+        }
+        state.enable_interrupts(address, &rpi_api).await.unwrap();
+
+        // IOCON mirrors both banks onto each INT line
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x0A, 1).await.unwrap(),
+            vec![0x40]
+        );
+        // GPINTENA: only the inputs interrupt
+        assert_eq!(
+            rpi_api.read_i2c(address, 0x04, 1).await.unwrap(),
+            vec![0b101]
+        );
+        assert_eq!(rpi_api.read_i2c(address, 0x05, 1).await.unwrap(), vec![0]);
+        // INTCONA: compare against the previous value
+        assert_eq!(rpi_api.read_i2c(address, 0x08, 1).await.unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_mcp23017_take_pin_changes() {
+        let rpi_api = create_mock_rpi();
+        let address: I2cAddress = 0x20;
+        let state = mcp23017::Mcp23017State::new();
+
+        assert!(
+            state
+                .take_pin_changes(address, &rpi_api)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // INTFA/INTCAPA: pin 1 went high, pin 3 went low; INTFB/INTCAPB: pin 2 went high
+        rpi_api.set_i2c_register(address, 0x0E, vec![0b1010]).await;
+        rpi_api.set_i2c_register(address, 0x10, vec![0b0010]).await;
+        rpi_api.set_i2c_register(address, 0x0F, vec![0b100]).await;
+        rpi_api.set_i2c_register(address, 0x11, vec![0b100]).await;
+
+        let changes = state.take_pin_changes(address, &rpi_api).await.unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                mcp23017::PinChange {
+                    index: 1,
+                    value: true
+                },
+                mcp23017::PinChange {
+                    index: 3,
+                    value: false
+                },
+                mcp23017::PinChange {
+                    index: 10,
+                    value: true
+                },
+            ]
+        );
+    }
+
     // ==================== Utility Function Tests ====================
 
     #[test]
@@ -638,12 +1060,59 @@ mod tests {
         assert_eq!(bank_pin_to_index(Bank::A, Pin::Pin0), 0);
         assert_eq!(bank_pin_to_index(Bank::A, Pin::Pin7), 7);
 
+        // Test bank B
+        assert_eq!(bank_pin_to_index(Bank::B, Pin::Pin0), 8);
+        assert_eq!(bank_pin_to_index(Bank::B, Pin::Pin7), 15);
+        assert_eq!(index_to_bank_pin(9), (Bank::B, Pin::Pin1));
+
         // Test round-trip for bank A
         let (bank, pin) = index_to_bank_pin(0);
         assert_eq!(bank, Bank::A);
         assert_eq!(pin, Pin::Pin0);
     }
 
+    // ==================== I2C Scan Tests ====================
+
+    #[tokio::test]
+    async fn test_scan_empty_bus() {
+        let rpi_api = create_mock_rpi();
+        assert_eq!(scan::scan(&rpi_api).await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_fingerprints_known_chips() {
+        let rpi_api = create_mock_rpi();
+        rpi_api.set_i2c_register(0x18, 0x06, vec![0x00, 0x54]).await;
+        rpi_api.set_i2c_register(0x77, 0xD0, vec![0x55]).await;
+        rpi_api.set_i2c_register(0x20, 0x00, vec![0xFF]).await;
+        // something we don't know, and an MCP9808 address answering with the wrong ID
+        rpi_api.set_i2c_register(0x40, 0x00, vec![0x00]).await;
+        rpi_api.set_i2c_register(0x19, 0x06, vec![0x12, 0x34]).await;
+
+        let found = scan::scan(&rpi_api).await.unwrap();
+        let chips: Vec<(i32, Option<&str>)> = found
+            .iter()
+            .map(|d| (d.address, d.chip.as_deref()))
+            .collect();
+        assert_eq!(
+            chips,
+            vec![
+                (0x18, Some("MCP9808")),
+                (0x19, None),
+                (0x20, Some("MCP23017")),
+                (0x40, None),
+                (0x77, Some("BMP085")),
+            ]
+        );
+        assert_eq!(
+            found[0].suggestion,
+            Some(crate::app::device::Type::MCP9808(
+                crate::app::device::MCP9808 { address: 0x18 }
+            ))
+        );
+        assert_eq!(found[1].suggestion, None);
+    }
+
     // ==================== Mock GPIO State Tests ====================
 
     #[tokio::test]
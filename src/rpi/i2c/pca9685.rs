@@ -0,0 +1,187 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+const MODE1: u8 = 0x00;
+const MODE2: u8 = 0x01;
+const LED0_ON_L: u8 = 0x06;
+const ALL_LED_ON_L: u8 = 0xFA;
+const PRESCALE: u8 = 0xFE;
+
+const MODE1_AUTO_INCREMENT: u8 = 0x20;
+const MODE1_SLEEP: u8 = 0x10;
+/// MODE2 OUTDRV: totem-pole outputs, which can drive LEDs and MOSFET gates directly
+const MODE2_TOTEM_POLE: u8 = 0x04;
+
+/// Bit 4 of LEDn_ON_H or LEDn_OFF_H holds a channel fully on or off
+const FULL: u8 = 0x10;
+
+pub const CHANNELS: usize = 16;
+const OSCILLATOR_HZ: f64 = 25_000_000.0;
+const STEPS: f64 = 4096.0;
+
+/// The PRESCALE value for a PWM frequency in Hz; the chip supports roughly 24Hz to 1526Hz
+pub fn prescale(frequency: i32) -> Result<u8> {
+    let prescale = (OSCILLATOR_HZ / (STEPS * frequency as f64)).round() - 1.0;
+    if (3.0..=255.0).contains(&prescale) {
+        Ok(prescale as u8)
+    } else {
+        Err(Error::Config(format!(
+            "the PCA9685 can't run its PWM at {}Hz",
+            frequency
+        )))
+    }
+}
+
+/// LEDn_ON_L, LEDn_ON_H, LEDn_OFF_L, LEDn_OFF_H for a duty cycle from 0 to 1
+fn channel_registers(level: f64) -> [u8; 4] {
+    if level <= 0.0 {
+        [0, 0, 0, FULL]
+    } else if level >= 1.0 {
+        [0, FULL, 0, 0]
+    } else {
+        let [hi, lo] = ((level * STEPS).round().clamp(1.0, STEPS - 1.0) as u16).to_be_bytes();
+        [0, 0, lo, hi]
+    }
+}
+
+/// PCA9685
+/// 16-channel, 12-bit PWM LED controller
+/// https://www.nxp.com/docs/en/data-sheet/PCA9685.pdf
+#[derive(Clone, Debug, Default)]
+pub struct Pca9685State {
+    /// What each channel was last set to, as the chip can't report a duty cycle back
+    levels: [f64; CHANNELS],
+}
+
+impl Pca9685State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the PWM frequency and turn every channel off
+    pub async fn reset(
+        &mut self,
+        address: I2cAddress,
+        frequency: i32,
+        rapi: &RpiApi,
+    ) -> Result<()> {
+        let prescale = prescale(frequency)?;
+        // the prescaler can only be set while the oscillator is asleep
+        rapi.write_i2c(address, MODE1, vec![MODE1_SLEEP | MODE1_AUTO_INCREMENT])
+            .await?;
+        rapi.write_i2c(address, PRESCALE, vec![prescale]).await?;
+        rapi.write_i2c(address, MODE2, vec![MODE2_TOTEM_POLE])
+            .await?;
+        rapi.write_i2c(address, ALL_LED_ON_L, channel_registers(0.0).to_vec())
+            .await?;
+        rapi.write_i2c(address, MODE1, vec![MODE1_AUTO_INCREMENT])
+            .await?;
+        // the oscillator takes up to 500us to start
+        tokio::time::sleep(Duration::from_micros(500)).await;
+        self.levels = [0.0; CHANNELS];
+        Ok(())
+    }
+
+    pub async fn set_level(
+        &mut self,
+        address: I2cAddress,
+        channel: usize,
+        level: f64,
+        rapi: &RpiApi,
+    ) -> Result<()> {
+        if channel >= CHANNELS {
+            return Err(Error::OutOfBounds(channel));
+        }
+        if !(0.0..=1.0).contains(&level) {
+            return Err(Error::Config(format!(
+                "level {} is not between 0 and 1",
+                level
+            )));
+        }
+        let register = LED0_ON_L + 4 * channel as u8;
+        rapi.write_i2c(address, register, channel_registers(level).to_vec())
+            .await?;
+        self.levels[channel] = level;
+        Ok(())
+    }
+
+    pub fn level(&self, channel: usize) -> Result<f64> {
+        self.levels
+            .get(channel)
+            .copied()
+            .ok_or(Error::OutOfBounds(channel))
+    }
+}
+
+#[derive(Debug)]
+struct Pca9685 {
+    config: device::PCA9685,
+    state: Pca9685State,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::PCA9685(config) => Ok(Box::new(Pca9685 {
+            config: *config,
+            state: Pca9685State::new(),
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("PCA9685", model)),
+    }
+}
+
+impl Driver for Pca9685 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![
+            device::Slot {
+                can_input: true,
+                can_output: true,
+                unit: Unit::Ratio,
+                direction: None,
+            };
+            CHANNELS
+        ]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            self.state
+                .reset(addr, self.config.frequency, &self.rapi)
+                .await
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move { Ok(Dimensioned::from_ratio(self.read_level(index)?)) })
+    }
+
+    fn read_boolean(&self, index: i32) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move { Ok(self.read_level(index)? > 0.0) })
+    }
+
+    fn write_boolean(&mut self, index: i32, value: bool) -> BoxFuture<'_, Result<()>> {
+        self.write_level(index, if value { 1.0 } else { 0.0 })
+    }
+
+    fn read_level(&self, index: i32) -> Result<f64> {
+        self.state.level(index as usize)
+    }
+
+    fn write_level(&mut self, index: i32, value: f64) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            self.state
+                .set_level(addr, index as usize, value, &self.rapi)
+                .await
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use super::super::RpiApi;
+use super::I2cAddress;
+use crate::app::device;
+use crate::error::Result;
+use juniper::GraphQLObject;
+
+/// Lowest and highest addresses that aren't reserved by the I2C specification
+const FIRST_ADDRESS: I2cAddress = 0x03;
+const LAST_ADDRESS: I2cAddress = 0x77;
+
+/// MCP9808 manufacturer ID register, and Microchip's ID
+const MCP9808_MANUFACTURER_REGISTER: u8 = 0x06;
+const MCP9808_MANUFACTURER_ID: [u8; 2] = [0x00, 0x54];
+
+/// BMP085 chip ID register, and the ID it reports
+const BMP085_CHIP_ID_REGISTER: u8 = 0xD0;
+const BMP085_CHIP_ID: u8 = 0x55;
+
+/// Something that answered on the bus
+#[derive(Clone, GraphQLObject, PartialEq, Debug)]
+pub struct Detected {
+    pub address: i32,
+
+    /// The chip we think this is, if we recognise it
+    pub chip: Option<String>,
+
+    /// A device that could be added for this address
+    pub suggestion: Option<device::Type>,
+}
+
+/// Work out which supported chip is at `address`, if any.
+///
+/// Chips with ID registers are identified by them. The MCP23017 has no ID register, so anything in
+/// its address range is assumed to be one.
+async fn fingerprint(rapi: &RpiApi, address: I2cAddress) -> Option<(&'static str, device::Type)> {
+    let addr = address as i32;
+    if (0x18..=0x1F).contains(&address)
+        && let Ok(id) = rapi
+            .read_i2c(address, MCP9808_MANUFACTURER_REGISTER, 2)
+            .await
+        && id == MCP9808_MANUFACTURER_ID
+    {
+        return Some((
+            "MCP9808",
+            device::Type::MCP9808(device::MCP9808 { address: addr }),
+        ));
+    }
+    if address == 0x77
+        && let Ok(id) = rapi.read_i2c(address, BMP085_CHIP_ID_REGISTER, 1).await
+        && id == [BMP085_CHIP_ID]
+    {
+        return Some((
+            "BMP085",
+            device::Type::BMP085(device::BMP085 {
+                address: addr,
+                mode: device::SamplingMode::Standard,
+            }),
+        ));
+    }
+    if (0x20..=0x27).contains(&address) {
+        return Some((
+            "MCP23017",
+            device::Type::MCP23017(device::MCP23017 {
+                address: addr,
+                bank_a: device::Directions::new(),
+                bank_b: device::Directions::new(),
+                interrupt_pin: None,
+            }),
+        ));
+    }
+    None
+}
+
+/// Probe every usable address on the bus, and suggest a device for each responder we recognise
+pub async fn scan(rapi: &RpiApi) -> Result<Vec<Detected>> {
+    let mut found = Vec::new();
+    for address in FIRST_ADDRESS..=LAST_ADDRESS {
+        if !rapi.probe_i2c(address).await? {
+            continue;
+        }
+        let (chip, suggestion) = match fingerprint(rapi, address).await {
+            Some((chip, suggestion)) => (Some(chip.to_string()), Some(suggestion)),
+            None => (None, None),
+        };
+        found.push(Detected {
+            address: address as i32,
+            chip,
+            suggestion,
+        });
+    }
+    Ok(found)
+}
@@ -0,0 +1,121 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use super::{I2cAddress, to_i2c_addr, util::checked_words};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// Commands are 16 bits, sent as a command byte and one parameter byte
+type Command = [u8; 2];
+
+const SOFT_RESET: Command = [0x30, 0xA2];
+const HEATER_ON: Command = [0x30, 0x6D];
+const HEATER_OFF: Command = [0x30, 0x66];
+
+/// Single shot, high repeatability, with and without clock stretching
+const MEASURE_STRETCHED: Command = [0x2C, 0x06];
+const MEASURE_POLLED: Command = [0x24, 0x00];
+
+/// A high repeatability measurement takes at most 15.5ms
+const MEASUREMENT_TIME: Duration = Duration::from_millis(16);
+const RESET_TIME: Duration = Duration::from_millis(2);
+
+const CRC_INIT: u8 = 0xFF;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Reading {
+    /// °C
+    pub temperature: f64,
+    /// %RH
+    pub humidity: f64,
+}
+
+async fn command(rapi: &RpiApi, address: I2cAddress, [command, parameter]: Command) -> Result<()> {
+    rapi.write_i2c(address, command, vec![parameter]).await
+}
+
+/// SHT31 (also SHT30 and SHT35)
+/// Humidity and temperature sensor, ±2%RH, ±0.3°C
+/// https://sensirion.com/media/documents/213E6A3B/63A5A569/Datasheet_SHT3x_DIS.pdf
+///
+/// Reset the sensor and switch its heater on or off. The heater warms the sensor by a few degrees
+/// to drive off condensation, so readings taken while it's on aren't ambient.
+pub async fn reset(rapi: &RpiApi, address: I2cAddress, heater: bool) -> Result<()> {
+    command(rapi, address, SOFT_RESET).await?;
+    tokio::time::sleep(RESET_TIME).await;
+    let heater_command = if heater { HEATER_ON } else { HEATER_OFF };
+    command(rapi, address, heater_command).await
+}
+
+/// Take a single measurement. With clock stretching the sensor holds the bus until it's done,
+/// otherwise we wait out the measurement time before reading; not every I2C controller copes with
+/// stretching, the Pi's included.
+pub async fn read(rapi: &RpiApi, address: I2cAddress, clock_stretching: bool) -> Result<Reading> {
+    if clock_stretching {
+        command(rapi, address, MEASURE_STRETCHED).await?;
+    } else {
+        command(rapi, address, MEASURE_POLLED).await?;
+        tokio::time::sleep(MEASUREMENT_TIME).await;
+    }
+    let data = rapi.read_i2c_raw(address, 6).await?;
+    match checked_words(CRC_INIT, &data).as_deref() {
+        Some(&[temperature, humidity]) => Ok(Reading {
+            temperature: -45.0 + 175.0 * temperature as f64 / 65535.0,
+            humidity: 100.0 * humidity as f64 / 65535.0,
+        }),
+        _ => Err(Error::DeviceReadError(format!(
+            "SHT31 at 0x{:02x} failed its CRC check: {:02x?}",
+            address, data
+        ))),
+    }
+}
+
+#[derive(Debug)]
+struct Sht31 {
+    config: device::SHT31,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::SHT31(config) => Ok(Box::new(Sht31 {
+            config: *config,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("SHT31", model)),
+    }
+}
+
+impl Driver for Sht31 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![
+            driver::input_slot(Unit::DegC),
+            driver::input_slot(Unit::RelHumidity),
+        ]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            reset(&self.rapi, addr, self.config.heater).await
+        })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let addr = to_i2c_addr(self.config.address)?;
+            if !(0..=1).contains(&index) {
+                return Err(Error::OutOfBounds(index as usize));
+            }
+            let reading = read(&self.rapi, addr, self.config.clock_stretching).await?;
+            Ok(if index == 0 {
+                Dimensioned::from_degc(reading.temperature)
+            } else {
+                Dimensioned::from_rel_humidity(reading.humidity)
+            })
+        })
+    }
+}
@@ -50,3 +50,35 @@ pub fn i2be(r: u16) -> i16 {
 pub fn u2be(r: u16) -> u16 {
     r.to_be()
 }
+
+/// CRC-8 with polynomial x^8 + x^5 + x^4 + 1 (0x31), MSB first, as Sensirion and
+/// Measurement Specialties humidity sensors check their words. The SHT3x starts from 0xff, the
+/// HTU21D from 0.
+///
+/// ```
+/// assert_eq!(librpi::rpi::i2c::util::crc8_31(0xff, &[0xbe, 0xef]), 0x92);
+/// assert_eq!(librpi::rpi::i2c::util::crc8_31(0x00, &[0x68, 0x3a]), 0x7c);
+/// ```
+pub fn crc8_31(init: u8, data: &[u8]) -> u8 {
+    data.iter().fold(init, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Check the CRC that follows each 2 byte word in `data`, returning the words
+pub fn checked_words(init: u8, data: &[u8]) -> Option<Vec<u16>> {
+    data.chunks(3)
+        .map(|chunk| match chunk {
+            [hi, lo, crc] if crc8_31(init, &[*hi, *lo]) == *crc => {
+                Some(u16::from_be_bytes([*hi, *lo]))
+            }
+            _ => None,
+        })
+        .collect()
+}
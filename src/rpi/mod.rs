@@ -5,13 +5,19 @@ use crate::error::Result;
 use rppal::gpio::{Gpio, InputPin, OutputPin};
 #[cfg(feature = "raspberrypi")]
 use rppal::i2c::I2c;
+#[cfg(feature = "raspberrypi")]
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
 #[cfg(any(feature = "raspberrypi", feature = "mock-gpio"))]
 use std::collections::HashMap;
+#[cfg(all(feature = "mock-gpio", not(feature = "raspberrypi")))]
+use std::collections::VecDeque;
 #[cfg(any(feature = "raspberrypi", feature = "mock-gpio"))]
 use std::sync::Arc;
 use std::vec::Vec;
 #[cfg(any(feature = "raspberrypi", feature = "mock-gpio"))]
 use tokio::sync::Mutex;
+#[cfg(any(feature = "raspberrypi", feature = "mock-gpio"))]
+use tokio::sync::mpsc;
 
 #[cfg(feature = "raspberrypi")]
 use tracing::debug;
@@ -20,7 +26,12 @@ use tracing::debug;
 use tracing::debug;
 
 pub mod device;
+pub mod driver;
+pub mod handle;
+pub mod health;
 pub mod i2c;
+pub mod spi;
+pub mod w1;
 
 /// GPIO level for non-raspberrypi builds
 #[cfg(not(feature = "raspberrypi"))]
@@ -99,6 +110,8 @@ struct RpiState {
     current_address: Option<i2c::I2cAddress>,
     gpio: Gpio,
     pins: HashMap<u8, GpioPin>,
+    /// SPI0, opened on first use of each chip select
+    spi: HashMap<u8, Spi>,
 }
 
 #[cfg(feature = "raspberrypi")]
@@ -107,6 +120,7 @@ impl std::fmt::Debug for RpiState {
         f.debug_struct("RpiState")
             .field("current_address", &self.current_address)
             .field("pins", &self.pins.keys().collect::<Vec<_>>())
+            .field("spi", &self.spi.keys().collect::<Vec<_>>())
             .finish_non_exhaustive()
     }
 }
@@ -125,6 +139,7 @@ impl RpiState {
             current_address: None,
             gpio,
             pins: HashMap::new(),
+            spi: HashMap::new(),
         })
     }
 
@@ -166,6 +181,58 @@ impl RpiState {
         Ok(buffer)
     }
 
+    fn i2c_read_raw(&mut self, address: i2c::I2cAddress, size: usize) -> Result<Vec<u8>> {
+        self.ensure_address(address)?;
+        let mut buffer = vec![0u8; size];
+        self.i2c
+            .read(&mut buffer)
+            .map_err(|e| crate::error::Error::I2cError(format!("I2C read failed: {}", e)))?;
+        debug!(
+            "i2c raw read: addr={}, size={}, result={:?}",
+            address, size, buffer
+        );
+        Ok(buffer)
+    }
+
+    fn spi_transfer(&mut self, chip_select: u8, clock_hz: u32, write: &[u8]) -> Result<Vec<u8>> {
+        let spi = match self.spi.entry(chip_select) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let slave_select = match chip_select {
+                    0 => SlaveSelect::Ss0,
+                    1 => SlaveSelect::Ss1,
+                    _ => {
+                        return Err(crate::error::Error::SpiError(format!(
+                            "SPI0 has no chip select {}",
+                            chip_select
+                        )));
+                    }
+                };
+                entry.insert(Spi::new(Bus::Spi0, slave_select, clock_hz, Mode::Mode0)?)
+            }
+        };
+        spi.set_clock_speed(clock_hz)?;
+        let mut read = vec![0u8; write.len()];
+        spi.transfer(&mut read, write)?;
+        debug!(
+            "spi transfer: cs={}, write={:?}, read={:?}",
+            chip_select, write, read
+        );
+        Ok(read)
+    }
+
+    /// Does anything acknowledge `address`? Like i2cdetect, we read a byte from ranges where
+    /// a quick write could corrupt EEPROMs, and use a quick write elsewhere.
+    fn i2c_probe(&mut self, address: i2c::I2cAddress) -> Result<bool> {
+        self.ensure_address(address)?;
+        let acked = match address {
+            0x30..=0x37 | 0x50..=0x5F => self.i2c.read(&mut [0u8; 1]).is_ok(),
+            _ => self.i2c.smbus_quick_command(false).is_ok(),
+        };
+        debug!("i2c probe: addr={}, acked={}", address, acked);
+        Ok(acked)
+    }
+
     fn gpio_read(&mut self, pin: u8) -> Result<rppal::gpio::Level> {
         // Check if we already have this pin configured
         if let Some(gpio_pin) = self.pins.get(&pin) {
@@ -205,6 +272,35 @@ impl RpiState {
         }
     }
 
+    /// Configure `pin` as a pulled-up input and report each falling edge on the returned channel
+    fn gpio_watch_falling(&mut self, pin: u8) -> Result<mpsc::UnboundedReceiver<()>> {
+        self.pins.remove(&pin);
+        let mut input_pin = self
+            .gpio
+            .get(pin)
+            .map_err(|e| {
+                crate::error::Error::DeviceReadError(format!(
+                    "Failed to get GPIO pin {}: {}",
+                    pin, e
+                ))
+            })?
+            .into_input_pullup();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        input_pin
+            .set_async_interrupt(rppal::gpio::Trigger::FallingEdge, None, move |_| {
+                let _ = sender.send(());
+            })
+            .map_err(|e| {
+                crate::error::Error::DeviceReadError(format!(
+                    "Failed to watch GPIO pin {}: {}",
+                    pin, e
+                ))
+            })?;
+        debug!("gpio watching pin {} for falling edges", pin);
+        self.pins.insert(pin, GpioPin::Input(input_pin));
+        Ok(receiver)
+    }
+
     fn gpio_write(&mut self, pin: u8, level: rppal::gpio::Level) -> Result<()> {
         // Check if we already have this pin configured as output
         if let Some(gpio_pin) = self.pins.get_mut(&pin) {
@@ -276,6 +372,14 @@ struct MockState {
     pins: HashMap<u8, MockPinState>,
     /// Mock I2C device registers: address -> (register -> value)
     i2c_devices: HashMap<u16, HashMap<u8, Vec<u8>>>,
+    /// What a read without a command returns, per address
+    i2c_raw_reads: HashMap<u16, Vec<u8>>,
+    /// Scripted responses to SPI transfers, in order, per chip select
+    spi_responses: HashMap<u8, VecDeque<Vec<u8>>>,
+    /// Every SPI transfer written, per chip select
+    spi_transfers: HashMap<u8, Vec<Vec<u8>>>,
+    /// Channels waiting for falling edges on a pin
+    gpio_watchers: HashMap<u8, Vec<mpsc::UnboundedSender<()>>>,
 }
 
 #[cfg(all(feature = "mock-gpio", not(feature = "raspberrypi")))]
@@ -326,6 +430,47 @@ impl MockState {
         let device = self.i2c_devices.entry(address).or_default();
         device.insert(register, data);
     }
+
+    fn i2c_read_raw(&self, address: u16, size: usize) -> Vec<u8> {
+        let mut result = self
+            .i2c_raw_reads
+            .get(&address)
+            .cloned()
+            .unwrap_or_default();
+        result.resize(size, 0);
+        result
+    }
+
+    /// Answer with the next scripted response, or zeros once they run out
+    fn spi_transfer(&mut self, chip_select: u8, write: &[u8]) -> Vec<u8> {
+        self.spi_transfers
+            .entry(chip_select)
+            .or_default()
+            .push(write.to_vec());
+        let mut read = self
+            .spi_responses
+            .get_mut(&chip_select)
+            .and_then(|responses| responses.pop_front())
+            .unwrap_or_default();
+        read.resize(write.len(), 0);
+        read
+    }
+
+    fn i2c_probe(&self, address: u16) -> bool {
+        self.i2c_devices.contains_key(&address)
+    }
+
+    fn watch_falling(&mut self, pin: u8) -> mpsc::UnboundedReceiver<()> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.gpio_watchers.entry(pin).or_default().push(sender);
+        receiver
+    }
+
+    fn fire_falling(&mut self, pin: u8) {
+        if let Some(watchers) = self.gpio_watchers.get_mut(&pin) {
+            watchers.retain(|w| w.send(()).is_ok());
+        }
+    }
 }
 
 // ============================================================================
@@ -379,6 +524,43 @@ impl RpiApi {
         }
     }
 
+    /// Read without writing a command first, for chips that are sent their command separately
+    pub async fn read_i2c_raw(&self, address: i2c::I2cAddress, size: usize) -> Result<Vec<u8>> {
+        let mut guard = self.state.lock().await;
+        match guard.as_mut() {
+            Some(rpi_state) => rpi_state.i2c_read_raw(address, size),
+            None => Err(crate::error::Error::I2cError(
+                "I2C bus not initialized".to_string(),
+            )),
+        }
+    }
+
+    /// Full duplex transfer on SPI0 in mode 0, returning as many bytes as were written
+    pub async fn transfer_spi(
+        &self,
+        chip_select: u8,
+        clock_hz: u32,
+        write: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut guard = self.state.lock().await;
+        match guard.as_mut() {
+            Some(rpi_state) => rpi_state.spi_transfer(chip_select, clock_hz, &write),
+            None => Err(crate::error::Error::SpiError(
+                "SPI bus not initialized".to_string(),
+            )),
+        }
+    }
+
+    pub async fn probe_i2c(&self, address: i2c::I2cAddress) -> Result<bool> {
+        let mut guard = self.state.lock().await;
+        match guard.as_mut() {
+            Some(rpi_state) => rpi_state.i2c_probe(address),
+            None => Err(crate::error::Error::I2cError(
+                "I2C bus not initialized".to_string(),
+            )),
+        }
+    }
+
     pub async fn read_gpio(&self, pin: u8) -> Result<rppal::gpio::Level> {
         let mut guard = self.state.lock().await;
         match guard.as_mut() {
@@ -398,6 +580,17 @@ impl RpiApi {
             )),
         }
     }
+
+    /// Receive a message for every falling edge on `pin`, such as an active-low interrupt line
+    pub async fn watch_gpio_falling(&self, pin: u8) -> Result<mpsc::UnboundedReceiver<()>> {
+        let mut guard = self.state.lock().await;
+        match guard.as_mut() {
+            Some(rpi_state) => rpi_state.gpio_watch_falling(pin),
+            None => Err(crate::error::Error::DeviceReadError(
+                "GPIO not initialized".to_string(),
+            )),
+        }
+    }
 }
 
 // Mock implementation for testing
@@ -432,6 +625,34 @@ impl RpiApi {
         Ok(guard.i2c_read(address, command, size))
     }
 
+    pub async fn read_i2c_raw(&self, address: i2c::I2cAddress, size: usize) -> Result<Vec<u8>> {
+        let guard = self.state.lock().await;
+        debug!("mock i2c raw read: addr={}, size={}", address, size);
+        Ok(guard.i2c_read_raw(address, size))
+    }
+
+    pub async fn transfer_spi(
+        &self,
+        chip_select: u8,
+        clock_hz: u32,
+        write: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut guard = self.state.lock().await;
+        debug!(
+            "mock spi transfer: cs={}, clock={}Hz, write={:?}",
+            chip_select, clock_hz, write
+        );
+        Ok(guard.spi_transfer(chip_select, &write))
+    }
+
+    /// Only addresses that have been written to, or given registers in tests, respond
+    pub async fn probe_i2c(&self, address: i2c::I2cAddress) -> Result<bool> {
+        let guard = self.state.lock().await;
+        let acked = guard.i2c_probe(address);
+        debug!("mock i2c probe: addr={}, acked={}", address, acked);
+        Ok(acked)
+    }
+
     pub async fn read_gpio(&self, pin: u8) -> Result<GpioLevel> {
         let guard = self.state.lock().await;
         let level = guard.read_pin(pin);
@@ -464,6 +685,45 @@ impl RpiApi {
         let mut guard = self.state.lock().await;
         guard.set_i2c_register(address, register, data);
     }
+
+    /// Queue the bytes the next SPI transfer on `chip_select` reads back
+    pub async fn script_spi_response(&self, chip_select: u8, read: Vec<u8>) {
+        let mut guard = self.state.lock().await;
+        guard
+            .spi_responses
+            .entry(chip_select)
+            .or_default()
+            .push_back(read);
+    }
+
+    /// The bytes written by each SPI transfer on `chip_select` so far
+    pub async fn spi_transfers(&self, chip_select: u8) -> Vec<Vec<u8>> {
+        let guard = self.state.lock().await;
+        guard
+            .spi_transfers
+            .get(&chip_select)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Set what reads without a command return from `address`
+    pub async fn set_i2c_raw_read(&self, address: u16, data: Vec<u8>) {
+        let mut guard = self.state.lock().await;
+        guard.i2c_raw_reads.insert(address, data);
+    }
+
+    pub async fn watch_gpio_falling(&self, pin: u8) -> Result<mpsc::UnboundedReceiver<()>> {
+        let mut guard = self.state.lock().await;
+        debug!("mock watch gpio {} for falling edges", pin);
+        Ok(guard.watch_falling(pin))
+    }
+
+    /// Simulate a falling edge on `pin`, as an interrupt line would produce (for tests)
+    pub async fn fire_gpio_interrupt(&self, pin: u8) {
+        let mut guard = self.state.lock().await;
+        debug!("mock gpio interrupt on {}", pin);
+        guard.fire_falling(pin);
+    }
 }
 
 // Stub implementation when no GPIO feature is enabled
@@ -491,11 +751,43 @@ impl RpiApi {
         ))
     }
 
+    pub async fn read_i2c_raw(&self, _address: i2c::I2cAddress, _size: usize) -> Result<Vec<u8>> {
+        Err(Error::DeviceReadError(
+            "I2C unavailable: enable 'raspberrypi' or 'mock-gpio' feature".to_string(),
+        ))
+    }
+
+    pub async fn transfer_spi(
+        &self,
+        _chip_select: u8,
+        _clock_hz: u32,
+        _write: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        Err(Error::DeviceReadError(
+            "SPI unavailable: enable 'raspberrypi' or 'mock-gpio' feature".to_string(),
+        ))
+    }
+
+    pub async fn probe_i2c(&self, _address: i2c::I2cAddress) -> Result<bool> {
+        Err(Error::DeviceReadError(
+            "I2C unavailable: enable 'raspberrypi' or 'mock-gpio' feature".to_string(),
+        ))
+    }
+
     pub async fn read_gpio(&self, _pin: u8) -> Result<GpioLevel> {
         Err(Error::DeviceReadError(
             "GPIO unavailable: enable 'raspberrypi' or 'mock-gpio' feature".to_string(),
         ))
     }
+
+    pub async fn watch_gpio_falling(
+        &self,
+        _pin: u8,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<()>> {
+        Err(Error::DeviceReadError(
+            "GPIO unavailable: enable 'raspberrypi' or 'mock-gpio' feature".to_string(),
+        ))
+    }
 }
 
 // ============================================================================
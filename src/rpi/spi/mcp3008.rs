@@ -0,0 +1,101 @@
+use super::super::RpiApi;
+use super::super::driver::{self, Driver};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+
+pub const CHANNELS: usize = 8;
+
+/// The slowest the MCP3008 is guaranteed to keep up with is 1.35MHz, at 2.7V
+const CLOCK_HZ: u32 = 1_000_000;
+
+const START: u8 = 0x01;
+const SINGLE_ENDED: u8 = 0x80;
+
+/// 10 bit conversions
+const STEPS: f64 = 1024.0;
+
+/// The Pi brings out two chip selects for SPI0
+pub fn chip_select(config: &device::MCP3008) -> Result<u8> {
+    match config.chip_select {
+        0 | 1 => Ok(config.chip_select as u8),
+        other => Err(Error::Config(format!(
+            "SPI0 has chip selects 0 and 1, not {}",
+            other
+        ))),
+    }
+}
+
+pub fn validate(config: &device::MCP3008) -> Result<()> {
+    chip_select(config)?;
+    if config.vref > 0.0 {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "MCP3008 reference voltage must be positive, not {}",
+            config.vref
+        )))
+    }
+}
+
+/// MCP3008
+/// 2.7V 8-Channel 10-Bit A/D Converter with SPI Serial Interface
+/// https://ww1.microchip.com/downloads/en/DeviceDoc/21295d.pdf
+///
+/// Convert a single ended channel. The start bit goes in the first byte so the 10 result bits
+/// come back right aligned in the last two.
+pub async fn read_volts(rapi: &RpiApi, config: &device::MCP3008, channel: usize) -> Result<f64> {
+    if channel >= CHANNELS {
+        return Err(Error::OutOfBounds(channel));
+    }
+    let write = vec![START, SINGLE_ENDED | ((channel as u8) << 4), 0x00];
+    let read = rapi
+        .transfer_spi(chip_select(config)?, CLOCK_HZ, write)
+        .await?;
+    match read.as_slice() {
+        &[_, hi, lo] => {
+            let counts = (((hi & 0x03) as u16) << 8) | lo as u16;
+            Ok(counts as f64 * config.vref / STEPS)
+        }
+        other => Err(Error::DeviceReadError(format!(
+            "MCP3008 returned {} bytes",
+            other.len()
+        ))),
+    }
+}
+
+#[derive(Debug)]
+struct Mcp3008 {
+    config: device::MCP3008,
+    rapi: RpiApi,
+}
+
+pub fn driver(model: &device::Type, rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::MCP3008(config) => Ok(Box::new(Mcp3008 {
+            config: *config,
+            rapi,
+        })),
+        _ => Err(driver::wrong_model("MCP3008", model)),
+    }
+}
+
+impl Driver for Mcp3008 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![driver::input_slot(Unit::Volts); CHANNELS]
+    }
+
+    // conversions are single-shot, so there is nothing to set up beforehand
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { validate(&self.config) })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            let volts = read_volts(&self.rapi, &self.config, index as usize).await?;
+            Ok(Dimensioned::from_volts(volts))
+        })
+    }
+}
@@ -0,0 +1,60 @@
+pub mod mcp3008;
+
+#[cfg(all(test, feature = "mock-gpio"))]
+mod tests {
+    use super::*;
+    use crate::app::device::MCP3008;
+    use crate::rpi;
+
+    fn mcp3008() -> MCP3008 {
+        MCP3008 {
+            chip_select: 1,
+            vref: 3.3,
+        }
+    }
+
+    // ==================== MCP3008 ADC Tests ====================
+
+    #[tokio::test]
+    async fn test_mcp3008_single_ended_read() {
+        let rpi_api = rpi::start(1);
+        // 512 counts, with junk in the bits that aren't part of the result
+        rpi_api.script_spi_response(1, vec![0xFF, 0xFA, 0x00]).await;
+
+        let volts = mcp3008::read_volts(&rpi_api, &mcp3008(), 5).await.unwrap();
+        assert!((volts - 1.65).abs() < 1e-9);
+        assert_eq!(
+            rpi_api.spi_transfers(1).await,
+            vec![vec![0x01, 0b1101_0000, 0x00]]
+        );
+        assert!(rpi_api.spi_transfers(0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mcp3008_responses_are_taken_in_order() {
+        let rpi_api = rpi::start(1);
+        rpi_api.script_spi_response(1, vec![0x00, 0x03, 0xFF]).await;
+        rpi_api.script_spi_response(1, vec![0x00, 0x00, 0x00]).await;
+
+        let full = mcp3008::read_volts(&rpi_api, &mcp3008(), 0).await.unwrap();
+        assert!((full - 3.3 * 1023.0 / 1024.0).abs() < 1e-9);
+        let zero = mcp3008::read_volts(&rpi_api, &mcp3008(), 0).await.unwrap();
+        assert_eq!(zero, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_mcp3008_rejects_bad_config() {
+        let rpi_api = rpi::start(1);
+        assert!(mcp3008::read_volts(&rpi_api, &mcp3008(), 8).await.is_err());
+        let config = MCP3008 {
+            chip_select: 2,
+            ..mcp3008()
+        };
+        assert!(mcp3008::validate(&config).is_err());
+        let config = MCP3008 {
+            vref: 0.0,
+            ..mcp3008()
+        };
+        assert!(mcp3008::validate(&config).is_err());
+    }
+}
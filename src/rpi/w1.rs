@@ -0,0 +1,330 @@
+use super::RpiApi;
+use super::driver::{self, Driver};
+use crate::app::device;
+use crate::app::dimensioned::Dimensioned;
+use crate::config::types::Unit;
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use std::io;
+use std::path::PathBuf;
+
+/// Where the kernel's w1 driver publishes the slaves it finds, one directory per ROM ID
+pub const SYSFS_ROOT: &str = "/sys/bus/w1/devices";
+
+/// ROM IDs of DS18B20s start with their family code
+const DS18B20_FAMILY: &str = "28-";
+
+/// The temperature register holds 85°C after power on, until the first conversion completes. A
+/// probe that browns out mid-read reports it too, so it's never trusted as a reading.
+const POWER_ON_RESET_MILLIDEGREES: i64 = 85_000;
+
+/// A 1-Wire bus as seen through sysfs. Tests point `root` at a temporary directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct W1Bus {
+    root: PathBuf,
+}
+
+impl Default for W1Bus {
+    fn default() -> Self {
+        Self::new(SYSFS_ROOT)
+    }
+}
+
+impl W1Bus {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// ROM IDs of the DS18B20s attached to the bus, in order. A bus that isn't enabled has none.
+    pub async fn rom_ids(&self) -> Result<Vec<String>> {
+        let root = self.root.clone();
+        blocking(move || {
+            let entries = match std::fs::read_dir(&root) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+                Err(e) => return Err(e.into()),
+            };
+            let mut ids = vec![];
+            for entry in entries {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if validate_rom_id(&name).is_ok() {
+                    ids.push(name);
+                }
+            }
+            ids.sort();
+            Ok(ids)
+        })
+        .await
+    }
+
+    /// Read a probe's temperature in °C. The kernel runs a conversion for every read, which takes
+    /// up to 750ms at 12 bit resolution.
+    pub async fn read_temperature(&self, rom_id: &str) -> Result<f64> {
+        validate_rom_id(rom_id)?;
+        let path = self.root.join(rom_id).join("w1_slave");
+        let rom_id = rom_id.to_string();
+        let contents = blocking(move || match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::NonExistant(format!(
+                "no 1-Wire device {} on the bus",
+                rom_id
+            ))),
+            Err(e) => Err(e.into()),
+        })
+        .await?;
+        parse_w1_slave(&contents)
+    }
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::IoError(format!("1-Wire read didn't finish: {}", e)))?
+}
+
+/// Check a DS18B20 ROM ID as the kernel names it: the family code, a dash, and 12 hex digits
+pub fn validate_rom_id(rom_id: &str) -> Result<()> {
+    match rom_id.strip_prefix(DS18B20_FAMILY) {
+        Some(serial) if serial.len() == 12 && serial.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(())
+        }
+        _ => Err(Error::Config(format!(
+            "'{}' is not a DS18B20 ROM ID, e.g. 28-0316a2791aff",
+            rom_id
+        ))),
+    }
+}
+
+/// Dallas/Maxim CRC-8, polynomial x^8 + x^5 + x^4 + 1, as the DS18B20 computes over its scratchpad
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8)
+            .fold((crc, byte), |(crc, byte), _| {
+                let mix = (crc ^ byte) & 0x01;
+                let crc = if mix == 1 {
+                    (crc >> 1) ^ 0x8C
+                } else {
+                    crc >> 1
+                };
+                (crc, byte >> 1)
+            })
+            .0
+    })
+}
+
+/// Parse the temperature in °C out of a w1_slave file, which looks like:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+///
+/// The first line is the scratchpad and whether the kernel's CRC check passed. We check the CRC
+/// ourselves too, as some kernels report YES for a bus full of 0xff.
+pub fn parse_w1_slave(contents: &str) -> Result<f64> {
+    let mut lines = contents.lines();
+    let (crc_line, temp_line) = match (lines.next(), lines.next()) {
+        (Some(crc_line), Some(temp_line)) => (crc_line, temp_line),
+        _ => return Err(read_error("truncated w1_slave", contents)),
+    };
+
+    if !crc_line.trim_end().ends_with("YES") {
+        return Err(read_error("CRC check failed", crc_line));
+    }
+    let scratchpad = crc_line
+        .split(':')
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| read_error("bad scratchpad", crc_line))?;
+    match scratchpad.split_last() {
+        Some((&crc, data)) if data.len() == 8 && crc8(data) == crc => (),
+        _ => return Err(read_error("CRC mismatch", crc_line)),
+    }
+
+    let millidegrees: i64 = temp_line
+        .rsplit_once("t=")
+        .and_then(|(_, t)| t.trim().parse().ok())
+        .ok_or_else(|| read_error("no temperature", temp_line))?;
+    if millidegrees == POWER_ON_RESET_MILLIDEGREES {
+        return Err(read_error(
+            "power-on reset value, no conversion has completed",
+            temp_line,
+        ));
+    }
+    Ok(millidegrees as f64 / 1000.0)
+}
+
+fn read_error(problem: &str, line: &str) -> Error {
+    Error::DeviceReadError(format!("DS18B20 {}: '{}'", problem, line.trim()))
+}
+
+#[derive(Debug)]
+struct Ds18b20 {
+    rom_id: String,
+    bus: W1Bus,
+}
+
+pub fn driver(model: &device::Type, _rapi: RpiApi) -> Result<Box<dyn Driver>> {
+    match model {
+        device::Type::DS18B20(device::DS18B20 { rom_id }) => Ok(Box::new(Ds18b20 {
+            rom_id: rom_id.clone(),
+            bus: W1Bus::default(),
+        })),
+        _ => Err(driver::wrong_model("DS18B20", model)),
+    }
+}
+
+impl Driver for Ds18b20 {
+    fn slots(&self) -> Vec<device::Slot> {
+        vec![driver::input_slot(Unit::DegC)]
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { validate_rom_id(&self.rom_id) })
+    }
+
+    fn read_sensor(&self, index: i32) -> BoxFuture<'_, Result<Dimensioned>> {
+        Box::pin(async move {
+            match index {
+                0 => Ok(Dimensioned::from_degc(
+                    self.bus.read_temperature(&self.rom_id).await?,
+                )),
+                _ => Err(Error::OutOfBounds(index as usize)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+                        72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+
+    /// A scratch sysfs tree, removed when dropped
+    struct FakeSysfs(PathBuf);
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("restedpi-w1-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).unwrap();
+            FakeSysfs(root)
+        }
+
+        fn add(&self, rom_id: &str, w1_slave: &str) {
+            let dir = self.0.join(rom_id);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("w1_slave"), w1_slave).unwrap();
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn crc_matches_the_datasheet() {
+        assert_eq!(
+            crc8(&[0x72, 0x01, 0x4b, 0x46, 0x7f, 0xff, 0x0e, 0x10]),
+            0x57
+        );
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn parses_a_good_reading() {
+        assert_eq!(parse_w1_slave(GOOD).unwrap(), 23.125);
+    }
+
+    /// A w1_slave file for a scratchpad, with a correct CRC
+    fn w1_slave(scratchpad: [u8; 8], millidegrees: i64) -> String {
+        let bytes: Vec<String> = scratchpad
+            .iter()
+            .chain([crc8(&scratchpad)].iter())
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let crc = bytes[8].clone();
+        let bytes = bytes.join(" ");
+        format!("{bytes} : crc={crc} YES\n{bytes} t={millidegrees}\n")
+    }
+
+    #[test]
+    fn parses_negative_readings() {
+        // -10.125°C is 0xff5e
+        let contents = w1_slave([0x5e, 0xff, 0x4b, 0x46, 0x7f, 0xff, 0x02, 0x10], -10125);
+        assert_eq!(parse_w1_slave(&contents).unwrap(), -10.125);
+    }
+
+    #[test]
+    fn rejects_failed_crc() {
+        let kernel_says_no = GOOD.replace("YES", "NO");
+        assert!(matches!(
+            parse_w1_slave(&kernel_says_no),
+            Err(Error::DeviceReadError(_))
+        ));
+        let corrupted = GOOD.replacen("4b", "4c", 1);
+        assert!(matches!(
+            parse_w1_slave(&corrupted),
+            Err(Error::DeviceReadError(_))
+        ));
+        assert!(parse_w1_slave("").is_err());
+    }
+
+    #[test]
+    fn rejects_power_on_reset_value() {
+        let contents = w1_slave([0x50, 0x05, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10], 85000);
+        assert!(matches!(
+            parse_w1_slave(&contents),
+            Err(Error::DeviceReadError(_))
+        ));
+    }
+
+    #[test]
+    fn validates_rom_ids() {
+        assert!(validate_rom_id("28-0316a2791aff").is_ok());
+        assert!(validate_rom_id("10-0316a2791aff").is_err());
+        assert!(validate_rom_id("28-0316a2791af").is_err());
+        assert!(validate_rom_id("28-0316a2791afg").is_err());
+        assert!(validate_rom_id("../28-0316a2791aff").is_err());
+    }
+
+    #[tokio::test]
+    async fn discovers_and_reads_probes() {
+        let sysfs = FakeSysfs::new("discover");
+        sysfs.add("28-0316a2791aff", GOOD);
+        sysfs.add("28-000005e2fdc3", GOOD);
+        sysfs.add("w1_bus_master1", "");
+        let bus = W1Bus::new(&sysfs.0);
+
+        assert_eq!(
+            bus.rom_ids().await.unwrap(),
+            vec!["28-000005e2fdc3", "28-0316a2791aff"]
+        );
+        assert_eq!(
+            bus.read_temperature("28-0316a2791aff").await.unwrap(),
+            23.125
+        );
+        assert!(matches!(
+            bus.read_temperature("28-ffffffffffff").await,
+            Err(Error::NonExistant(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_bus_has_no_probes() {
+        let bus = W1Bus::new(std::env::temp_dir().join("restedpi-w1-not-there"));
+        assert_eq!(bus.rom_ids().await.unwrap(), Vec::<String>::new());
+    }
+}
@@ -1,8 +1,21 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Integer,
+        user -> Text,
+        mutation -> Text,
+        arguments -> Text,
+        succeeded -> Bool,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     devices (name) {
         name -> Text,
+        display_name -> Text,
         model -> Text,
         notes -> Text,
         disabled -> Bool,
@@ -13,21 +26,44 @@ diesel::table! {
 diesel::table! {
     inputs (name) {
         name -> Text,
+        display_name -> Text,
         device_id -> Text,
         device_input_id -> Integer,
+        offset -> Double,
+        scale -> Double,
+        calibration -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    interlocks (name) {
+        name -> Text,
+        rule -> Text,
         created_at -> Timestamp,
     }
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use crate::app::output::ErrorPolicyMapping;
+    use crate::app::output::OutputKindMapping;
+
     outputs (name) {
         name -> Text,
+        display_name -> Text,
         device_id -> Text,
         device_output_id -> Integer,
         active_low -> Bool,
         automation_script -> Nullable<Text>,
+        min_on -> Nullable<Integer>,
+        min_off -> Nullable<Integer>,
+        max_switches_per_hour -> Nullable<Integer>,
+        safe_state -> Nullable<Bool>,
+        error_policy -> ErrorPolicyMapping,
+        kind -> OutputKindMapping,
         created_at -> Timestamp,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(devices, inputs, outputs,);
+diesel::allow_tables_to_appear_in_same_query!(audit_log, devices, inputs, interlocks, outputs,);
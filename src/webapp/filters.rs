@@ -5,6 +5,7 @@ use juniper_graphql_ws::ConnectionConfig;
 use juniper_warp::subscriptions::make_ws_filter;
 use juniper_warp::{make_graphql_filter, playground_filter};
 use std::sync::Arc;
+use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply, any, get, header, http::Response, post};
 
 fn with_app(
@@ -21,10 +22,12 @@ async fn metrics_handler(app: AppContext) -> Result<impl Reply, Rejection> {
     b.push_str("# HELP input_value The current value of inputs\n");
     b.push_str("# TYPE input_value gauge\n");
     for inp in app.channel().all_inputs().await? {
-        let v = inp.value(&app).await;
+        let v = inp.value(&app, None).await;
         let name = inp.name();
-        let unit = v.unit()?;
-        let value = v.value()?;
+        // inputs that can't be read, e.g. on disabled devices, have no sample
+        let (Ok(unit), Ok(value)) = (v.unit(), v.value()) else {
+            continue;
+        };
         b.push_str(&format!(
             "input_value{{name=\"{name}\", unit=\"{unit:?}\"}} {value}\n",
         ));
@@ -36,17 +39,72 @@ async fn metrics_handler(app: AppContext) -> Result<impl Reply, Rejection> {
     for op in app.channel().all_outputs().await? {
         let v = op.value(&app).await;
         let name = op.name();
-        let unit = v.unit()?;
-        let value = v.value()?;
+        let (Ok(unit), Ok(value)) = (v.unit(), v.value()) else {
+            continue;
+        };
         b.push_str(&format!(
             "output_value{{name=\"{name}\", unit=\"{unit:?}\"}} {value}\n",
         ));
     }
     b.push('\n');
 
+    let devices = app.channel().all_devices().await?;
+    let mut healths = Vec::new();
+    for device in &devices {
+        // devices that aren't running have no health to report
+        let name = device.db_device.name.clone();
+        if let Ok(health) = app.channel().device_health(name.clone()).await {
+            healths.push((name, health));
+        }
+    }
+    b.push_str(
+        "# HELP device_consecutive_failures Failed operations since a device last answered\n",
+    );
+    b.push_str("# TYPE device_consecutive_failures gauge\n");
+    for (name, health) in &healths {
+        let failures = health.consecutive_failures;
+        b.push_str(&format!(
+            "device_consecutive_failures{{name=\"{name}\"}} {failures}\n"
+        ));
+    }
+    b.push_str("# HELP device_error_rate Fraction of a device's last 100 operations that failed\n");
+    b.push_str("# TYPE device_error_rate gauge\n");
+    for (name, health) in &healths {
+        let rate = health.error_rate;
+        b.push_str(&format!("device_error_rate{{name=\"{name}\"}} {rate}\n"));
+    }
+    b.push_str("# HELP device_failures_total Failed device operations\n");
+    b.push_str("# TYPE device_failures_total counter\n");
+    for (name, health) in &healths {
+        let failures = health.failures;
+        b.push_str(&format!(
+            "device_failures_total{{name=\"{name}\"}} {failures}\n"
+        ));
+    }
+    b.push_str("# HELP device_resets_total Resets after a device failed repeatedly\n");
+    b.push_str("# TYPE device_resets_total counter\n");
+    for (name, health) in &healths {
+        let resets = health.resets;
+        b.push_str(&format!(
+            "device_resets_total{{name=\"{name}\"}} {resets}\n"
+        ));
+    }
+    b.push('\n');
+
     Ok(response)
 }
 
+/// Liveness and readiness of the app loop, for process supervisors and load balancers
+async fn healthz_handler(app: SharedAppState) -> Result<impl Reply, Rejection> {
+    let status = app.health().status();
+    let code = if status.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&status), code))
+}
+
 pub fn graphql_api(
     app: SharedAppState,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
@@ -68,6 +126,15 @@ pub fn graphql_api(
             .or(get().and(playground_filter("/graphql", Some("/subscriptions")))),
     );
 
+    // Health endpoint
+    let healthz = {
+        let app_clone = app.clone();
+        warp::path("healthz")
+            .and(get())
+            .and(any().map(move || app_clone.clone()))
+            .and_then(healthz_handler)
+    };
+
     // Metrics endpoint
     let metrics = warp::path("metrics")
         .and(get())
@@ -77,5 +144,5 @@ pub fn graphql_api(
     // Serve index.html for root and any other path (SPA-style)
     let index = get().and_then(super::serve_index);
 
-    subscriptions.or(graphql).or(metrics).or(index)
+    subscriptions.or(graphql).or(healthz).or(metrics).or(index)
 }
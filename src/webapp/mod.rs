@@ -34,10 +34,14 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
             Error::ParseError => 0x1002,
             #[cfg(feature = "raspberrypi")]
             Error::I2cError(_) => 0x1003,
+            #[cfg(feature = "raspberrypi")]
+            Error::SpiError(_) => 0x1005,
             Error::NonExistant(_) => 0x0010,
             Error::NotUnique(_) => 0x1210,
             Error::OutOfBounds(_) => 0x0011,
             Error::DeviceReadError(_) => 0x0311,
+            Error::DeviceTimeout(_) => 0x0312,
+            Error::DeviceDisabled(_) => 0x0313,
             Error::RecvError(_) => 0x0100,
             Error::SendError(_) => 0x0101,
             Error::StorageError(_) => 0x0102,
@@ -45,10 +49,12 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
             Error::EncodingError(_) => 0x0002,
             Error::InputNotFound(_) => 0x0003,
             Error::OutputNotFound(_) => 0x0004,
+            Error::InterlockViolation(_) => 0x1004,
             Error::UserNotFound => 0x0005,
             Error::TokenIssue => 0x0006,
             Error::PasswordIssue => 0x0007,
             Error::NotLoggedIn => 0x0000,
+            Error::NotAdmin => 0x0008,
         };
 
         let message = err.to_string();
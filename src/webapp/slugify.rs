@@ -32,6 +32,20 @@ pub fn slugify(name: &str, inc: usize) -> String {
     result.join("-")
 }
 
+/// Convert a human name into an ID that expressions can use without quoting: its slug, with
+/// anything but ASCII letters, digits and underscores made an underscore, and never starting with
+/// a digit. The ID may still be a keyword, which the caller can avoid by picking another `inc`.
+pub fn identifier(name: &str, inc: usize) -> String {
+    let mut id: String = slugify(name, inc)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if id.starts_with(|c: char| c.is_ascii_digit()) {
+        id.insert(0, '_');
+    }
+    id
+}
+
 // Make some common unicode characters into more usable slug ascii
 fn replace_char(c: char) -> String {
     match c {
@@ -567,7 +581,7 @@ fn replace_char(c: char) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::webapp::slugify::slugify;
+    use crate::webapp::slugify::{identifier, slugify};
     #[test]
     fn basic() {
         assert_eq!(slugify("hello world", 0), "hello-world");
@@ -636,4 +650,14 @@ mod tests {
     fn test_cyrillic_text() {
         assert_eq!(slugify("Компьютер", 0), "kompyuter");
     }
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier("Attic Fan", 0), "attic_fan");
+        assert_eq!(identifier("Attic Fan", 2), "attic_fan_2");
+        assert_eq!(identifier("alice@bob.com", 0), "alice_at_bob_com");
+        assert_eq!(identifier("2nd floor", 0), "_2nd_floor");
+        assert_eq!(identifier("Bob's 'heater'", 0), "bob_s__heater_");
+        assert_eq!(identifier("", 1), "_1");
+    }
 }